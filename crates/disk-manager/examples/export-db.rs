@@ -0,0 +1,35 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Dumps block devices for loading with `UDevDb`, or loads a dump given as an argument.
+
+use pop_disk_manager::snapshot::Snapshot;
+use pop_disk_manager::udev_db::{self, UDevDb};
+use pop_disk_manager::*;
+use std::path::Path;
+
+fn main() {
+    if let Some(path) = std::env::args().nth(1) {
+        let mut db = UDevDb::from_file(Path::new(&path)).unwrap();
+
+        let mut t = ACellOwner::new();
+        let mut disk_manager = DiskManager::simulated();
+
+        disk_manager.reload(&mut db, &mut t);
+
+        println!("{}", Snapshot::new(&disk_manager, &t).to_json().unwrap());
+        return;
+    }
+
+    let context = libudev::Context::new().unwrap();
+    let mut enumerator = libudev::Enumerator::new(&context).unwrap();
+
+    enumerator.match_subsystem("block").unwrap();
+
+    let mut udev = UDev {
+        context,
+        enumerator,
+    };
+
+    print!("{}", udev_db::export(&mut udev).unwrap());
+}
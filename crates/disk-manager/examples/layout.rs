@@ -0,0 +1,27 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use pop_disk_manager::snapshot::Snapshot;
+use pop_disk_manager::*;
+
+fn main() {
+    let context = libudev::Context::new().unwrap();
+    let mut enumerator = libudev::Enumerator::new(&context).unwrap();
+
+    enumerator.match_subsystem("block").unwrap();
+
+    let mut udev = UDev {
+        context,
+        enumerator,
+    };
+
+    let mut t = ACellOwner::new();
+
+    let dm = devicemapper::DM::new().unwrap();
+
+    let mut disk_manager = DiskManager::new(dm);
+
+    disk_manager.reload(&mut udev, &mut t);
+
+    println!("{}", Snapshot::new(&disk_manager, &t).to_json().unwrap());
+}
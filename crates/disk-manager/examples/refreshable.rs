@@ -51,6 +51,14 @@ fn main() {
                         }
                     }
                 }
+                BlockDevice::Loop(lo) => {
+                    let lo = lo.ro(&t);
+                    if let Some(fs) = lo.device.fs.as_ref() {
+                        if fs.uuid == entry.uuid {
+                            eprintln!("  Entry is on {}", devname);
+                        }
+                    }
+                }
             }
         }
     }
@@ -0,0 +1,337 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Byte-exact backups of partition tables, which may be restored after a failed edit.
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where backups are stored before the partition table of a disk is written.
+pub const DEFAULT_DIR: &str = "/run/distinst/table-backups";
+
+const MAGIC: &[u8; 8] = b"DSTPTBAK";
+const VERSION: u32 = 1;
+
+/// The largest GPT entry array that is backed up, which is far beyond the usual 16 KiB.
+const MAX_ENTRY_ARRAY: u64 = 1024 * 1024;
+
+/// The most EBRs that are followed in the chain of logical partitions.
+const MAX_EBRS: u32 = 256;
+
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error("failed to open {0:?}")]
+    Open(PathBuf, #[source] io::Error),
+    #[error("failed to read partition table")]
+    Read(#[source] io::Error),
+    #[error("failed to write partition table")]
+    Write(#[source] io::Error),
+    #[error("failed to save backup to {0:?}")]
+    Save(PathBuf, #[source] io::Error),
+    #[error("failed to load backup from {0:?}")]
+    Load(PathBuf, #[source] io::Error),
+    #[error("{0:?} is not a partition table backup")]
+    Format(PathBuf),
+    #[error("no partition table was found on {0:?}")]
+    NoTable(PathBuf),
+    #[error("backup was taken from a disk with a different size or sector size")]
+    Mismatch,
+    #[error("failed to sync partition table to disk")]
+    Sync(#[source] io::Error),
+    #[error("kernel failed to re-read the partition table")]
+    Reread(#[source] gptman::linux::BlockError),
+}
+
+/// A range of bytes copied from a disk.
+#[derive(Clone, Debug)]
+pub struct Region {
+    /// Offset in bytes from the start of the disk.
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// A copy of every sector which describes the partition table of a disk.
+///
+/// For GPT, this is the protective MBR, and both headers with their entry arrays. For
+/// MBR, this is the MBR and the chain of extended boot records.
+#[derive(Clone, Debug)]
+pub struct TableBackup {
+    pub sector_size: u64,
+    /// Size of the disk in bytes.
+    pub size: u64,
+    pub gpt: bool,
+    pub regions: Vec<Region>,
+}
+
+impl TableBackup {
+    /// Copy the partition table of the disk at `path`.
+    pub fn read(path: &Path, sector_size: u64) -> Result<Self, BackupError> {
+        let file = File::open(path).map_err(|why| BackupError::Open(path.to_owned(), why))?;
+        let size = disk_size(&file).map_err(BackupError::Read)?;
+
+        let mut backup = Self {
+            sector_size,
+            size,
+            gpt: false,
+            regions: Vec::new(),
+        };
+
+        let sector = |lba: u64| read_at(&file, lba * sector_size, sector_size);
+
+        let header = sector(1)?;
+        if &header[0..8] == b"EFI PART" {
+            backup.gpt = true;
+
+            // The protective MBR, primary header, and primary entries are contiguous.
+            let entries_lba = LittleEndian::read_u64(&header[72..]);
+            let entries_end = entries_lba + entry_sectors(&header, sector_size);
+            backup.push(&file, 0, entries_end)?;
+
+            let backup_lba = LittleEndian::read_u64(&header[32..]);
+            let alternate = sector(backup_lba)?;
+
+            let first = if &alternate[0..8] == b"EFI PART" {
+                LittleEndian::read_u64(&alternate[72..])
+            } else {
+                backup_lba.saturating_sub(entry_sectors(&header, sector_size))
+            };
+
+            backup.push(&file, first, backup_lba + 1)?;
+        } else {
+            let mbr = sector(0)?;
+
+            if mbr[510..512] != [0x55, 0xAA] {
+                return Err(BackupError::NoTable(path.to_owned()));
+            }
+
+            backup.regions.push(Region {
+                offset: 0,
+                data: mbr.clone(),
+            });
+
+            let extended = (0..4)
+                .map(|id| &mbr[446 + id * 16..446 + id * 16 + 16])
+                .find(|entry| crate::mbr::is_extended(entry[4]))
+                .map(|entry| u64::from(LittleEndian::read_u32(&entry[8..])));
+
+            if let Some(extended) = extended {
+                let mut ebr = extended;
+
+                // Guard against loops in a corrupted chain.
+                for _ in 0..MAX_EBRS {
+                    let data = sector(ebr)?;
+                    if data[510..512] != [0x55, 0xAA] {
+                        break;
+                    }
+
+                    let next = &data[446 + 16..446 + 32];
+                    let link = LittleEndian::read_u32(&next[8..]);
+
+                    backup.regions.push(Region {
+                        offset: ebr * sector_size,
+                        data: data.clone(),
+                    });
+
+                    if !crate::mbr::is_extended(next[4]) || link == 0 {
+                        break;
+                    }
+
+                    ebr = extended + u64::from(link);
+                }
+            }
+        }
+
+        Ok(backup)
+    }
+
+    /// Copy sectors `start..end` into the backup.
+    fn push(&mut self, file: &File, start: u64, end: u64) -> Result<(), BackupError> {
+        let offset = start.saturating_mul(self.sector_size);
+        let length = end.saturating_sub(start).saturating_mul(self.sector_size);
+
+        if length > max_region(self.sector_size) || offset.saturating_add(length) > self.size {
+            return Err(BackupError::Read(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        let data = read_at(file, offset, length)?;
+        self.regions.push(Region { offset, data });
+        Ok(())
+    }
+
+    /// Load a backup which was saved with [`TableBackup::save`].
+    pub fn load(path: &Path) -> Result<Self, BackupError> {
+        let file = File::open(path).map_err(|why| BackupError::Load(path.to_owned(), why))?;
+        let mut reader = BufReader::new(file);
+
+        let mut magic = [0u8; 8];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|why| BackupError::Load(path.to_owned(), why))?;
+
+        if &magic != MAGIC {
+            return Err(BackupError::Format(path.to_owned()));
+        }
+
+        let decode = |reader: &mut BufReader<File>| -> io::Result<Self> {
+            if reader.read_u32::<LittleEndian>()? != VERSION {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+
+            let sector_size = reader.read_u64::<LittleEndian>()?;
+            let size = reader.read_u64::<LittleEndian>()?;
+            let gpt = reader.read_u8()? != 0;
+            let count = reader.read_u32::<LittleEndian>()?;
+
+            // Lengths are checked before anything is allocated for them.
+            let valid_sector = sector_size.is_power_of_two() && sector_size <= 65536;
+            if !valid_sector || count > MAX_EBRS + 1 {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+
+            let mut regions = Vec::new();
+            for _ in 0..count {
+                let offset = reader.read_u64::<LittleEndian>()?;
+                let length = reader.read_u64::<LittleEndian>()?;
+
+                if length > max_region(sector_size) || offset.saturating_add(length) > size {
+                    return Err(io::ErrorKind::InvalidData.into());
+                }
+
+                let mut data = vec![0; length as usize];
+                reader.read_exact(&mut data)?;
+                regions.push(Region { offset, data });
+            }
+
+            Ok(Self {
+                sector_size,
+                size,
+                gpt,
+                regions,
+            })
+        };
+
+        decode(&mut reader).map_err(|_| BackupError::Format(path.to_owned()))
+    }
+
+    /// Save the backup to a file.
+    pub fn save(&self, path: &Path) -> Result<(), BackupError> {
+        let save = || -> io::Result<()> {
+            let mut writer = BufWriter::new(File::create(path)?);
+
+            writer.write_all(MAGIC)?;
+            writer.write_u32::<LittleEndian>(VERSION)?;
+            writer.write_u64::<LittleEndian>(self.sector_size)?;
+            writer.write_u64::<LittleEndian>(self.size)?;
+            writer.write_u8(self.gpt as u8)?;
+            writer.write_u32::<LittleEndian>(self.regions.len() as u32)?;
+
+            for region in &self.regions {
+                writer.write_u64::<LittleEndian>(region.offset)?;
+                writer.write_u64::<LittleEndian>(region.data.len() as u64)?;
+                writer.write_all(&region.data)?;
+            }
+
+            writer.into_inner()?.sync_all()
+        };
+
+        save().map_err(|why| BackupError::Save(path.to_owned(), why))
+    }
+
+    /// Write the backup back to the disk it was taken from, and have the kernel re-read it.
+    pub fn restore(&self, path: &Path, sector_size: u64) -> Result<(), BackupError> {
+        if sector_size != self.sector_size {
+            return Err(BackupError::Mismatch);
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|why| BackupError::Open(path.to_owned(), why))?;
+
+        if disk_size(&file).map_err(BackupError::Read)? != self.size {
+            return Err(BackupError::Mismatch);
+        }
+
+        eprintln!("restoring partition table of {:?}", path);
+
+        // A GPT written since the backup was taken would otherwise take precedence over
+        // the restored MBR.
+        if !self.gpt {
+            let header = read_at(&file, self.sector_size, self.sector_size)?;
+
+            if &header[0..8] == b"EFI PART" {
+                let backup_lba = LittleEndian::read_u64(&header[32..]);
+                let zeroes = vec![0; self.sector_size as usize];
+
+                for lba in &[1, backup_lba] {
+                    if (lba + 1) * self.sector_size <= self.size {
+                        file.write_all_at(&zeroes, lba * self.sector_size)
+                            .map_err(BackupError::Write)?;
+                    }
+                }
+            }
+        }
+
+        for region in &self.regions {
+            file.write_all_at(&region.data, region.offset)
+                .map_err(BackupError::Write)?;
+        }
+
+        file.sync_all().map_err(BackupError::Sync)?;
+
+        gptman::linux::reread_partition_table(&mut file).map_err(BackupError::Reread)
+    }
+}
+
+/// Save a backup of the current partition table of `disk` into `dir`, returning the path
+/// of the backup.
+pub fn save_current(disk: &Path, sector_size: u64, dir: &Path) -> Result<PathBuf, BackupError> {
+    let backup = TableBackup::read(disk, sector_size)?;
+
+    fs::create_dir_all(dir).map_err(|why| BackupError::Save(dir.to_owned(), why))?;
+
+    let name = disk
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or_default();
+
+    let path = dir.join(format!("{}-{}.ptbackup", name, time));
+
+    eprintln!("backing up partition table of {:?} to {:?}", disk, path);
+    backup.save(&path)?;
+
+    Ok(path)
+}
+
+/// The largest region of a backup: the protective MBR, a GPT header, and its entry array.
+fn max_region(sector_size: u64) -> u64 {
+    2 * sector_size + MAX_ENTRY_ARRAY
+}
+
+/// Number of sectors occupied by the partition entry array described by a GPT header.
+fn entry_sectors(header: &[u8], sector_size: u64) -> u64 {
+    let entries = u64::from(LittleEndian::read_u32(&header[80..]));
+    let entry_size = u64::from(LittleEndian::read_u32(&header[84..]));
+    (entries * entry_size + sector_size - 1) / sector_size
+}
+
+fn disk_size(mut file: &File) -> io::Result<u64> {
+    file.seek(SeekFrom::End(0))
+}
+
+fn read_at(file: &File, offset: u64, length: u64) -> Result<Vec<u8>, BackupError> {
+    let mut buffer = vec![0; length as usize];
+    file.read_exact_at(&mut buffer, offset)
+        .map_err(BackupError::Read)?;
+    Ok(buffer)
+}
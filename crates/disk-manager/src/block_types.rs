@@ -1,7 +1,10 @@
 // Copyright 2021 System76 <info@system76.com>
 // SPDX-License-Identifier: LGPL-3.0-only
 
-use crate::ACell;
+use crate::lvm::report::{LogicalVolume, PhysicalVolume};
+use crate::superblock::{self, Superblock, SuperblockError};
+use crate::{ACell, ACellOwner};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -9,20 +12,71 @@ pub enum BlockDevice {
     Partition(Arc<ACell<PartitionEntry>>),
     Disk(Arc<ACell<Disk>>),
     DeviceMap(Arc<ACell<DeviceMap>>),
+    Loop(Arc<ACell<LoopDevice>>),
 }
 
 #[derive(Clone)]
 pub struct Device {
     /// A device name could be `/dev/sda1`.
     pub name: String,
-    /// Number of sectors
+    /// Number of 512-byte sectors, regardless of the logical sector size.
     pub size: u64,
     pub fs: Option<FileSystem>,
+    /// Symlinks to the device, such as those in `/dev/disk/by-id`.
+    pub links: Vec<String>,
+    /// Where the filesystem on this device is mounted.
+    pub mounts: Vec<PathBuf>,
+    /// Whether the device is in use as swap.
+    pub swap: bool,
+    /// The LVM PV on the device, from the [`Report`](crate::lvm::report::Report) of the
+    /// last reload.
+    pub pv: Option<PhysicalVolume>,
+    /// The `DEVNAME` of each device which this device is on top of, such as the members
+    /// of an MD RAID array, or the PVs of an LV.
+    pub parents: Vec<String>,
     pub children: Vec<Arc<ACell<DeviceMap>>>,
 }
 
+impl Device {
+    /// Reads the superblock of the filesystem on this device, without mounting it.
+    pub fn superblock(&self) -> Result<Superblock, SuperblockError> {
+        let fs = self.fs.as_ref().map(|fs| fs.type_.as_str());
+        superblock::read(Path::new(&self.name), fs)
+    }
+}
+
+/// A block device which is backed by a file, such as the squashfs of a live image.
+pub struct LoopDevice {
+    pub device: Device,
+    pub backing_file: PathBuf,
+    /// Whether the backing file is on the live installation media.
+    pub live_media: bool,
+}
+
+/// A Linux software RAID array, which is assembled by mdadm from its member devices.
+///
+/// The array itself is a [`Disk`], which may be partitioned like any other.
+#[derive(Clone)]
+pub struct MdArray {
+    /// RAID level, such as `raid1`.
+    pub level: String,
+    /// UUID of the array, in the colon-separated form used by mdadm.
+    pub uuid: String,
+    /// `DEVNAME`s of the `linux_raid_member` devices which the array is assembled from.
+    pub members: Vec<String>,
+    /// Number of members in the array when it is not degraded.
+    pub raid_disks: u32,
+    /// Number of members which are missing or have failed.
+    pub degraded: u32,
+    /// State of the array from `md/array_state`, such as `clean`, `active`, or `inactive`.
+    pub state: String,
+}
+
 pub struct DeviceMap {
     pub device: Device,
+    /// The LVM LV of the device map, from the [`Report`](crate::lvm::report::Report) of
+    /// the last reload.
+    pub lv: Option<LogicalVolume>,
     pub lv_name: Option<String>,
     pub name: String,
     pub vg_name: Option<String>,
@@ -35,23 +89,223 @@ pub struct Disk {
     pub model: String,
     pub serial: String,
     pub table: Option<PartitionTable>,
+    pub topology: Topology,
+    pub transport: Transport,
+    /// Spinning media, as opposed to solid state storage.
+    pub rotational: bool,
+    pub removable: bool,
+    pub read_only: bool,
+    /// Whether the disk accepts discard (TRIM) requests.
+    pub discard: bool,
+    /// Whether the disk holds the live installation media, which must not be installed to.
+    pub live_media: bool,
+    /// The RAID details of the disk, if it is an MD RAID array such as `/dev/md0`.
+    pub md: Option<MdArray>,
     pub children: Vec<Arc<ACell<PartitionEntry>>>,
 }
 
-#[derive(Clone, Debug)]
+impl Disk {
+    /// Number of logical sectors on the disk.
+    pub fn sectors(&self) -> u64 {
+        self.device.size * 512 / self.sector_size
+    }
+
+    /// The boundary in logical sectors that partitions should be aligned to.
+    ///
+    /// This is 1 MiB, unless the disk reports a larger optimal IO size.
+    pub fn alignment(&self) -> u64 {
+        let bytes = self.topology.optimal_io_size.max(1024 * 1024);
+        (bytes / self.sector_size).max(1)
+    }
+
+    /// Unallocated regions of the disk, aligned to its [`alignment`](Self::alignment).
+    ///
+    /// Regions are described in bytes, along with the logical sectors they span.
+    ///
+    /// A disk without a partition table is entirely free, unless it holds a filesystem.
+    pub fn free_regions(&self, t: &ACellOwner) -> Vec<FreeRegion> {
+        let (first, last) = match self.table {
+            Some(table) => table.usable_sectors(self.sector_size, self.sectors()),
+            None if self.device.fs.is_none() => (0, self.sectors().saturating_sub(1)),
+            None => return Vec::new(),
+        };
+
+        let mut primary = Vec::new();
+        let mut logical = Vec::new();
+        let mut extended = None;
+
+        for child in &self.children {
+            let part = child.ro(t);
+            let start = part.start(self.sector_size);
+            let sectors = part.sectors(self.sector_size);
+
+            if sectors == 0 {
+                continue;
+            }
+
+            let region = (start, start + sectors - 1);
+
+            match part.kind {
+                PartitionKind::Primary => primary.push(region),
+                PartitionKind::Extended => {
+                    primary.push(region);
+                    extended = Some(region);
+                }
+                PartitionKind::Logical => logical.push(region),
+            }
+        }
+
+        let mut regions = self.gaps(first, last, &mut primary, false);
+
+        if let Some((start, end)) = extended {
+            regions.extend(self.gaps(start, end, &mut logical, true));
+            regions.sort_by_key(|region| region.start);
+        }
+
+        regions
+    }
+
+    /// Aligned gaps between the `used` regions, from the `first` to the `last` sector.
+    fn gaps(
+        &self,
+        first: u64,
+        last: u64,
+        used: &mut Vec<(u64, u64)>,
+        logical: bool,
+    ) -> Vec<FreeRegion> {
+        let alignment = self.alignment();
+
+        used.sort_unstable();
+
+        let mut regions = Vec::new();
+        let mut next = first;
+
+        for &(start, end) in used.iter().chain(std::iter::once(&(last + 1, last + 1))) {
+            if start > next {
+                // Each logical partition is preceded by its extended boot record.
+                let from = if logical { next + 1 } else { next };
+                let aligned_start = (from + alignment - 1) / alignment * alignment;
+                let aligned_end = start / alignment * alignment;
+
+                if aligned_end > aligned_start {
+                    regions.push(FreeRegion {
+                        start: aligned_start * self.sector_size,
+                        end: aligned_end * self.sector_size - 1,
+                        size: (aligned_end - aligned_start) * self.sector_size,
+                        first_sector: aligned_start,
+                        last_sector: aligned_end - 1,
+                        logical,
+                    });
+                }
+            }
+
+            next = next.max(end + 1);
+        }
+
+        regions
+    }
+}
+
+/// IO geometry of a disk, in bytes, as reported by the kernel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Topology {
+    pub physical_sector_size: u64,
+    pub minimum_io_size: u64,
+    /// Zero if the disk does not report a preference.
+    pub optimal_io_size: u64,
+    /// Offset of the first physically-aligned sector from the start of the disk.
+    pub alignment_offset: u64,
+}
+
+/// The bus which a disk is attached by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Transport {
+    Nvme,
+    Sata,
+    Scsi,
+    Usb,
+    Mmc,
+    Virtio,
+    Unknown,
+}
+
+/// An unallocated region of a disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FreeRegion {
+    /// Offset of the first byte of the region from the start of the disk.
+    pub start: u64,
+    /// Offset of the last byte of the region from the start of the disk.
+    pub end: u64,
+    /// Size of the region in bytes.
+    pub size: u64,
+    /// First logical sector of the region, for placing partitions.
+    pub first_sector: u64,
+    /// Last logical sector of the region.
+    pub last_sector: u64,
+    /// Set if the region is inside of the extended partition, and only usable by logical
+    /// partitions.
+    pub logical: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FileSystem {
     pub type_: String,
     pub uuid: String,
+    pub label: Option<String>,
 }
 
 pub struct PartitionEntry {
     pub device: Device,
+    pub kind: PartitionKind,
+    /// Partition number in the partition table.
+    pub number: u32,
     pub offset: u64,
     pub uuid: String,
+    /// GPT partition name
+    pub label: Option<String>,
+    /// GPT partition type GUID, or MBR system ID such as `0x83`
+    pub type_: Option<String>,
+}
+
+impl PartitionEntry {
+    /// First logical sector of the partition on a disk with the given sector size.
+    pub fn start(&self, sector_size: u64) -> u64 {
+        self.offset * 512 / sector_size
+    }
+
+    /// Length of the partition in logical sectors on a disk with the given sector size.
+    pub fn sectors(&self, sector_size: u64) -> u64 {
+        self.device.size * 512 / sector_size
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PartitionKind {
+    Primary,
+    /// An MBR container for logical partitions.
+    Extended,
+    /// An MBR partition inside of the extended partition.
+    Logical,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum PartitionTable {
     Mbr,
     Gpt,
 }
+
+impl PartitionTable {
+    /// The first and last logical sectors which partitions may occupy.
+    ///
+    /// GPT reserves the protective MBR, header, and a 128-entry partition array at the
+    /// start of the disk, and a backup of the header and array at the end.
+    pub fn usable_sectors(self, sector_size: u64, sectors: u64) -> (u64, u64) {
+        match self {
+            PartitionTable::Gpt => {
+                let entries = (128 * 128 + sector_size - 1) / sector_size;
+                (2 + entries, sectors.saturating_sub(2 + entries))
+            }
+            PartitionTable::Mbr => (1, sectors.saturating_sub(1)),
+        }
+    }
+}
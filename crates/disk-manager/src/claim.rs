@@ -0,0 +1,219 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Detecting what holds a device busy, and locking disks while they are written.
+
+use crate::block_types::BlockDevice;
+use crate::disk_manager::DiskManager;
+use crate::ACellOwner;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long to wait for udev to release its lock on a disk that it is probing.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Error)]
+pub enum ClaimError {
+    #[error("cannot claim a device which does not exist")]
+    DeviceNotFound,
+    #[error("{0} is in use by {}", list(.1))]
+    Busy(String, Vec<Holder>),
+    #[error("{0} is locked by another process")]
+    Locked(String),
+    #[error("failed to open {0}")]
+    Open(String, #[source] io::Error),
+    #[error("failed to lock {0}")]
+    Lock(String, #[source] io::Error),
+}
+
+/// Something which is using a device, or a device on top of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Holder {
+    /// A filesystem on `device` is mounted at `target`.
+    Mount { device: String, target: PathBuf },
+    /// `device` is in use as swap.
+    Swap { device: String },
+    /// `device` is an unlocked LUKS volume, which is mapped to `name`.
+    Luks { device: String, name: String },
+    /// A device map such as an LVM logical volume is on top of `device`.
+    DeviceMap { device: String, name: String },
+    /// `device` is a member of the MD RAID array `name`.
+    MdArray { device: String, name: String },
+    /// A kernel holder of `device` which is not otherwise known, such as a bcache device.
+    Kernel { device: String, holder: String },
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Holder::Mount { device, target } => {
+                write!(f, "{} mounted at {}", device, target.display())
+            }
+            Holder::Swap { device } => write!(f, "swap on {}", device),
+            Holder::Luks { device, name } => {
+                write!(f, "unlocked LUKS volume {} on {}", name, device)
+            }
+            Holder::DeviceMap { device, name } => write!(f, "device map {} on {}", name, device),
+            Holder::MdArray { device, name } => {
+                write!(f, "MD RAID array {} on {}", name, device)
+            }
+            Holder::Kernel { device, holder } => write!(f, "{} on {}", holder, device),
+        }
+    }
+}
+
+fn list(holders: &[Holder]) -> String {
+    holders
+        .iter()
+        .map(Holder::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Everything which is using a device, or a device on top of it.
+pub(crate) fn holders(dm: &DiskManager, devname: &str, t: &ACellOwner) -> Vec<Holder> {
+    let mut holders = Vec::new();
+
+    let root = devname;
+    let mut stack = Vec::new();
+
+    // An array is reached once through each of its members which are on the device.
+    for devname in std::iter::once(root).chain(dm.descendants(root, t)) {
+        if !stack.contains(&devname) {
+            stack.push(devname);
+        }
+    }
+
+    for &devname in &stack {
+        let block = match dm.blocks.get(devname) {
+            Some(block) => block,
+            None => continue,
+        };
+
+        let device = DiskManager::device_from_block(block, t);
+
+        holders.extend(device.mounts.iter().map(|target| Holder::Mount {
+            device: devname.to_owned(),
+            target: target.clone(),
+        }));
+
+        if device.swap {
+            holders.push(Holder::Swap {
+                device: devname.to_owned(),
+            });
+        }
+
+        match block {
+            // The device itself is not its own holder.
+            _ if devname == root => (),
+
+            // An LV is reported once for each of its PVs which are on the device.
+            BlockDevice::DeviceMap(map) => {
+                let name = &map.ro(t).name;
+                let parents = dm.parents_of(devname, t).iter();
+
+                for parent in parents.filter(|parent| stack.contains(&parent.as_str())) {
+                    let luks = dm.blocks.get(parent).map_or(false, |parent| {
+                        let parent = DiskManager::device_from_block(parent, t);
+                        parent
+                            .fs
+                            .as_ref()
+                            .map_or(false, |fs| fs.type_ == "crypto_LUKS")
+                    });
+
+                    let device = parent.clone();
+                    let name = name.clone();
+
+                    holders.push(if luks {
+                        Holder::Luks { device, name }
+                    } else {
+                        Holder::DeviceMap { device, name }
+                    });
+                }
+            }
+
+            BlockDevice::Disk(disk) => {
+                let members = disk.ro(t).md.iter().flat_map(|md| &md.members);
+
+                for member in members {
+                    if stack.contains(&member.as_str()) {
+                        holders.push(Holder::MdArray {
+                            device: member.clone(),
+                            name: devname.to_owned(),
+                        });
+                    }
+                }
+            }
+
+            _ => (),
+        }
+
+        // Holders in sysfs which are not block devices known to the disk manager.
+        let kname = devname.trim_start_matches("/dev/");
+        if let Ok(entries) = fs::read_dir(["/sys/class/block/", kname, "/holders"].concat()) {
+            for entry in entries.filter_map(Result::ok) {
+                let holder = entry.file_name().to_string_lossy().into_owned();
+
+                if !dm.blocks.contains_key(&["/dev/", &holder].concat()) {
+                    holders.push(Holder::Kernel {
+                        device: devname.to_owned(),
+                        holder,
+                    });
+                }
+            }
+        }
+    }
+
+    holders
+}
+
+/// An exclusive lock on a whole disk, which stops udev from probing it while it is written.
+///
+/// The lock is released when the claim is dropped.
+pub struct DiskClaim {
+    disk: String,
+    _file: File,
+}
+
+impl DiskClaim {
+    /// Take the BSD file lock on a whole disk, waiting briefly for udev to release it.
+    pub fn new(disk: &str) -> Result<Self, ClaimError> {
+        let file = File::open(disk).map_err(|why| ClaimError::Open(disk.to_owned(), why))?;
+
+        let mut waited = Duration::from_secs(0);
+        let interval = Duration::from_millis(100);
+
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+                break;
+            }
+
+            let why = io::Error::last_os_error();
+
+            if why.kind() != io::ErrorKind::WouldBlock {
+                return Err(ClaimError::Lock(disk.to_owned(), why));
+            }
+
+            if waited >= LOCK_TIMEOUT {
+                return Err(ClaimError::Locked(disk.to_owned()));
+            }
+
+            std::thread::sleep(interval);
+            waited += interval;
+        }
+
+        Ok(Self {
+            disk: disk.to_owned(),
+            _file: file,
+        })
+    }
+
+    /// The disk which is claimed.
+    pub fn disk(&self) -> &str {
+        &self.disk
+    }
+}
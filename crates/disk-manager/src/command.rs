@@ -0,0 +1,63 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Running external tools, and capturing their output for error reporting.
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("failed to execute {0}")]
+    Spawn(&'static str, #[source] io::Error),
+    #[error("{0} failed: {1}")]
+    Failed(&'static str, String),
+}
+
+/// Run a command to completion, returning its standard output.
+pub fn run(program: &'static str, args: &[&str]) -> Result<String, CommandError> {
+    execute(program, args, None, |code| code == 0)
+}
+
+/// Run a command to completion with `stdin` as its input, where `success` decides which
+/// exit codes are successful.
+pub fn execute(
+    program: &'static str,
+    args: &[&str],
+    stdin: Option<&str>,
+    success: fn(i32) -> bool,
+) -> Result<String, CommandError> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|why| CommandError::Spawn(program, why))?;
+
+    if let Some(mut pipe) = child.stdin.take() {
+        if let Some(input) = stdin {
+            let _ = pipe.write_all(input.as_bytes());
+        }
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|why| CommandError::Spawn(program, why))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+
+    if output.status.code().map_or(false, success) {
+        Ok(stdout)
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = [stderr.trim(), stdout.trim()]
+            .iter()
+            .filter(|output| !output.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        Err(CommandError::Failed(program, message))
+    }
+}
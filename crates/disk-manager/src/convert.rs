@@ -0,0 +1,252 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! In-place conversion of MBR partition tables to GPT, preserving every partition.
+
+use crate::block_types::PartitionTable;
+use crate::gpt::{self, GptError, GptPartition, GptTable};
+use mbrman::{BOOT_ACTIVE, MBR};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// GPT attribute bit which marks a partition as bootable by legacy BIOS.
+pub const LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+
+/// Windows recovery environment
+const WINDOWS_RECOVERY: &str = "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC";
+
+#[derive(Debug, Error)]
+pub enum ConvertError {
+    #[error("failed to open {0:?}")]
+    Open(PathBuf, #[source] io::Error),
+    #[error("failed to read the disk")]
+    Read(#[source] io::Error),
+    #[error("disk does not have an MBR partition table")]
+    NotMbr(#[source] mbrman::Error),
+    #[error("disk already has a GUID partition table")]
+    AlreadyGpt,
+    #[error("partition {0} begins before sector {1}, which GPT requires for its header")]
+    StartConflict(u32, u64),
+    #[error("partition {0} ends after sector {1}, which GPT requires for its backup header")]
+    EndConflict(u32, u64),
+    #[error("GUID partition table error")]
+    Gpt(#[from] GptError),
+}
+
+/// A partition as it will exist in the converted table.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ConvertedPartition {
+    /// Partition number in the MBR table, which is kept in the GPT.
+    pub number: u32,
+    /// MBR system ID.
+    pub sys: u8,
+    /// GPT partition type GUID that the system ID maps to.
+    pub type_guid: String,
+    pub start: u64,
+    pub sectors: u64,
+    pub bootable: bool,
+}
+
+/// What a conversion would change, for reviewing before anything is written.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ConversionPlan {
+    pub partitions: Vec<ConvertedPartition>,
+    /// The first sector and length of the BIOS boot partition which will be added.
+    pub bios_boot: Option<(u64, u64)>,
+    /// Problems which do not prevent conversion, but may require attention.
+    pub warnings: Vec<String>,
+}
+
+/// Determine how the MBR partition table of a disk would be converted, without writing.
+///
+/// If `bios_boot` is set, a BIOS boot partition of that many sectors is planned for, if
+/// the disk has a free region which can hold it.
+pub fn plan(
+    path: &Path,
+    sector_size: u64,
+    bios_boot: Option<u64>,
+) -> Result<ConversionPlan, ConvertError> {
+    let mut file = File::open(path).map_err(|why| ConvertError::Open(path.to_owned(), why))?;
+
+    let sectors = file.seek(SeekFrom::End(0)).map_err(ConvertError::Read)? / sector_size;
+    file.seek(SeekFrom::Start(0)).map_err(ConvertError::Read)?;
+
+    let mbr = MBR::read_from(&mut file, sector_size as u32).map_err(ConvertError::NotMbr)?;
+    let (first, last) = PartitionTable::Gpt.usable_sectors(sector_size, sectors);
+
+    if mbr.iter().any(|(_, entry)| entry.sys == 0xEE) {
+        return Err(ConvertError::AlreadyGpt);
+    }
+
+    let primary = (1..=4usize)
+        .filter(|&number| mbr[number].is_used() && !crate::mbr::is_extended(mbr[number].sys))
+        .map(|number| (number as u32, &mbr[number]));
+
+    let logical = mbr
+        .logical_partitions
+        .iter()
+        .enumerate()
+        .map(|(id, logical)| (id as u32 + 5, &logical.partition));
+
+    let mut partitions = Vec::new();
+    let mut warnings = Vec::new();
+
+    for (number, entry) in primary.chain(logical) {
+        let start = u64::from(entry.starting_lba);
+        let length = u64::from(entry.sectors.max(1));
+
+        if start < first {
+            return Err(ConvertError::StartConflict(number, first));
+        }
+
+        if start + length - 1 > last {
+            return Err(ConvertError::EndConflict(number, last));
+        }
+
+        let type_guid = match type_guid(entry.sys) {
+            Some(guid) => guid,
+            None => {
+                warnings.push(format!(
+                    "partition {} has unknown system ID {:#04X}, and will be a Linux filesystem partition",
+                    number, entry.sys
+                ));
+                gpt::LINUX_FS
+            }
+        };
+
+        partitions.push(ConvertedPartition {
+            number,
+            sys: entry.sys,
+            type_guid: type_guid.to_owned(),
+            start,
+            sectors: length,
+            bootable: entry.boot == BOOT_ACTIVE,
+        });
+    }
+
+    partitions.sort_by_key(|part| part.start);
+
+    warnings.push("partition UUIDs will change, so PARTUUID references must be updated".to_owned());
+
+    // GRUB embeds its core image in the gap after the MBR on legacy installs.
+    let reserved = read_at(&file, sector_size, (first - 1) * sector_size)?;
+    if reserved.iter().any(|&byte| byte != 0) {
+        warnings.push(format!(
+            "sectors 1 to {} contain data, such as an embedded boot loader, which will be overwritten",
+            first - 1
+        ));
+    }
+
+    let bios_boot = bios_boot.and_then(|length| {
+        let place = free_place(&partitions, first, last, length, 1024 * 1024 / sector_size);
+
+        if place.is_none() {
+            warnings.push(format!(
+                "no free region can hold a BIOS boot partition of {} sectors",
+                length
+            ));
+        }
+
+        place.map(|start| (start, length))
+    });
+
+    Ok(ConversionPlan {
+        partitions,
+        bios_boot,
+        warnings,
+    })
+}
+
+/// Rewrite the MBR partition table of a disk as GPT, according to a [`plan`].
+pub fn convert(path: &Path, sector_size: u64, plan: &ConversionPlan) -> Result<(), ConvertError> {
+    let mut table = GptTable::create(path, sector_size)?;
+
+    for part in &plan.partitions {
+        let type_guid = gpt::parse_guid(&part.type_guid).unwrap_or_default();
+        let attributes = if part.bootable {
+            LEGACY_BIOS_BOOTABLE
+        } else {
+            0
+        };
+
+        let partition = GptPartition::new(type_guid, part.sectors)
+            .start(part.start)
+            .number(part.number)
+            .attributes(attributes);
+
+        table.add(&partition)?;
+    }
+
+    if let Some((start, sectors)) = plan.bios_boot {
+        let type_guid = gpt::parse_guid(gpt::BIOS_BOOT).unwrap_or_default();
+        let partition = GptPartition::new(type_guid, sectors)
+            .start(start)
+            .name("BIOS boot partition");
+
+        table.add(&partition)?;
+    }
+
+    eprintln!("converting partition table of {:?} to GPT", path);
+
+    table.commit()?;
+
+    Ok(())
+}
+
+/// The GPT partition type that an MBR system ID corresponds to.
+fn type_guid(sys: u8) -> Option<&'static str> {
+    let guid = match sys {
+        0x01 | 0x04 | 0x06 | 0x07 | 0x0B | 0x0C | 0x0E => gpt::MS_BASIC_DATA,
+        0x27 => WINDOWS_RECOVERY,
+        0x82 => gpt::LINUX_SWAP,
+        0x83 => gpt::LINUX_FS,
+        0x8E => gpt::LINUX_LVM,
+        0xEF => gpt::ESP,
+        0xFD => gpt::LINUX_RAID,
+        _ => return None,
+    };
+
+    Some(guid)
+}
+
+/// The first sector of a free region that can hold `length` sectors, aligned if possible.
+fn free_place(
+    partitions: &[ConvertedPartition],
+    first: u64,
+    last: u64,
+    length: u64,
+    alignment: u64,
+) -> Option<u64> {
+    let alignment = alignment.max(1);
+    let mut next = first;
+
+    let regions = partitions
+        .iter()
+        .map(|part| (part.start, part.start + part.sectors - 1))
+        .chain(std::iter::once((last + 1, last + 1)));
+
+    for (start, end) in regions {
+        if start > next {
+            let aligned = (next + alignment - 1) / alignment * alignment;
+
+            if aligned + length <= start {
+                return Some(aligned);
+            } else if next + length <= start {
+                return Some(next);
+            }
+        }
+
+        next = next.max(end + 1);
+    }
+
+    None
+}
+
+fn read_at(file: &File, offset: u64, length: u64) -> Result<Vec<u8>, ConvertError> {
+    let mut buffer = vec![0; length as usize];
+    file.read_exact_at(&mut buffer, offset)
+        .map_err(ConvertError::Read)?;
+    Ok(buffer)
+}
@@ -1,12 +1,25 @@
 // Copyright 2021 System76 <info@system76.com>
 // SPDX-License-Identifier: LGPL-3.0-only
 
+use crate::backup::{BackupError, TableBackup};
 use crate::block_types::*;
-use crate::udev::UDev;
+use crate::claim::{ClaimError, DiskClaim, Holder};
+use crate::convert::{ConversionPlan, ConvertError};
+use crate::gpt::{GptError, GptPartition, GptTable};
+use crate::index::{BlockIndex, Index};
+use crate::lvm::report::{LogicalVolume, PhysicalVolume, Report, ReportError};
+use crate::lvm::{LvSize, LvmError, VgActivation};
+use crate::mbr::{MbrError, MbrPartition, MbrTable};
+use crate::md::{MdError, MdLevel};
+use crate::mkfs::{Mkfs, MkfsError};
+use crate::mounts::{self, MountsError};
+use crate::resize::ResizeError;
+use crate::source::BlockSource;
 use crate::{ACell, ACellOwner};
 use libcryptsetup_rs::LibcryptErr;
-use std::collections::{BTreeMap, BTreeSet};
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 #[derive(Debug, Error)]
@@ -15,20 +28,125 @@ pub enum EncryptionError {
     DeviceNotFound,
     #[error("decryption failed")]
     Cryptsetup(#[source] LibcryptErr),
+    #[error("failed to change the activation of LVM volumes")]
+    Lvm(#[from] LvmError),
+}
+
+#[derive(Debug, Error)]
+pub enum PartitionError {
+    #[error("cannot modify a device which does not exist")]
+    DeviceNotFound,
+    #[error("{0} is not a disk")]
+    NotADisk(String),
+    #[error("{0} is read-only")]
+    ReadOnly(String),
+    #[error("{0} holds the live installation media")]
+    LiveMedia(String),
+    #[error(transparent)]
+    Claim(#[from] ClaimError),
+    #[error("partition table backup error")]
+    Backup(#[from] BackupError),
+    #[error("MBR to GPT conversion error")]
+    Convert(#[from] ConvertError),
+    #[error("GUID partition table error")]
+    Gpt(#[from] GptError),
+    #[error("MBR partition table error")]
+    Mbr(#[from] MbrError),
 }
 
 pub type DevName<'a> = &'a str;
 
+/// Where the live installation media is mounted.
+pub const LIVE_MEDIA_MOUNT: &str = "/cdrom";
+
+/// Devices which were added, removed, or changed by a [`DiskManager::reload`].
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ReloadDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Devices whose size, filesystem, partition table, or children have changed.
+    pub changed: Vec<String>,
+}
+
+impl ReloadDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// The properties of a device which are compared between reloads.
+#[derive(PartialEq)]
+struct Summary<'a> {
+    size: u64,
+    fs: Option<&'a FileSystem>,
+    table: Option<PartitionTable>,
+    children: Vec<&'a str>,
+}
+
+impl<'a> Summary<'a> {
+    fn new(block: &'a BlockDevice, t: &'a ACellOwner) -> Self {
+        let device = DiskManager::device_from_block(block, t);
+
+        let (table, partitions) = match block {
+            BlockDevice::Disk(disk) => {
+                let disk = disk.ro(t);
+                (disk.table, disk.children.as_slice())
+            }
+            _ => (None, &[][..]),
+        };
+
+        let partitions = partitions
+            .iter()
+            .map(|part| part.ro(t).device.name.as_str());
+
+        let maps = device
+            .children
+            .iter()
+            .map(|map| map.ro(t).device.name.as_str());
+
+        Self {
+            size: device.size,
+            fs: device.fs.as_ref(),
+            table,
+            children: partitions.chain(maps).collect(),
+        }
+    }
+}
+
 pub struct DiskManager {
-    pub dm: devicemapper::DM,
+    /// `None` for a manager which only describes a [`BlockSource`], such as a simulation.
+    pub dm: Option<devicemapper::DM>,
     pub blocks: BTreeMap<String, BlockDevice>,
+    /// Where partition tables are backed up to before they are written.
+    pub backup_dir: PathBuf,
+    /// Lookup tables for the identifiers of `blocks`.
+    pub index: BlockIndex,
+    /// LVM's view of its PVs, VGs, and LVs, as of the last reload of a source which
+    /// describes them, or [`DiskManager::reload_lvm`].
+    pub lvm: Report,
+    /// The temporary names of VGs imported by [`DiskManager::vg_activate_on`], with the
+    /// names that they are given back when their LUKS volumes are locked.
+    pub imported_vgs: BTreeMap<String, String>,
 }
 
 impl DiskManager {
     pub fn new(dm: devicemapper::DM) -> Self {
         Self {
-            dm,
+            dm: Some(dm),
+            ..Self::simulated()
+        }
+    }
+
+    /// A disk manager without access to devicemapper, for describing the devices of a
+    /// [`MemorySource`](crate::source::MemorySource) or [`UDevDb`](crate::udev_db::UDevDb).
+    pub fn simulated() -> Self {
+        Self {
+            dm: None,
             blocks: BTreeMap::new(),
+            backup_dir: PathBuf::from(crate::backup::DEFAULT_DIR),
+            index: BlockIndex::default(),
+            lvm: Report::default(),
+            imported_vgs: BTreeMap::new(),
         }
     }
 
@@ -41,34 +159,398 @@ impl DiskManager {
     pub fn block_by_part_uuid<'a>(
         &'a self,
         uuid: &str,
-        t: &ACellOwner,
+        _t: &ACellOwner,
     ) -> Option<(DevName<'a>, BlockDevice)> {
-        for (devname, block) in self.blocks.iter() {
-            if let BlockDevice::Partition(part) = block {
-                if part.ro(t).uuid == uuid {
-                    return Some((devname, block.clone()));
-                }
-            }
-        }
-
-        None
+        self.indexed(&self.index.part_uuid, &uuid.to_ascii_lowercase())
     }
 
     /// Locate a block device by FS UUID
     pub fn block_by_uuid<'a>(
         &'a self,
         uuid: &str,
-        t: &ACellOwner,
+        _t: &ACellOwner,
     ) -> Option<(DevName<'a>, BlockDevice)> {
-        for (devname, block) in self.blocks.iter() {
-            if let Some(fs) = Self::device_from_block(block, t).fs.as_ref() {
-                if fs.uuid == uuid {
-                    return Some((devname, block.clone()));
-                }
+        self.indexed(&self.index.uuid, &uuid.to_ascii_lowercase())
+    }
+
+    /// Locate a block device by FS label
+    pub fn block_by_label<'a>(
+        &'a self,
+        label: &str,
+        _t: &ACellOwner,
+    ) -> Option<(DevName<'a>, BlockDevice)> {
+        self.indexed(&self.index.label, label)
+    }
+
+    /// Locate a partition by its GPT partition name
+    pub fn block_by_partlabel<'a>(
+        &'a self,
+        label: &str,
+        _t: &ACellOwner,
+    ) -> Option<(DevName<'a>, BlockDevice)> {
+        self.indexed(&self.index.part_label, label)
+    }
+
+    /// Locate a block device by a symlink, such as `/dev/disk/by-id/wwn-0x5000c500a1b2c3d4`.
+    ///
+    /// Paths relative to `/dev/disk`, such as `by-path/pci-0000:00:17.0-ata-1`, are accepted.
+    pub fn block_by_id_path<'a>(
+        &'a self,
+        link: &str,
+        _t: &ACellOwner,
+    ) -> Option<(DevName<'a>, BlockDevice)> {
+        if link.starts_with('/') {
+            self.indexed(&self.index.links, link)
+        } else {
+            self.indexed(&self.index.links, &["/dev/disk/", link].concat())
+        }
+    }
+
+    /// Locate every partition with a GPT partition type GUID, or MBR system ID such as `0x83`.
+    pub fn blocks_by_part_type<'a>(
+        &'a self,
+        type_: &str,
+        _t: &ACellOwner,
+    ) -> Vec<(DevName<'a>, BlockDevice)> {
+        let devnames = match self.index.part_type.get(&type_.to_ascii_lowercase()) {
+            Some(devnames) => devnames.as_slice(),
+            None => &[],
+        };
+
+        devnames
+            .iter()
+            .filter_map(|devname| Some((devname.as_str(), self.blocks.get(devname)?.clone())))
+            .collect()
+    }
+
+    /// Locate a partition on a disk by its partition number.
+    pub fn partition_by_number(
+        &self,
+        disk: &str,
+        number: u32,
+        t: &ACellOwner,
+    ) -> Option<Arc<ACell<PartitionEntry>>> {
+        match self.blocks.get(disk)? {
+            BlockDevice::Disk(disk) => disk
+                .ro(t)
+                .children
+                .iter()
+                .find(|part| part.ro(t).number == number)
+                .cloned(),
+            _ => None,
+        }
+    }
+
+    /// The first device in an index with the given key.
+    fn indexed<'a>(&'a self, index: &'a Index, key: &str) -> Option<(DevName<'a>, BlockDevice)> {
+        let devname = index.get(key)?.first()?;
+        let block = self.blocks.get(devname)?;
+        Some((devname.as_str(), block.clone()))
+    }
+
+    /// Locate a disk by its `DEVNAME`.
+    pub fn disk_by_devname(
+        &self,
+        name: &str,
+        _t: &ACellOwner,
+    ) -> Result<Arc<ACell<Disk>>, PartitionError> {
+        match self.blocks.get(name) {
+            Some(BlockDevice::Disk(disk)) => Ok(disk.clone()),
+            Some(_) => Err(PartitionError::NotADisk(name.to_owned())),
+            None => Err(PartitionError::DeviceNotFound),
+        }
+    }
+
+    /// Locate the disk which contains a partition, which may be an MD RAID array.
+    pub fn disk_of_partition(&self, partition: &str, t: &ACellOwner) -> Option<Arc<ACell<Disk>>> {
+        match self.blocks.get(partition)? {
+            BlockDevice::Partition(_) => match self.blocks.get(self.parent_of(partition, t)?)? {
+                BlockDevice::Disk(disk) => Some(disk.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Locate the physical disk that a device is stacked on, such as the disk of an LV.
+    ///
+    /// A disk is its own root disk. A device on several disks, such as an LV with PVs on
+    /// each of them, is on the first of them.
+    pub fn root_disk_of(&self, devname: &str, t: &ACellOwner) -> Option<Arc<ACell<Disk>>> {
+        self.roots_of(devname, t)
+            .into_iter()
+            .find_map(|root| match self.blocks.get(root)? {
+                BlockDevice::Disk(disk) => Some(disk.clone()),
+                _ => None,
+            })
+    }
+
+    /// Whether a device is, or is stacked on, the live installation media.
+    pub fn is_live_media(&self, devname: &str, t: &ACellOwner) -> bool {
+        self.roots_of(devname, t)
+            .into_iter()
+            .any(|root| match self.blocks.get(root) {
+                Some(BlockDevice::Disk(disk)) => disk.ro(t).live_media,
+                Some(BlockDevice::Loop(lo)) => lo.ro(t).live_media,
+                _ => false,
+            })
+    }
+
+    /// The `DEVNAME` of the first device that a device is on top of.
+    pub fn parent_of<'a>(&'a self, devname: &str, t: &'a ACellOwner) -> Option<DevName<'a>> {
+        self.parents_of(devname, t).first().map(String::as_str)
+    }
+
+    /// The `DEVNAME` of each device that a device is on top of.
+    pub fn parents_of<'a>(&'a self, devname: &str, t: &'a ACellOwner) -> &'a [String] {
+        match self.blocks.get(devname) {
+            Some(block) => &Self::device_from_block(block, t).parents,
+            None => &[],
+        }
+    }
+
+    /// The devices that a device is stacked on, nearest first, down to the disks at the
+    /// bottom of the stack.
+    ///
+    /// Devices which several of its ancestors are on top of are only visited once.
+    pub fn ancestors<'a>(
+        &'a self,
+        devname: &str,
+        t: &'a ACellOwner,
+    ) -> impl Iterator<Item = DevName<'a>> + 'a {
+        let mut pending = self
+            .parents_of(devname, t)
+            .iter()
+            .map(String::as_str)
+            .collect::<VecDeque<_>>();
+
+        let mut visited = BTreeSet::new();
+
+        std::iter::from_fn(move || loop {
+            let devname = pending.pop_front()?;
+
+            if visited.insert(devname) {
+                pending.extend(self.parents_of(devname, t).iter().map(String::as_str));
+                return Some(devname);
             }
+        })
+    }
+
+    /// The devices at the bottom of the stack that a device is on.
+    ///
+    /// A device which is not on top of another device is its own root.
+    pub fn roots_of<'a>(&'a self, devname: &'a str, t: &'a ACellOwner) -> Vec<DevName<'a>> {
+        let roots = self
+            .ancestors(devname, t)
+            .filter(|ancestor| self.parents_of(ancestor, t).is_empty())
+            .collect::<Vec<_>>();
+
+        if roots.is_empty() {
+            vec![devname]
+        } else {
+            roots
         }
+    }
 
-        None
+    /// The devices directly on top of a device: the partitions of a disk, device maps, and
+    /// MD RAID arrays.
+    pub fn holders<'a>(
+        &'a self,
+        devname: &str,
+        t: &'a ACellOwner,
+    ) -> impl Iterator<Item = DevName<'a>> + 'a {
+        let block = self.blocks.get(devname);
+
+        let partitions = match block {
+            Some(BlockDevice::Disk(disk)) => disk.ro(t).children.as_slice(),
+            _ => &[],
+        };
+
+        let maps = match block {
+            Some(block) => Self::device_from_block(block, t).children.as_slice(),
+            None => &[],
+        };
+
+        let partitions = partitions
+            .iter()
+            .map(move |part| part.ro(t).device.name.as_str());
+
+        let maps = maps.iter().map(move |map| map.ro(t).device.name.as_str());
+
+        let devname = devname.to_owned();
+
+        let arrays = self
+            .blocks
+            .iter()
+            .filter(move |(_, block)| match block {
+                BlockDevice::Disk(disk) => disk
+                    .ro(t)
+                    .md
+                    .as_ref()
+                    .map_or(false, |md| md.members.contains(&devname)),
+                _ => false,
+            })
+            .map(|(name, _)| name.as_str());
+
+        partitions.chain(maps).chain(arrays)
+    }
+
+    /// The MD RAID array which a device is a member of.
+    pub fn md_of_member(&self, devname: &str, t: &ACellOwner) -> Option<Arc<ACell<Disk>>> {
+        self.blocks.values().find_map(|block| match block {
+            BlockDevice::Disk(disk) => {
+                let md = disk.ro(t).md.as_ref()?;
+                md.members
+                    .iter()
+                    .any(|member| member == devname)
+                    .then(|| disk.clone())
+            }
+            _ => None,
+        })
+    }
+
+    /// Every device stacked on top of a device, depth-first, with holders before their own.
+    pub fn descendants<'a>(
+        &'a self,
+        devname: &str,
+        t: &'a ACellOwner,
+    ) -> impl Iterator<Item = DevName<'a>> + 'a {
+        let mut stack = self.holders(devname, t).collect::<Vec<_>>();
+        stack.reverse();
+
+        std::iter::from_fn(move || {
+            let next = stack.pop()?;
+
+            let start = stack.len();
+            stack.extend(self.holders(next, t));
+            stack[start..].reverse();
+
+            Some(next)
+        })
+    }
+
+    /// Save a copy of the partition table of a disk into `backup_dir`, returning its path.
+    pub fn backup_table(&self, disk: &str, t: &ACellOwner) -> Result<PathBuf, PartitionError> {
+        let sector_size = self.disk_by_devname(disk, t)?.ro(t).sector_size;
+        let path = crate::backup::save_current(Path::new(disk), sector_size, &self.backup_dir)?;
+        Ok(path)
+    }
+
+    /// Write a partition table backup back to the disk it was taken from.
+    pub fn restore_table(
+        &mut self,
+        disk: &str,
+        backup: &Path,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), PartitionError> {
+        self.ensure_unused(disk, t)?;
+        let (sector_size, claim) = self.prepare_write(disk, false, t)?;
+
+        TableBackup::load(backup)?.restore(Path::new(disk), sector_size)?;
+
+        drop(claim);
+        crate::udev::settle();
+        self.reload(udev, t);
+
+        Ok(())
+    }
+
+    /// Everything which is using a device, or a device on top of it.
+    pub fn busy(&self, devname: &str, t: &ACellOwner) -> Vec<Holder> {
+        crate::claim::holders(self, devname, t)
+    }
+
+    /// Fail with [`ClaimError::Busy`] if anything is using a device, or a device on top of it.
+    pub fn ensure_unused(&self, devname: &str, t: &ACellOwner) -> Result<(), ClaimError> {
+        if !self.blocks.contains_key(devname) {
+            return Err(ClaimError::DeviceNotFound);
+        }
+
+        let holders = self.busy(devname, t);
+
+        if holders.is_empty() {
+            Ok(())
+        } else {
+            Err(ClaimError::Busy(devname.to_owned(), holders))
+        }
+    }
+
+    /// Fail with [`ClaimError::Busy`] if a partition of a disk is in use.
+    ///
+    /// The logical partitions inside of an extended partition are checked along with it.
+    fn ensure_partition_unused(
+        &self,
+        disk: &str,
+        number: u32,
+        t: &ACellOwner,
+    ) -> Result<(), ClaimError> {
+        let children = match self.blocks.get(disk) {
+            Some(BlockDevice::Disk(disk)) => &disk.ro(t).children,
+            _ => return Ok(()),
+        };
+
+        let extended = children.iter().any(|child| {
+            let child = child.ro(t);
+            child.number == number && child.kind == PartitionKind::Extended
+        });
+
+        for child in children {
+            let child = child.ro(t);
+
+            if child.number == number || (extended && child.kind == PartitionKind::Logical) {
+                self.ensure_unused(&child.device.name, t)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lock a disk so that udev does not probe it while it is written.
+    pub fn claim(&self, disk: &str, _t: &ACellOwner) -> Result<DiskClaim, ClaimError> {
+        match self.blocks.get(disk) {
+            Some(BlockDevice::Disk(_)) => DiskClaim::new(disk),
+            _ => Err(ClaimError::DeviceNotFound),
+        }
+    }
+
+    /// Fail if a disk is read-only, or holds the live installation media.
+    pub(crate) fn ensure_writable(&self, disk: &str, t: &ACellOwner) -> Result<(), PartitionError> {
+        let cell = self.disk_by_devname(disk, t)?;
+        let info = cell.ro(t);
+
+        if info.read_only {
+            return Err(PartitionError::ReadOnly(disk.to_owned()));
+        }
+
+        // Arrays are not flagged themselves, but may be stacked on the live media.
+        if self.is_live_media(disk, t) {
+            return Err(PartitionError::LiveMedia(disk.to_owned()));
+        }
+
+        Ok(())
+    }
+
+    /// Ensure that a disk may be written to, back up its existing partition table, and
+    /// claim it for the duration of the write.
+    ///
+    /// Returns the sector size of the disk.
+    pub(crate) fn prepare_write(
+        &self,
+        disk: &str,
+        backup: bool,
+        t: &ACellOwner,
+    ) -> Result<(u64, DiskClaim), PartitionError> {
+        self.ensure_writable(disk, t)?;
+
+        let cell = self.disk_by_devname(disk, t)?;
+        let info = cell.ro(t);
+
+        if backup && info.table.is_some() {
+            self.backup_table(disk, t)?;
+        }
+
+        Ok((info.sector_size, self.claim(disk, t)?))
     }
 
     /// Every block device has a `device` field.
@@ -77,6 +559,17 @@ impl DiskManager {
             BlockDevice::Disk(disk) => &disk.ro(t).device,
             BlockDevice::Partition(entry) => &entry.ro(t).device,
             BlockDevice::DeviceMap(dm) => &dm.ro(t).device,
+            BlockDevice::Loop(lo) => &lo.ro(t).device,
+        }
+    }
+
+    /// Mutable access to the `device` field of a block device.
+    fn device_from_block_mut<'a>(dev: &'a BlockDevice, t: &'a mut ACellOwner) -> &'a mut Device {
+        match dev {
+            BlockDevice::Disk(disk) => &mut disk.rw(t).device,
+            BlockDevice::Partition(entry) => &mut entry.rw(t).device,
+            BlockDevice::DeviceMap(dm) => &mut dm.rw(t).device,
+            BlockDevice::Loop(lo) => &mut lo.rw(t).device,
         }
     }
 
@@ -108,105 +601,2086 @@ impl DiskManager {
         None
     }
 
-    /// Reload block device information from the system.
-    pub fn reload(&mut self, udev: &mut UDev, t: &mut ACellOwner) {
-        self.blocks.clear();
+    /// Query LVM for its PVs, VGs, and LVs.
+    ///
+    /// A reload of [`UDev`](crate::UDev) also does this, but a simulated source does not.
+    /// The report is linked to the devices of the disk manager on the next reload.
+    pub fn reload_lvm(&mut self) -> Result<(), ReportError> {
+        self.lvm = crate::lvm::report::report()?;
+        Ok(())
+    }
+
+    /// Refresh the LVM report before LVM is modified.
+    ///
+    /// A simulated disk manager has no LVM to query, so it takes the report of its source.
+    fn refresh_lvm(&mut self, udev: &mut dyn BlockSource) -> Result<(), ReportError> {
+        if self.dm.is_some() {
+            return self.reload_lvm();
+        }
+
+        if let Some(report) = udev.lvm_report() {
+            self.lvm = report;
+        }
+
+        Ok(())
+    }
+
+    /// The LVM PV on a partition, disk, or device map.
+    pub fn physical_volume(&self, devname: &str) -> Option<&PhysicalVolume> {
+        self.lvm.pv(devname)
+    }
 
-        let devices = match udev.enumerator.scan_devices() {
-            Ok(devices) => devices.collect::<Vec<_>>(),
-            Err(_) => return,
+    /// The LVM LV which a device map belongs to.
+    pub fn logical_volume(&self, devname: &str, t: &ACellOwner) -> Option<&LogicalVolume> {
+        let map = match self.blocks.get(devname)? {
+            BlockDevice::DeviceMap(map) => map.ro(t),
+            _ => return None,
         };
 
-        for device in devices {
-            udev.append(self, &device, t);
-        }
+        self.lvm
+            .lv(map.vg_name.as_deref()?, map.lv_name.as_deref()?)
     }
 
-    /// Close a LUKS partition with libcryptsetup, deactivating its volumes.
-    pub fn luks_lock(
+    /// The known devices which hold the PVs of a VG.
+    pub fn devices_of_vg<'a>(&'a self, vg: &'a str) -> impl Iterator<Item = DevName<'a>> + 'a {
+        self.lvm
+            .pvs_of(vg)
+            .filter(move |pv| self.blocks.contains_key(&pv.devname))
+            .map(|pv| pv.devname.as_str())
+    }
+
+    /// Write a new, empty GUID partition table to a disk.
+    pub fn gpt_create(
         &mut self,
-        device: &str,
-        name: &str,
-        udev: &mut UDev,
+        disk: &str,
+        udev: &mut dyn BlockSource,
         t: &mut ACellOwner,
-    ) -> Result<(), EncryptionError> {
-        // Check if the device to be locked exists.
-        let dev = self
-            .blocks
-            .get(device)
-            .ok_or(EncryptionError::DeviceNotFound)?;
+    ) -> Result<(), PartitionError> {
+        self.ensure_unused(disk, t)?;
+        let (sector_size, claim) = self.prepare_write(disk, true, t)?;
 
-        // Get the children of this device that need to be deactivated.
-        let dev = Self::device_from_block(dev, t);
+        GptTable::create(Path::new(disk), sector_size)?.commit()?;
 
-        // Determine which VGs need to be deactivated, and deactivate them;
-        {
-            let mut children = dev.children.clone();
+        drop(claim);
+        crate::udev::settle();
+        self.reload(udev, t);
 
-            let mut vgs_to_suspend = BTreeSet::new();
-            let mut luks_to_lock = vec![device.to_owned()];
+        Ok(())
+    }
 
-            while let Some(child_) = children.pop() {
-                let child = child_.ro(t);
+    /// Apply a set of changes to the existing GUID partition table of a disk.
+    ///
+    /// The table is only written if `edit` succeeds.
+    pub fn gpt_edit<F, T>(
+        &mut self,
+        disk: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+        edit: F,
+    ) -> Result<T, PartitionError>
+    where
+        F: FnOnce(&mut GptTable) -> Result<T, GptError>,
+    {
+        let (sector_size, claim) = self.prepare_write(disk, true, t)?;
 
-                if let Some(vg) = child.vg_name.clone() {
-                    vgs_to_suspend.insert(vg);
-                }
+        let mut table = GptTable::open(Path::new(disk), sector_size)?;
+        let value = edit(&mut table)?;
+        table.commit()?;
 
-                if let Some(fs) = child.device.fs.as_ref() {
-                    if fs.type_ == "crypto_LUKS" {
-                        luks_to_lock.push(child.device.name.clone());
-                    }
-                }
+        drop(claim);
+        crate::udev::settle();
+        self.reload(udev, t);
 
-                children.extend(child.device.children.iter().cloned())
-            }
+        Ok(value)
+    }
 
-            for vg in vgs_to_suspend {
-                let _ = crate::lvm::vg_deactivate(&vg).unwrap();
-            }
+    /// Add a partition to a disk with a GUID partition table, returning its partition number.
+    pub fn gpt_add_partition(
+        &mut self,
+        disk: &str,
+        partition: &GptPartition,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<u32, PartitionError> {
+        self.gpt_edit(disk, udev, t, |table| table.add(partition))
+    }
 
-            for luks in luks_to_lock {
-                if let Err(why) = crate::luks::deactivate(Path::new(&luks), name) {
-                    return Err(EncryptionError::Cryptsetup(why));
-                }
-            }
-        }
+    /// Remove a partition from a disk with a GUID partition table.
+    pub fn gpt_remove_partition(
+        &mut self,
+        disk: &str,
+        number: u32,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), PartitionError> {
+        self.ensure_partition_unused(disk, number, t)?;
+        self.gpt_edit(disk, udev, t, |table| table.remove(number))
+    }
+
+    /// Write a new, empty MBR partition table to a disk.
+    pub fn mbr_create(
+        &mut self,
+        disk: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), PartitionError> {
+        self.ensure_unused(disk, t)?;
+        let (sector_size, claim) = self.prepare_write(disk, true, t)?;
+
+        MbrTable::create(Path::new(disk), sector_size)?.commit()?;
 
+        drop(claim);
+        crate::udev::settle();
         self.reload(udev, t);
 
         Ok(())
     }
 
-    /// Unlock a LUKS partitition, and activate its volumes.
-    pub fn luks_unlock(
+    /// Apply a set of changes to the existing MBR partition table of a disk.
+    ///
+    /// The table is only written if `edit` succeeds.
+    pub fn mbr_edit<F, T>(
         &mut self,
-        device: &str,
-        dm_name: &str,
-        key: &[u8],
-        udev: &mut UDev,
+        disk: &str,
+        udev: &mut dyn BlockSource,
         t: &mut ACellOwner,
-    ) -> Result<(), EncryptionError> {
-        // Check if the LUKS partition exists.
-        if self.blocks.get(device).is_none() {
-            return Err(EncryptionError::DeviceNotFound);
-        }
+        edit: F,
+    ) -> Result<T, PartitionError>
+    where
+        F: FnOnce(&mut MbrTable) -> Result<T, MbrError>,
+    {
+        let (sector_size, claim) = self.prepare_write(disk, true, t)?;
 
-        // Decrypt the LUKS partition with libcryptsetup.
-        if let Err(why) = crate::luks::activate(Path::new(device), dm_name, key) {
-            return Err(EncryptionError::Cryptsetup(why));
+        let mut table = MbrTable::open(Path::new(disk), sector_size)?;
+        let value = edit(&mut table)?;
+        table.commit()?;
+
+        drop(claim);
+        crate::udev::settle();
+        self.reload(udev, t);
+
+        Ok(value)
+    }
+
+    /// Add a primary, extended, or logical partition to a disk with an MBR partition table,
+    /// returning its partition number.
+    pub fn mbr_add_partition(
+        &mut self,
+        disk: &str,
+        partition: &MbrPartition,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<usize, PartitionError> {
+        self.mbr_edit(disk, udev, t, |table| table.add(partition))
+    }
+
+    /// Remove a partition from a disk with an MBR partition table.
+    pub fn mbr_remove_partition(
+        &mut self,
+        disk: &str,
+        number: usize,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), PartitionError> {
+        if let Ok(number) = u32::try_from(number) {
+            self.ensure_partition_unused(disk, number, t)?;
         }
 
-        // Ensure that any volume groups that may have been on this partition are activated.
-        let _ = crate::lvm::vg_activate_all();
+        self.mbr_edit(disk, udev, t, |table| table.remove(number))
+    }
 
-        // Ensure that the newly-created device map has been activated.
+    /// Determine how the MBR partition table of a disk would be converted to GPT.
+    ///
+    /// Nothing is written to the disk, so this may be used as a dry run.
+    pub fn mbr_to_gpt_plan(
+        &self,
+        disk: &str,
+        bios_boot: Option<u64>,
+        t: &ACellOwner,
+    ) -> Result<ConversionPlan, PartitionError> {
+        let sector_size = self.disk_by_devname(disk, t)?.ro(t).sector_size;
+        let plan = crate::convert::plan(Path::new(disk), sector_size, bios_boot)?;
+        Ok(plan)
+    }
+
+    /// Convert the MBR partition table of a disk to GPT in place, according to a plan
+    /// from [`DiskManager::mbr_to_gpt_plan`].
+    pub fn mbr_to_gpt(
+        &mut self,
+        disk: &str,
+        plan: &ConversionPlan,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), PartitionError> {
+        // The live media is always in use, but is reported as the live media.
+        self.ensure_writable(disk, t)?;
+        self.ensure_unused(disk, t)?;
+        let (sector_size, claim) = self.prepare_write(disk, true, t)?;
+
+        crate::convert::convert(Path::new(disk), sector_size, plan)?;
+
+        drop(claim);
+        crate::udev::settle();
         self.reload(udev, t);
-        while self.dm_by_dm_name(dm_name, t).is_none() {
-            std::thread::sleep(std::time::Duration::from_secs(1));
-            self.reload(udev, t);
-        }
 
         Ok(())
     }
+
+    /// Move and/or resize a partition, along with the filesystem inside of it.
+    ///
+    /// `start` and `sectors` are in logical sectors of the partition's disk.
+    pub fn resize_partition(
+        &mut self,
+        partition: &str,
+        start: u64,
+        sectors: u64,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), ResizeError> {
+        crate::resize::resize(self, partition, start, sectors, udev, t)
+    }
+
+    /// Create a filesystem on a block device, and reload to pick up its new `FileSystem`.
+    pub fn mkfs(
+        &mut self,
+        devname: &str,
+        options: &Mkfs,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), MkfsError> {
+        if !self.blocks.contains_key(devname) {
+            return Err(MkfsError::DeviceNotFound);
+        }
+
+        if self.is_live_media(devname, t) {
+            return Err(MkfsError::LiveMedia(devname.to_owned()));
+        }
+
+        self.ensure_unused(devname, t)?;
+        crate::mkfs::format(devname, options)?;
+
+        crate::udev::settle();
+        self.reload(udev, t);
+
+        Ok(())
+    }
+
+    /// Reload block device information from a source, such as [`UDev`](crate::UDev).
+    ///
+    /// Devices which still exist keep their cells, which are updated in place, so that
+    /// references held elsewhere remain valid.
+    pub fn reload(&mut self, udev: &mut dyn BlockSource, t: &mut ACellOwner) -> ReloadDiff {
+        let previous = std::mem::take(&mut self.blocks);
+
+        udev.for_each(&mut |device, parents| crate::udev::append(self, device, parents, t));
+
+        let mut diff = ReloadDiff::default();
+
+        for (devname, block) in self.blocks.iter_mut() {
+            let old = match previous.get(devname) {
+                Some(old) => old,
+                None => {
+                    diff.added.push(devname.clone());
+                    continue;
+                }
+            };
+
+            if Summary::new(old, t) != Summary::new(block, t) {
+                diff.changed.push(devname.clone());
+            }
+
+            // Move the new contents into the previous cell.
+            match (old, &*block) {
+                (BlockDevice::Disk(old), BlockDevice::Disk(new)) => {
+                    let (old, new) = t.rw2(old, new);
+                    std::mem::swap(old, new);
+                }
+                (BlockDevice::Partition(old), BlockDevice::Partition(new)) => {
+                    let (old, new) = t.rw2(old, new);
+                    std::mem::swap(old, new);
+                }
+                (BlockDevice::DeviceMap(old), BlockDevice::DeviceMap(new)) => {
+                    let (old, new) = t.rw2(old, new);
+                    std::mem::swap(old, new);
+                }
+                (BlockDevice::Loop(old), BlockDevice::Loop(new)) => {
+                    let (old, new) = t.rw2(old, new);
+                    std::mem::swap(old, new);
+                }
+                _ => continue,
+            }
+
+            *block = old.clone();
+        }
+
+        diff.removed = previous
+            .keys()
+            .filter(|devname| !self.blocks.contains_key(*devname))
+            .cloned()
+            .collect();
+
+        self.relink(t);
+        self.link_md_members(t);
+        self.attach_mounts(udev, t);
+
+        if let Some(report) = udev.lvm_report() {
+            self.lvm = report;
+        }
+
+        self.link_lvm(t);
+        self.mark_live_media(t);
+        self.index = BlockIndex::new(self, t);
+
+        diff
+    }
+
+    /// Flag the disk and loop devices which hold the live installation media.
+    fn mark_live_media(&self, t: &mut ACellOwner) {
+        let media = self.blocks.iter().find_map(|(devname, block)| {
+            let device = Self::device_from_block(block, t);
+            let mounted = device
+                .mounts
+                .iter()
+                .any(|target| target == Path::new(LIVE_MEDIA_MOUNT));
+            mounted.then(|| devname.as_str())
+        });
+
+        if let Some(disk) = media.and_then(|media| self.root_disk_of(media, t)) {
+            disk.rw(t).live_media = true;
+        }
+
+        for block in self.blocks.values() {
+            if let BlockDevice::Loop(lo) = block {
+                let live = media.is_some() && lo.ro(t).backing_file.starts_with(LIVE_MEDIA_MOUNT);
+                lo.rw(t).live_media = live;
+            }
+        }
+    }
+
+    /// Record where each device is mounted, and whether it is in use as swap.
+    ///
+    /// Only the source knows whether its devices are those of this system, whose mounts
+    /// and swap are in `/proc`.
+    fn attach_mounts(&self, udev: &mut dyn BlockSource, t: &mut ACellOwner) {
+        let mut targets = BTreeMap::<String, Vec<PathBuf>>::new();
+
+        for (devname, target) in udev.mounts() {
+            targets.entry(devname).or_default().push(target);
+        }
+
+        let swaps = udev.swaps().into_iter().collect::<BTreeSet<_>>();
+
+        for (devname, block) in &self.blocks {
+            let device = Self::device_from_block_mut(block, t);
+            device.mounts = targets.remove(devname).unwrap_or_default();
+            device.swap = swaps.contains(devname);
+        }
+    }
+
+    /// Unmount every filesystem and disable all swap on a disk, and the devices on top of it.
+    ///
+    /// Devices are released from the top of the stack down, swap before filesystems, and
+    /// nested mount points before the mount points they are inside of.
+    pub fn unmount_disk(
+        &mut self,
+        disk: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), MountsError> {
+        if !self.blocks.contains_key(disk) {
+            return Err(MountsError::DeviceNotFound);
+        }
+
+        let mut stack = std::iter::once(disk)
+            .chain(self.descendants(disk, t))
+            .collect::<Vec<_>>();
+
+        stack.reverse();
+
+        let mut swaps = Vec::new();
+        let mut targets = Vec::new();
+
+        for devname in stack {
+            if let Some(block) = self.blocks.get(devname) {
+                let device = Self::device_from_block(block, t);
+
+                if device.swap {
+                    swaps.push(devname.to_owned());
+                }
+
+                targets.extend(device.mounts.iter().cloned());
+            }
+        }
+
+        // Arrays with several members on the disk are reached through each of them.
+        swaps.sort();
+        swaps.dedup();
+        targets.sort();
+        targets.dedup();
+
+        targets.sort_by_key(|target| std::cmp::Reverse(target.components().count()));
+
+        for swap in swaps {
+            mounts::swapoff(&swap)?;
+        }
+
+        for target in targets {
+            mounts::unmount(&target)?;
+        }
+
+        crate::udev::settle();
+        self.reload(udev, t);
+
+        Ok(())
+    }
+
+    /// Point the children of every device at the cells stored in `blocks`.
+    fn relink(&self, t: &mut ACellOwner) {
+        for block in self.blocks.values() {
+            if let BlockDevice::Disk(disk) = block {
+                let partitions = disk
+                    .ro(t)
+                    .children
+                    .iter()
+                    .filter_map(|child| match self.blocks.get(&child.ro(t).device.name) {
+                        Some(BlockDevice::Partition(part)) => Some(part.clone()),
+                        _ => None,
+                    })
+                    .collect();
+
+                disk.rw(t).children = partitions;
+            }
+
+            let maps = Self::device_from_block(block, t)
+                .children
+                .iter()
+                .filter_map(|child| match self.blocks.get(&child.ro(t).device.name) {
+                    Some(BlockDevice::DeviceMap(map)) => Some(map.clone()),
+                    _ => None,
+                })
+                .collect();
+
+            match block {
+                BlockDevice::Disk(disk) => disk.rw(t).device.children = maps,
+                BlockDevice::Partition(part) => part.rw(t).device.children = maps,
+                BlockDevice::DeviceMap(map) => map.rw(t).device.children = maps,
+                BlockDevice::Loop(lo) => lo.rw(t).device.children = maps,
+            }
+        }
+    }
+
+    /// Attach the PVs and LVs of the LVM report to the devices which they are on.
+    fn link_lvm(&self, t: &mut ACellOwner) {
+        for (devname, block) in &self.blocks {
+            let pv = self.lvm.pv(devname).cloned();
+            Self::device_from_block_mut(block, t).pv = pv;
+
+            if let BlockDevice::DeviceMap(map) = block {
+                let lv = {
+                    let map = map.ro(t);
+                    match (map.vg_name.as_deref(), map.lv_name.as_deref()) {
+                        (Some(vg), Some(lv)) => self.lvm.lv(vg, lv).cloned(),
+                        _ => None,
+                    }
+                };
+
+                map.rw(t).lv = lv;
+            }
+        }
+    }
+
+    /// Record the members of each MD RAID array, which are the `linux_raid_member` devices
+    /// that share its UUID.
+    fn link_md_members(&self, t: &mut ACellOwner) {
+        let mut members = BTreeMap::<String, Vec<String>>::new();
+
+        for (devname, block) in &self.blocks {
+            if let Some(fs) = Self::device_from_block(block, t).fs.as_ref() {
+                if fs.type_ == "linux_raid_member" {
+                    members
+                        .entry(crate::md::uuid_key(&fs.uuid))
+                        .or_default()
+                        .push(devname.clone());
+                }
+            }
+        }
+
+        for block in self.blocks.values() {
+            if let BlockDevice::Disk(disk) = block {
+                let uuid = match disk.ro(t).md.as_ref() {
+                    Some(md) => crate::md::uuid_key(&md.uuid),
+                    None => continue,
+                };
+
+                let found = members.remove(&uuid).unwrap_or_default();
+
+                // Keep the members reported by the source if the array has no known UUID.
+                if found.is_empty() {
+                    continue;
+                }
+
+                let disk = disk.rw(t);
+
+                for member in &found {
+                    if !disk.device.parents.contains(member) {
+                        disk.device.parents.push(member.clone());
+                    }
+                }
+
+                if let Some(md) = disk.md.as_mut() {
+                    md.members = found;
+                }
+            }
+        }
+    }
+
+    /// Close a LUKS partition with libcryptsetup, deactivating its volumes.
+    pub fn luks_lock(
+        &mut self,
+        device: &str,
+        name: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), EncryptionError> {
+        // Check if the device to be locked exists.
+        if !self.blocks.contains_key(device) {
+            return Err(EncryptionError::DeviceNotFound);
+        }
+
+        // Determine which VGs need to be deactivated, and deactivate them;
+        {
+            let mut vgs_to_suspend = BTreeSet::new();
+            let mut luks_to_lock = vec![device.to_owned()];
+
+            for child in self.descendants(device, t) {
+                let child = match self.blocks.get(child) {
+                    Some(BlockDevice::DeviceMap(child)) => child.ro(t),
+                    _ => continue,
+                };
+
+                if let Some(vg) = child.vg_name.clone() {
+                    vgs_to_suspend.insert(vg);
+                }
+
+                if let Some(fs) = child.device.fs.as_ref() {
+                    if fs.type_ == "crypto_LUKS" {
+                        luks_to_lock.push(child.device.name.clone());
+                    }
+                }
+            }
+
+            for vg in vgs_to_suspend {
+                crate::lvm::vg_deactivate(&vg)?;
+
+                if let Some(original) = self.imported_vgs.get(&vg) {
+                    let pvs = self.lvm.pvs_of(&vg).map(|pv| pv.devname.as_str());
+                    crate::lvm::vg_rename_on(&pvs.collect::<Vec<_>>(), &vg, original)?;
+                    self.imported_vgs.remove(&vg);
+                }
+            }
+
+            for luks in luks_to_lock {
+                if let Err(why) = crate::luks::deactivate(Path::new(&luks), name) {
+                    return Err(EncryptionError::Cryptsetup(why));
+                }
+            }
+        }
+
+        self.reload(udev, t);
+
+        Ok(())
+    }
+
+    /// Unlock a LUKS partitition, and activate its volumes.
+    pub fn luks_unlock(
+        &mut self,
+        device: &str,
+        dm_name: &str,
+        key: &[u8],
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), EncryptionError> {
+        // Check if the LUKS partition exists.
+        if self.blocks.get(device).is_none() {
+            return Err(EncryptionError::DeviceNotFound);
+        }
+
+        // Decrypt the LUKS partition with libcryptsetup.
+        if let Err(why) = crate::luks::activate(Path::new(device), dm_name, key) {
+            return Err(EncryptionError::Cryptsetup(why));
+        }
+
+        // Ensure that the newly-created device map has been activated.
+        self.reload(udev, t);
+        let map = loop {
+            if let Some(map) = self.dm_by_dm_name(dm_name, t) {
+                break map.ro(t).device.name.clone();
+            }
+
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            self.reload(udev, t);
+        };
+
+        // Activate only the volume groups which are on this partition.
+        self.vg_activate_on(&map, udev, t)?;
+
+        Ok(())
+    }
+
+    /// Activate the VGs which have a PV on a device, such as an unlocked LUKS volume, and
+    /// return their names.
+    ///
+    /// A VG which shares its name with another that is active, such as the `data` VG of a
+    /// second install, is imported under a temporary name so that both may be active at
+    /// once. Other VGs are activated by UUID, without modifying them.
+    pub fn vg_activate_on(
+        &mut self,
+        devname: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<Vec<String>, LvmError> {
+        self.refresh_lvm(udev)?;
+
+        let mut activated = Vec::new();
+
+        for activation in self.vg_activations(devname, t)? {
+            match activation {
+                VgActivation::Uuid { vg, uuid } => {
+                    crate::lvm::vg_activate_uuid(&uuid)?;
+                    activated.push(vg);
+                }
+
+                VgActivation::Import { vg, name, pvs, .. } => {
+                    let pvs = pvs.iter().map(String::as_str).collect::<Vec<_>>();
+                    crate::lvm::vg_import_clone(&name, &pvs)?;
+                    self.imported_vgs.insert(name.clone(), vg);
+                    crate::lvm::vg_activate(&name)?;
+                    activated.push(name);
+                }
+            }
+        }
+
+        self.lvm_refresh(udev, t)?;
+
+        Ok(activated)
+    }
+
+    /// How each of the VGs which have a PV on a device would be activated by
+    /// [`DiskManager::vg_activate_on`].
+    pub fn vg_activations(
+        &self,
+        devname: &str,
+        t: &ACellOwner,
+    ) -> Result<Vec<VgActivation>, LvmError> {
+        let mut activations = Vec::new();
+        let mut taken = Vec::new();
+
+        for pv in self.lvm.pvs.iter().filter(|pv| pv.devname == devname) {
+            let (vg, uuid) = match (pv.vg_name.clone(), pv.vg_uuid.clone()) {
+                (Some(vg), Some(uuid)) => (vg, uuid),
+                _ => continue,
+            };
+
+            let pvs = self
+                .lvm
+                .pvs
+                .iter()
+                .filter(|pv| pv.vg_uuid.as_deref() == Some(uuid.as_str()))
+                .map(|pv| pv.devname.clone())
+                .collect::<Vec<_>>();
+
+            // An active LV of the same VG name which is not on its PVs is of another VG.
+            let collides = self.blocks.values().any(|block| match block {
+                BlockDevice::DeviceMap(map) => {
+                    let map = map.ro(t);
+                    map.vg_name.as_deref() == Some(vg.as_str())
+                        && !self
+                            .ancestors(&map.device.name, t)
+                            .any(|dev| pvs.iter().any(|pv| pv == dev))
+                }
+                _ => false,
+            });
+
+            if !collides {
+                activations.push(VgActivation::Uuid { vg, uuid });
+                continue;
+            }
+
+            let name = self.unique_vg_name(&vg, &taken)?;
+            taken.push(name.clone());
+
+            activations.push(VgActivation::Import {
+                vg,
+                uuid,
+                name,
+                pvs,
+            });
+        }
+
+        Ok(activations)
+    }
+
+    /// A temporary name for a VG, based on `vg`, which no other VG has or is to be given.
+    fn unique_vg_name(&self, vg: &str, taken: &[String]) -> Result<String, LvmError> {
+        (1..100)
+            .map(|suffix| [vg, "_", &suffix.to_string()].concat())
+            .find(|name| {
+                self.lvm.vg(name).is_none()
+                    && !self.imported_vgs.contains_key(name)
+                    && !taken.contains(name)
+            })
+            .ok_or_else(|| LvmError::DuplicateVg(vg.to_owned()))
+    }
+
+    /// Initialize a device as an LVM PV, such as a partition or an unlocked LUKS volume.
+    pub fn pv_create(
+        &mut self,
+        device: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_lvm_writable(device, t)?;
+        self.ensure_unused(device, t)?;
+        crate::lvm::pv_create(device)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Wipe the LVM label from a PV which is not in a VG.
+    pub fn pv_remove(
+        &mut self,
+        device: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_lvm_writable(device, t)?;
+        crate::lvm::pv_remove(device)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Create a VG from PVs.
+    pub fn vg_create(
+        &mut self,
+        vg: &str,
+        pvs: &[&str],
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        for pv in pvs {
+            self.ensure_lvm_writable(pv, t)?;
+        }
+
+        crate::lvm::vg_create(vg, pvs)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    pub fn vg_rename(
+        &mut self,
+        vg: &str,
+        new_name: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+        crate::lvm::vg_rename(vg, new_name)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Remove a VG and every LV in it, if none of its LVs are in use.
+    pub fn vg_remove(
+        &mut self,
+        vg: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+
+        for map in self.blocks.values() {
+            if let BlockDevice::DeviceMap(map) = map {
+                let map = map.ro(t);
+                if map.vg_name.as_deref() == Some(vg) {
+                    self.ensure_unused(&map.device.name, t)?;
+                }
+            }
+        }
+
+        crate::lvm::vg_remove(vg)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Create an LV in a VG.
+    pub fn lv_create(
+        &mut self,
+        vg: &str,
+        lv: &str,
+        size: LvSize,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+        self.ensure_fits(vg, size)?;
+
+        crate::lvm::lv_create(vg, lv, size)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Grow or shrink an LV, along with its filesystem if `resize_fs` is set.
+    ///
+    /// An LV may only be shrunk along with its filesystem.
+    pub fn lv_resize(
+        &mut self,
+        vg: &str,
+        lv: &str,
+        size: LvSize,
+        resize_fs: bool,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+        self.ensure_resizable(vg, lv, size, resize_fs, t)?;
+
+        crate::lvm::lv_resize(vg, lv, size, resize_fs)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    pub fn lv_rename(
+        &mut self,
+        vg: &str,
+        lv: &str,
+        new_name: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+
+        if self.lvm.lv(vg, lv).is_none() {
+            return Err(LvmError::LvNotFound(vg.to_owned(), lv.to_owned()));
+        }
+
+        crate::lvm::lv_rename(vg, lv, new_name)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Remove an LV, if it is not in use.
+    pub fn lv_remove(
+        &mut self,
+        vg: &str,
+        lv: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+
+        if let Some(devname) = self.lv_devname(vg, lv, t) {
+            self.ensure_unused(&devname, t)?;
+        }
+
+        crate::lvm::lv_remove(vg, lv)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Create a snapshot of an LV, which may grow to `size` before it is invalidated.
+    pub fn snapshot_create(
+        &mut self,
+        vg: &str,
+        origin: &str,
+        name: &str,
+        size: LvSize,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+        self.ensure_fits(vg, size)?;
+
+        if self.lvm.lv(vg, origin).is_none() {
+            return Err(LvmError::LvNotFound(vg.to_owned(), origin.to_owned()));
+        }
+
+        crate::lvm::snapshot_create(vg, origin, name, size)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Create a snapshot of a thin volume, which shares the space of its thin pool.
+    pub fn thin_snapshot_create(
+        &mut self,
+        vg: &str,
+        origin: &str,
+        name: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+
+        if !self
+            .lvm
+            .lv(vg, origin)
+            .map_or(false, LogicalVolume::is_thin)
+        {
+            return Err(LvmError::LvNotFound(vg.to_owned(), origin.to_owned()));
+        }
+
+        crate::lvm::thin_snapshot_create(vg, origin, name)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Roll the origin of a snapshot back to the state it was in when the snapshot was taken.
+    ///
+    /// An origin which is in use, such as a mounted root, is rolled back when it is next
+    /// activated.
+    pub fn snapshot_merge(
+        &mut self,
+        vg: &str,
+        snapshot: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+        self.ensure_snapshot(vg, snapshot)?;
+
+        crate::lvm::snapshot_merge(vg, snapshot)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Discard a snapshot, keeping the changes made to its origin since it was taken.
+    pub fn snapshot_remove(
+        &mut self,
+        vg: &str,
+        snapshot: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+        self.ensure_snapshot(vg, snapshot)?;
+
+        if let Some(devname) = self.lv_devname(vg, snapshot, t) {
+            self.ensure_unused(&devname, t)?;
+        }
+
+        crate::lvm::lv_remove(vg, snapshot)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Create a thin pool, from which thin volumes and their snapshots are allocated.
+    pub fn thin_pool_create(
+        &mut self,
+        vg: &str,
+        pool: &str,
+        size: LvSize,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+        self.ensure_fits(vg, size)?;
+
+        crate::lvm::thin_pool_create(vg, pool, size)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// Create a thin volume in a thin pool, with a virtual size of `bytes`.
+    pub fn thin_create(
+        &mut self,
+        vg: &str,
+        pool: &str,
+        name: &str,
+        bytes: u64,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.ensure_vg_writable(vg, udev, t)?;
+
+        if !self
+            .lvm
+            .lv(vg, pool)
+            .map_or(false, LogicalVolume::is_thin_pool)
+        {
+            return Err(LvmError::LvNotFound(vg.to_owned(), pool.to_owned()));
+        }
+
+        crate::lvm::thin_create(vg, pool, name, bytes)?;
+        self.lvm_refresh(udev, t)
+    }
+
+    /// The `DEVNAME` of the device map of an active LV.
+    fn lv_devname(&self, vg: &str, lv: &str, t: &ACellOwner) -> Option<String> {
+        self.blocks.values().find_map(|block| match block {
+            BlockDevice::DeviceMap(map) => {
+                let map = map.ro(t);
+                if map.vg_name.as_deref() == Some(vg) && map.lv_name.as_deref() == Some(lv) {
+                    Some(map.device.name.clone())
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+    }
+
+    fn ensure_lvm_writable(&self, devname: &str, t: &ACellOwner) -> Result<(), LvmError> {
+        if !self.blocks.contains_key(devname) {
+            return Err(LvmError::DeviceNotFound);
+        }
+
+        if self.is_live_media(devname, t) {
+            return Err(LvmError::LiveMedia(devname.to_owned()));
+        }
+
+        Ok(())
+    }
+
+    /// Refresh the LVM report, and fail if the VG does not exist or any of its PVs may not
+    /// be written to.
+    ///
+    /// The checks which follow this rely on the report being fresh.
+    fn ensure_vg_writable(
+        &mut self,
+        vg: &str,
+        udev: &mut dyn BlockSource,
+        t: &ACellOwner,
+    ) -> Result<(), LvmError> {
+        self.refresh_lvm(udev)?;
+
+        if self.lvm.vg(vg).is_none() {
+            return Err(LvmError::VgNotFound(vg.to_owned()));
+        }
+
+        for devname in self.devices_of_vg(vg) {
+            self.ensure_lvm_writable(devname, t)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that the VG has the free extents to allocate `size`.
+    fn ensure_fits(&self, vg: &str, size: LvSize) -> Result<(), LvmError> {
+        let vg = self
+            .lvm
+            .vg(vg)
+            .ok_or_else(|| LvmError::VgNotFound(vg.to_owned()))?;
+
+        let required = match size {
+            LvSize::Bytes(bytes) => vg.extents_for(bytes),
+            LvSize::PercentFree(_) => 1,
+        };
+
+        if required > vg.free_extents {
+            return Err(LvmError::InsufficientExtents(
+                vg.name.clone(),
+                required,
+                vg.free_extents,
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check that a VG has the free extents to grow an LV to `size`, and that an LV is only
+    /// shrunk along with its filesystem, to no less than the filesystem needs.
+    fn ensure_resizable(
+        &self,
+        vg: &str,
+        lv: &str,
+        size: LvSize,
+        resize_fs: bool,
+        t: &ACellOwner,
+    ) -> Result<(), LvmError> {
+        let not_found = || LvmError::LvNotFound(vg.to_owned(), lv.to_owned());
+
+        let volume = self.lvm.lv(vg, lv).ok_or_else(not_found)?;
+        let group = self
+            .lvm
+            .vg(vg)
+            .ok_or_else(|| LvmError::VgNotFound(vg.to_owned()))?;
+
+        let current = group.extents_for(volume.size);
+        let required = match size {
+            LvSize::Bytes(bytes) => group.extents_for(bytes),
+            LvSize::PercentFree(percent) => group.free_extents * u64::from(percent) / 100,
+        };
+
+        if required > current {
+            let growth = required - current;
+            if growth > group.free_extents {
+                return Err(LvmError::InsufficientExtents(
+                    vg.to_owned(),
+                    growth,
+                    group.free_extents,
+                ));
+            }
+
+            return Ok(());
+        }
+
+        if required == current {
+            return Ok(());
+        }
+
+        if !resize_fs {
+            return Err(LvmError::ShrinkWithoutFs(vg.to_owned(), lv.to_owned()));
+        }
+
+        let bytes = required.saturating_mul(group.extent_size);
+
+        let minimum = self.lv_devname(vg, lv, t).and_then(|devname| {
+            let block = self.blocks.get(&devname)?;
+            let fs = Self::device_from_block(block, t).fs.as_ref()?;
+            crate::resize::minimum_size(&devname, &fs.type_)
+        });
+
+        match minimum {
+            Some(minimum) if bytes < minimum => Err(LvmError::BelowMinimum(
+                vg.to_owned(),
+                lv.to_owned(),
+                minimum,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that an LV is a snapshot.
+    fn ensure_snapshot(&self, vg: &str, snapshot: &str) -> Result<(), LvmError> {
+        match self.lvm.lv(vg, snapshot) {
+            Some(lv) if lv.is_snapshot() => Ok(()),
+            Some(_) => Err(LvmError::NotASnapshot(vg.to_owned(), snapshot.to_owned())),
+            None => Err(LvmError::LvNotFound(vg.to_owned(), snapshot.to_owned())),
+        }
+    }
+
+    /// Pick up the device maps and report of LVM after it has been modified.
+    fn lvm_refresh(
+        &mut self,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), LvmError> {
+        crate::udev::settle();
+        self.reload_lvm()?;
+        self.reload(udev, t);
+        Ok(())
+    }
+
+    /// Assemble an MD RAID array from its members, or every array found if none are given.
+    pub fn md_assemble(
+        &mut self,
+        array: &str,
+        members: &[&str],
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), MdError> {
+        if members.is_empty() {
+            crate::md::assemble_scan()?;
+        } else {
+            for member in members {
+                if !self.blocks.contains_key(*member) {
+                    return Err(MdError::DeviceNotFound);
+                }
+            }
+
+            crate::md::assemble(array, members)?;
+        }
+
+        crate::udev::settle();
+        self.reload(udev, t);
+
+        Ok(())
+    }
+
+    /// Create an MD RAID array, such as a RAID1 mirror of two partitions.
+    pub fn md_create(
+        &mut self,
+        array: &str,
+        level: MdLevel,
+        members: &[&str],
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), MdError> {
+        for member in members {
+            if !self.blocks.contains_key(*member) {
+                return Err(MdError::DeviceNotFound);
+            }
+
+            if self.is_live_media(member, t) {
+                return Err(MdError::LiveMedia((*member).to_owned()));
+            }
+
+            self.ensure_unused(member, t)?;
+        }
+
+        crate::md::create(array, level, members)?;
+
+        crate::udev::settle();
+        self.reload(udev, t);
+
+        Ok(())
+    }
+
+    /// Stop an MD RAID array, if nothing on it is in use.
+    pub fn md_stop(
+        &mut self,
+        array: &str,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), MdError> {
+        match self.blocks.get(array) {
+            Some(BlockDevice::Disk(disk)) if disk.ro(t).md.is_some() => (),
+            Some(_) => return Err(MdError::NotAnArray(array.to_owned())),
+            None => return Err(MdError::DeviceNotFound),
+        }
+
+        self.ensure_unused(array, t)?;
+        crate::md::stop(array)?;
+
+        crate::udev::settle();
+        self.reload(udev, t);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MemorySource;
+
+    const MIB: u64 = 1024 * 1024;
+    const GIB: u64 = 1024 * MIB;
+
+    const LUKS_UUID: &str = "6f5a3b1c-2d4e-4f60-8a7b-9c0d1e2f3a4b";
+
+    /// A GPT disk with an EFI partition, and LVM on LUKS in the second partition.
+    fn encrypted_lvm() -> MemorySource {
+        let mut source = MemorySource::new();
+
+        source.add_disk("/dev/vdz", 64 * GIB, Some(PartitionTable::Gpt));
+        source
+            .add_partition("/dev/vdz", 1, MIB, 512 * MIB)
+            .set_fs("vfat", "ABCD-EF01");
+        source.add_partition("/dev/vdz", 2, 513 * MIB, 32 * GIB);
+        source.add_luks("/dev/vdz2", "cryptdata", LUKS_UUID);
+        source
+            .add_lv("/dev/dm-0", "data", "root", 16 * GIB)
+            .set_fs("ext4", "0f1e2d3c-4b5a-4968-8776-a5b4c3d2e1f0");
+
+        source
+    }
+
+    fn fs_type(dm: &DiskManager, devname: &str, t: &ACellOwner) -> Option<String> {
+        let block = dm.blocks.get(devname)?;
+        let fs = DiskManager::device_from_block(block, t).fs.as_ref()?;
+        Some(fs.type_.clone())
+    }
+
+    #[test]
+    fn reload_stack() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        let diff = dm.reload(&mut encrypted_lvm(), &mut t);
+
+        assert_eq!(
+            diff.added,
+            [
+                "/dev/dm-0",
+                "/dev/dm-1",
+                "/dev/vdz",
+                "/dev/vdz1",
+                "/dev/vdz2"
+            ]
+        );
+        assert!(diff.removed.is_empty() && diff.changed.is_empty());
+
+        let disk = dm.disk_by_devname("/dev/vdz", &t).unwrap();
+        let disk = disk.ro(&t);
+        assert_eq!(disk.table, Some(PartitionTable::Gpt));
+        assert_eq!(disk.sector_size, 512);
+
+        let numbers = disk
+            .children
+            .iter()
+            .map(|part| part.ro(&t).number)
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, [1, 2]);
+
+        assert_eq!(fs_type(&dm, "/dev/vdz1", &t).as_deref(), Some("vfat"));
+        assert_eq!(
+            fs_type(&dm, "/dev/vdz2", &t).as_deref(),
+            Some("crypto_LUKS")
+        );
+        assert_eq!(
+            fs_type(&dm, "/dev/dm-0", &t).as_deref(),
+            Some("LVM2_member")
+        );
+        assert_eq!(fs_type(&dm, "/dev/dm-1", &t).as_deref(), Some("ext4"));
+
+        match dm.blocks.get("/dev/dm-1") {
+            Some(BlockDevice::DeviceMap(map)) => {
+                let map = map.ro(&t);
+                assert_eq!(map.name, "data-root");
+                assert_eq!(map.vg_name.as_deref(), Some("data"));
+                assert_eq!(map.lv_name.as_deref(), Some("root"));
+            }
+            _ => panic!("/dev/dm-1 is not a device map"),
+        }
+
+        let ancestors = dm.ancestors("/dev/dm-1", &t).collect::<Vec<_>>();
+        assert_eq!(ancestors, ["/dev/dm-0", "/dev/vdz2", "/dev/vdz"]);
+
+        let root = dm.root_disk_of("/dev/dm-1", &t).unwrap();
+        assert_eq!(root.ro(&t).device.name, "/dev/vdz");
+
+        assert!(dm.disk_of_partition("/dev/vdz2", &t).is_some());
+        assert!(dm.disk_of_partition("/dev/dm-0", &t).is_none());
+        assert!(dm.busy("/dev/vdz1", &t).is_empty());
+    }
+
+    #[test]
+    fn reload_multiple_parents() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        let mut source = MemorySource::new();
+
+        for disk in &["/dev/vdw", "/dev/vdx"] {
+            source.add_disk(disk, 8 * GIB, None);
+        }
+
+        for pv in &["/dev/vdy", "/dev/vdz"] {
+            source.add_disk(pv, 8 * GIB, None).set_fs("LVM2_member", "");
+        }
+
+        source.add_md(
+            "/dev/md0",
+            "raid1",
+            "3a2b1c0d:4e5f6071:8293a4b5:c6d7e8f9",
+            &["/dev/vdw", "/dev/vdx"],
+            8 * GIB - MIB,
+        );
+
+        // An LV with extents on both PVs of its VG.
+        source
+            .add_lv("/dev/vdy", "data", "root", 12 * GIB)
+            .parents
+            .push("/dev/vdz".into());
+
+        dm.reload(&mut source, &mut t);
+
+        let ancestors = dm.ancestors("/dev/md0", &t).collect::<Vec<_>>();
+        assert_eq!(ancestors, ["/dev/vdw", "/dev/vdx"]);
+        assert_eq!(dm.roots_of("/dev/md0", &t), ["/dev/vdw", "/dev/vdx"]);
+
+        let ancestors = dm.ancestors("/dev/dm-0", &t).collect::<Vec<_>>();
+        assert_eq!(ancestors, ["/dev/vdy", "/dev/vdz"]);
+
+        let root = dm.root_disk_of("/dev/dm-0", &t).unwrap();
+        assert_eq!(root.ro(&t).device.name, "/dev/vdy");
+
+        for pv in &["/dev/vdy", "/dev/vdz"] {
+            let holders = dm.holders(pv, &t).collect::<Vec<_>>();
+            assert_eq!(holders, ["/dev/dm-0"]);
+
+            let device_map = Holder::DeviceMap {
+                device: pv.to_string(),
+                name: "data-root".into(),
+            };
+
+            assert_eq!(dm.busy(pv, &t), [device_map]);
+        }
+
+        // A device on top of nothing is its own root.
+        assert_eq!(dm.roots_of("/dev/vdw", &t), ["/dev/vdw"]);
+    }
+
+    #[test]
+    fn reload_diff() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        dm.reload(&mut encrypted_lvm(), &mut t);
+        assert!(dm.reload(&mut encrypted_lvm(), &mut t).is_empty());
+
+        let esp = match dm.blocks.get("/dev/vdz1") {
+            Some(BlockDevice::Partition(part)) => part.clone(),
+            _ => panic!("/dev/vdz1 is not a partition"),
+        };
+
+        // Reformat the EFI partition, lock the LUKS volume, and add a partition.
+        let mut source = MemorySource::new();
+        source.add_disk("/dev/vdz", 64 * GIB, Some(PartitionTable::Gpt));
+        source
+            .add_partition("/dev/vdz", 1, MIB, 512 * MIB)
+            .set_fs("ext4", "3c2b1a09-8f7e-4d6c-9b5a-493827160504");
+        source
+            .add_partition("/dev/vdz", 2, 513 * MIB, 32 * GIB)
+            .set_fs("crypto_LUKS", LUKS_UUID);
+        source.add_partition("/dev/vdz", 3, 32 * GIB + 513 * MIB, GIB);
+
+        let diff = dm.reload(&mut source, &mut t);
+
+        assert_eq!(diff.added, ["/dev/vdz3"]);
+        assert_eq!(diff.removed, ["/dev/dm-0", "/dev/dm-1"]);
+        assert_eq!(diff.changed, ["/dev/vdz", "/dev/vdz1", "/dev/vdz2"]);
+
+        // Devices which still exist keep their cells.
+        match dm.blocks.get("/dev/vdz1") {
+            Some(BlockDevice::Partition(part)) => assert!(Arc::ptr_eq(part, &esp)),
+            _ => panic!("/dev/vdz1 is not a partition"),
+        }
+
+        assert_eq!(fs_type(&dm, "/dev/vdz1", &t).as_deref(), Some("ext4"));
+        assert!(esp.ro(&t).device.children.is_empty());
+    }
+
+    #[test]
+    fn lvm_report_links() {
+        use crate::lvm::report::{LogicalVolume, PhysicalVolume};
+
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        let mut source = encrypted_lvm();
+        source.lvm = Some(Report {
+            pvs: vec![PhysicalVolume {
+                devname: "/dev/dm-0".into(),
+                uuid: "pv-uuid".into(),
+                vg_name: Some("data".into()),
+                vg_uuid: Some("vg-uuid".into()),
+                size: 32 * GIB,
+                free: 16 * GIB,
+                tags: Vec::new(),
+            }],
+            vgs: Vec::new(),
+            lvs: vec![LogicalVolume {
+                name: "root".into(),
+                uuid: "lv-uuid".into(),
+                vg_name: "data".into(),
+                size: 16 * GIB,
+                path: "/dev/data/root".into(),
+                dm_path: "/dev/mapper/data-root".into(),
+                active: true,
+                attr: "-wi-ao----".into(),
+                tags: Vec::new(),
+                origin: None,
+                pool: None,
+                segment_types: vec!["linear".into()],
+            }],
+        });
+
+        dm.reload(&mut source, &mut t);
+
+        match dm.blocks.get("/dev/dm-0") {
+            Some(BlockDevice::DeviceMap(map)) => {
+                let map = map.ro(&t);
+                assert_eq!(
+                    map.device.pv.as_ref().map(|pv| pv.uuid.as_str()),
+                    Some("pv-uuid")
+                );
+                assert!(map.lv.is_none());
+            }
+            _ => panic!("/dev/dm-0 is not a device map"),
+        }
+
+        match dm.blocks.get("/dev/dm-1") {
+            Some(BlockDevice::DeviceMap(map)) => {
+                let map = map.ro(&t);
+                assert_eq!(map.lv.as_ref().map(|lv| lv.uuid.as_str()), Some("lv-uuid"));
+                assert!(map.device.pv.is_none());
+            }
+            _ => panic!("/dev/dm-1 is not a device map"),
+        }
+
+        // A source without a report keeps the last one.
+        dm.reload(&mut encrypted_lvm(), &mut t);
+        assert!(dm.logical_volume("/dev/dm-1", &t).is_some());
+    }
+
+    #[test]
+    fn mounts_from_source() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        let mut source = encrypted_lvm();
+        source
+            .add_disk("/dev/vdy", 4 * GIB, None)
+            .mount(LIVE_MEDIA_MOUNT);
+        source.add_loop("/dev/loop0", GIB, "/cdrom/casper/filesystem.squashfs");
+        source.devices[1].mount("/boot/efi");
+
+        dm.reload(&mut source, &mut t);
+
+        assert!(dm.is_live_media("/dev/vdy", &t));
+        assert!(dm.is_live_media("/dev/loop0", &t));
+        assert!(!dm.is_live_media("/dev/dm-1", &t));
+
+        let esp = Holder::Mount {
+            device: "/dev/vdz1".into(),
+            target: PathBuf::from("/boot/efi"),
+        };
+
+        assert_eq!(dm.busy("/dev/vdz1", &t), [esp.clone()]);
+        assert!(dm.busy("/dev/vdz", &t).contains(&esp));
+
+        assert!(matches!(
+            dm.ensure_unused("/dev/vdz1", &t),
+            Err(ClaimError::Busy(..))
+        ));
+    }
+
+    #[test]
+    fn md_partitions() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        let mut source = MemorySource::new();
+
+        for disk in &["/dev/vdx", "/dev/vdy"] {
+            source.add_disk(disk, 8 * GIB, Some(PartitionTable::Gpt));
+            source.add_partition(disk, 1, MIB, 4 * GIB);
+        }
+
+        source
+            .add_md(
+                "/dev/md0",
+                "raid1",
+                "3a2b1c0d:4e5f6071:8293a4b5:c6d7e8f9",
+                &["/dev/vdx1", "/dev/vdy1"],
+                4 * GIB - MIB,
+            )
+            .set_property("ID_PART_TABLE_TYPE", "gpt");
+        source
+            .add_partition("/dev/md0", 1, MIB, GIB)
+            .set_fs("ext4", "0f1e2d3c-4b5a-4968-8776-a5b4c3d2e1f0");
+
+        dm.reload(&mut source, &mut t);
+
+        let md0 = dm.disk_by_devname("/dev/md0", &t).unwrap();
+
+        {
+            let disk = md0.ro(&t);
+            let md = disk.md.as_ref().unwrap();
+            assert_eq!(md.level, "raid1");
+            assert_eq!(md.members, ["/dev/vdx1", "/dev/vdy1"]);
+            assert_eq!(disk.table, Some(PartitionTable::Gpt));
+            assert_eq!(disk.children.len(), 1);
+            assert!(!disk.free_regions(&t).is_empty());
+        }
+
+        let parent = dm.disk_of_partition("/dev/md0p1", &t).unwrap();
+        assert!(Arc::ptr_eq(&parent, &md0));
+
+        let array = dm.md_of_member("/dev/vdy1", &t).unwrap();
+        assert!(Arc::ptr_eq(&array, &md0));
+
+        assert!(dm.ensure_writable("/dev/md0", &t).is_ok());
+        assert!(crate::edit_queue::EditQueue::new(&dm, "/dev/md0", &t).is_ok());
+
+        let holder = Holder::MdArray {
+            device: "/dev/vdx1".into(),
+            name: "/dev/md0".into(),
+        };
+
+        assert!(dm.busy("/dev/vdx", &t).contains(&holder));
+        assert!(dm.busy("/dev/md0", &t).is_empty());
+    }
+
+    #[test]
+    fn claim_refusals() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        let mut source = encrypted_lvm();
+        source.devices[1].mount("/boot/efi");
+        source
+            .add_partition("/dev/vdz", 3, 33 * GIB, 4 * GIB)
+            .swapon();
+        source.add_partition("/dev/vdz", 4, 37 * GIB, GIB);
+        source
+            .add_disk("/dev/vdy", 8 * GIB, Some(PartitionTable::Gpt))
+            .set_attribute("ro", 1);
+        source.add_disk("/dev/vdx", 8 * GIB, Some(PartitionTable::Gpt));
+        source
+            .add_partition("/dev/vdx", 1, MIB, GIB)
+            .mount(LIVE_MEDIA_MOUNT);
+        source.add_partition("/dev/vdx", 2, GIB + MIB, GIB);
+        source.add_disk("/dev/vdw", 8 * GIB, None);
+        source.add_md(
+            "/dev/md0",
+            "raid1",
+            "3a2b1c0d:4e5f6071:8293a4b5:c6d7e8f9",
+            &["/dev/vdw"],
+            8 * GIB - MIB,
+        );
+        source.add_md(
+            "/dev/md1",
+            "raid1",
+            "0d1c2b3a:71605f4e:b5a49382:f9e8d7c6",
+            &["/dev/vdx2"],
+            GIB - MIB,
+        );
+
+        dm.reload(&mut source, &mut t);
+
+        let busy = dm.busy("/dev/vdz", &t);
+
+        let expected = [
+            Holder::Mount {
+                device: "/dev/vdz1".into(),
+                target: PathBuf::from("/boot/efi"),
+            },
+            Holder::Luks {
+                device: "/dev/vdz2".into(),
+                name: "cryptdata".into(),
+            },
+            Holder::DeviceMap {
+                device: "/dev/dm-0".into(),
+                name: "data-root".into(),
+            },
+            Holder::Swap {
+                device: "/dev/vdz3".into(),
+            },
+        ];
+
+        for holder in &expected {
+            assert!(
+                busy.contains(holder),
+                "{:?} is missing from {:?}",
+                holder,
+                busy
+            );
+        }
+
+        let md0 = Holder::MdArray {
+            device: "/dev/vdw".into(),
+            name: "/dev/md0".into(),
+        };
+
+        assert_eq!(dm.busy("/dev/vdw", &t), [md0]);
+        assert!(dm.busy("/dev/vdz4", &t).is_empty());
+        assert!(dm.ensure_unused("/dev/vdz4", &t).is_ok());
+
+        assert!(matches!(
+            dm.ensure_unused("/dev/vdz", &t),
+            Err(ClaimError::Busy(devname, _)) if devname == "/dev/vdz"
+        ));
+
+        assert!(matches!(
+            dm.ensure_unused("/dev/vdv", &t),
+            Err(ClaimError::DeviceNotFound)
+        ));
+
+        let ext4 = Mkfs::new(crate::mkfs::FileSystemType::Ext4);
+
+        for devname in &[
+            "/dev/vdz1",
+            "/dev/vdz2",
+            "/dev/vdz3",
+            "/dev/dm-0",
+            "/dev/vdw",
+        ] {
+            assert!(matches!(
+                dm.mkfs(devname, &ext4, &mut source, &mut t),
+                Err(MkfsError::Claim(ClaimError::Busy(..)))
+            ));
+        }
+
+        // The member of an array on the live media, and the array itself.
+        for devname in &["/dev/vdx2", "/dev/md1"] {
+            assert!(matches!(
+                dm.mkfs(devname, &ext4, &mut source, &mut t),
+                Err(MkfsError::LiveMedia(_))
+            ));
+        }
+
+        assert!(matches!(
+            dm.mkfs("/dev/vdv", &ext4, &mut source, &mut t),
+            Err(MkfsError::DeviceNotFound)
+        ));
+
+        assert!(matches!(
+            dm.prepare_write("/dev/vdy", false, &t),
+            Err(PartitionError::ReadOnly(_))
+        ));
+
+        for disk in &["/dev/vdx", "/dev/md1"] {
+            assert!(matches!(
+                dm.prepare_write(disk, false, &t),
+                Err(PartitionError::LiveMedia(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn gpt_refusals() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        let mut source = encrypted_lvm();
+        source.devices[1].mount("/boot/efi");
+        source
+            .add_disk("/dev/vdy", 8 * GIB, Some(PartitionTable::Gpt))
+            .set_attribute("ro", 1);
+        source.add_disk("/dev/vdx", 8 * GIB, Some(PartitionTable::Gpt));
+        source
+            .add_partition("/dev/vdx", 1, MIB, GIB)
+            .mount(LIVE_MEDIA_MOUNT);
+
+        dm.reload(&mut source, &mut t);
+
+        let linux = crate::gpt::parse_guid(crate::gpt::LINUX_FS).unwrap();
+        let partition = GptPartition::new(linux, GIB / 512);
+
+        // A mounted partition, and one with an unlocked LUKS volume on it.
+        for number in 1..=2 {
+            assert!(matches!(
+                dm.gpt_remove_partition("/dev/vdz", number, &mut source, &mut t),
+                Err(PartitionError::Claim(ClaimError::Busy(..)))
+            ));
+        }
+
+        assert!(matches!(
+            dm.gpt_create("/dev/vdz", &mut source, &mut t),
+            Err(PartitionError::Claim(ClaimError::Busy(..)))
+        ));
+
+        assert!(matches!(
+            dm.gpt_add_partition("/dev/vdy", &partition, &mut source, &mut t),
+            Err(PartitionError::ReadOnly(_))
+        ));
+
+        assert!(matches!(
+            dm.gpt_add_partition("/dev/vdx", &partition, &mut source, &mut t),
+            Err(PartitionError::LiveMedia(_))
+        ));
+
+        assert!(matches!(
+            dm.gpt_create("/dev/vdz1", &mut source, &mut t),
+            Err(PartitionError::NotADisk(_))
+        ));
+    }
+
+    #[test]
+    fn mbr_refusals() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        let mut source = MemorySource::new();
+        source.add_disk("/dev/vdz", 64 * GIB, Some(PartitionTable::Mbr));
+        source.add_partition("/dev/vdz", 1, MIB, GIB);
+        source
+            .add_partition("/dev/vdz", 2, GIB + MIB, 8 * GIB)
+            .set_property("ID_PART_ENTRY_TYPE", "0x0f");
+        source
+            .add_partition("/dev/vdz", 5, GIB + 2 * MIB, 4 * GIB)
+            .swapon();
+        source
+            .add_disk("/dev/vdy", 8 * GIB, Some(PartitionTable::Mbr))
+            .set_attribute("ro", 1);
+        source.add_disk("/dev/vdx", 8 * GIB, Some(PartitionTable::Mbr));
+        source
+            .add_partition("/dev/vdx", 1, MIB, GIB)
+            .mount(LIVE_MEDIA_MOUNT);
+
+        dm.reload(&mut source, &mut t);
+
+        let partition = MbrPartition::new(PartitionKind::Primary, crate::mbr::LINUX, 2048);
+
+        // The logical partitions of an extended partition are removed along with it.
+        for number in &[2, 5] {
+            assert!(matches!(
+                dm.mbr_remove_partition("/dev/vdz", *number, &mut source, &mut t),
+                Err(PartitionError::Claim(ClaimError::Busy(..)))
+            ));
+        }
+
+        assert!(matches!(
+            dm.mbr_create("/dev/vdz", &mut source, &mut t),
+            Err(PartitionError::Claim(ClaimError::Busy(..)))
+        ));
+
+        assert!(matches!(
+            dm.mbr_add_partition("/dev/vdy", &partition, &mut source, &mut t),
+            Err(PartitionError::ReadOnly(_))
+        ));
+
+        assert!(matches!(
+            dm.mbr_add_partition("/dev/vdx", &partition, &mut source, &mut t),
+            Err(PartitionError::LiveMedia(_))
+        ));
+    }
+
+    #[test]
+    fn free_regions_in_bytes() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        dm.reload(&mut encrypted_lvm(), &mut t);
+
+        let disk = dm.disk_by_devname("/dev/vdz", &t).unwrap();
+        let regions = disk.ro(&t).free_regions(&t);
+
+        // The space after the LUKS partition, aligned to 1 MiB on either end.
+        let start = 32 * GIB + 513 * MIB;
+        let end = 64 * GIB - MIB - 1;
+
+        assert_eq!(
+            regions,
+            [FreeRegion {
+                start,
+                end,
+                size: end + 1 - start,
+                first_sector: start / 512,
+                last_sector: (end + 1) / 512 - 1,
+                logical: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn mbr_to_gpt_refusals() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        let mut source = MemorySource::new();
+        source.add_disk("/dev/vdz", 8 * GIB, Some(PartitionTable::Mbr));
+        source.add_partition("/dev/vdz", 1, MIB, GIB).mount("/home");
+        source
+            .add_disk("/dev/vdy", 8 * GIB, Some(PartitionTable::Mbr))
+            .set_attribute("ro", 1);
+        source.add_disk("/dev/vdx", 8 * GIB, Some(PartitionTable::Mbr));
+        source
+            .add_partition("/dev/vdx", 1, MIB, GIB)
+            .mount(LIVE_MEDIA_MOUNT);
+
+        dm.reload(&mut source, &mut t);
+
+        let plan = ConversionPlan {
+            partitions: Vec::new(),
+            bios_boot: None,
+            warnings: Vec::new(),
+        };
+
+        let mut convert = |disk| dm.mbr_to_gpt(disk, &plan, &mut source, &mut t);
+
+        assert!(matches!(
+            convert("/dev/vdz"),
+            Err(PartitionError::Claim(ClaimError::Busy(..)))
+        ));
+        assert!(matches!(
+            convert("/dev/vdy"),
+            Err(PartitionError::ReadOnly(_))
+        ));
+        assert!(matches!(
+            convert("/dev/vdx"),
+            Err(PartitionError::LiveMedia(_))
+        ));
+        assert!(matches!(
+            convert("/dev/vdz1"),
+            Err(PartitionError::NotADisk(_))
+        ));
+    }
+
+    fn pv(devname: &str, vg: &str, vg_uuid: &str) -> PhysicalVolume {
+        PhysicalVolume {
+            devname: devname.into(),
+            uuid: format!("{}-pv", devname),
+            vg_name: Some(vg.into()),
+            vg_uuid: Some(vg_uuid.into()),
+            size: 32 * GIB,
+            free: 0,
+            tags: Vec::new(),
+        }
+    }
+
+    /// A VG with 4 MiB extents.
+    fn vg(name: &str, uuid: &str, extents: u64, free: u64) -> crate::lvm::report::VolumeGroup {
+        crate::lvm::report::VolumeGroup {
+            name: name.into(),
+            uuid: uuid.into(),
+            size: extents * 4 * MIB,
+            free: free * 4 * MIB,
+            extent_size: 4 * MIB,
+            extent_count: extents,
+            free_extents: free,
+            pv_count: 1,
+            lv_count: 1,
+            tags: Vec::new(),
+        }
+    }
+
+    fn lv(vg: &str, name: &str, bytes: u64) -> LogicalVolume {
+        LogicalVolume {
+            name: name.into(),
+            uuid: format!("{}-{}-lv", vg, name),
+            vg_name: vg.into(),
+            size: bytes,
+            path: format!("/dev/{}/{}", vg, name),
+            dm_path: format!("/dev/mapper/{}-{}", vg, name),
+            active: true,
+            attr: "-wi-a-----".into(),
+            tags: Vec::new(),
+            origin: None,
+            pool: None,
+            segment_types: vec!["linear".into()],
+        }
+    }
+
+    #[test]
+    fn lv_resize_refusals() {
+        use byteorder::{ByteOrder, LittleEndian};
+
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        // An ext4 filesystem of 1 GiB, of which half is in use.
+        let image = std::env::temp_dir().join(format!("distinst-lv-{}", std::process::id()));
+        let mut ext4 = vec![0u8; 4096];
+        let sb = &mut ext4[1024..2048];
+        LittleEndian::write_u32(&mut sb[0x04..], 262_144);
+        LittleEndian::write_u32(&mut sb[0x0C..], 131_072);
+        LittleEndian::write_u32(&mut sb[0x18..], 2);
+        LittleEndian::write_u16(&mut sb[0x38..], 0xEF53);
+        LittleEndian::write_u32(&mut sb[0x60..], 0x40);
+        std::fs::write(&image, &ext4).unwrap();
+
+        let mut source = MemorySource::new();
+        source.add_disk("/dev/vdz", 64 * GIB, Some(PartitionTable::Gpt));
+        source.add_partition("/dev/vdz", 1, MIB, 32 * GIB);
+        source
+            .add_lv("/dev/vdz1", "data", "root", GIB)
+            .set_property("DEVNAME", image.display())
+            .set_fs("ext4", "0f1e2d3c-4b5a-4968-8776-a5b4c3d2e1f0");
+
+        source.lvm = Some(Report {
+            pvs: vec![pv("/dev/vdz1", "data", "data-uuid")],
+            vgs: vec![vg("data", "data-uuid", 8191, 100)],
+            lvs: vec![lv("data", "root", GIB)],
+        });
+
+        dm.reload(&mut source, &mut t);
+
+        fn resize(
+            dm: &mut DiskManager,
+            bytes: u64,
+            resize_fs: bool,
+            source: &mut MemorySource,
+            t: &mut ACellOwner,
+        ) -> Result<(), LvmError> {
+            dm.lv_resize("data", "root", LvSize::Bytes(bytes), resize_fs, source, t)
+        }
+
+        // 101 more extents are needed, and 100 are free.
+        assert!(matches!(
+            resize(&mut dm, GIB + 401 * MIB, true, &mut source, &mut t),
+            Err(LvmError::InsufficientExtents(vg, 101, 100)) if vg == "data"
+        ));
+
+        assert!(matches!(
+            resize(&mut dm, 768 * MIB, false, &mut source, &mut t),
+            Err(LvmError::ShrinkWithoutFs(..))
+        ));
+
+        assert!(matches!(
+            resize(&mut dm, 512 * MIB, true, &mut source, &mut t),
+            Err(LvmError::BelowMinimum(_, _, minimum)) if minimum > 512 * MIB
+        ));
+
+        assert!(matches!(
+            dm.lv_resize(
+                "data",
+                "home",
+                LvSize::Bytes(GIB),
+                true,
+                &mut source,
+                &mut t
+            ),
+            Err(LvmError::LvNotFound(..))
+        ));
+
+        let check = |size, resize_fs| dm.ensure_resizable("data", "root", size, resize_fs, &t);
+
+        assert!(check(LvSize::Bytes(GIB + 400 * MIB), false).is_ok());
+        assert!(check(LvSize::Bytes(GIB), false).is_ok());
+        assert!(check(LvSize::Bytes(768 * MIB), true).is_ok());
+
+        // All of the free space is less than the LV has now.
+        assert!(matches!(
+            check(LvSize::PercentFree(100), false),
+            Err(LvmError::ShrinkWithoutFs(..))
+        ));
+
+        let _ = std::fs::remove_file(&image);
+    }
+
+    #[test]
+    fn vg_activations() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut dm = DiskManager::simulated();
+
+        // A second install, whose VG is also named `data`, and a VG with a unique name.
+        let mut source = encrypted_lvm();
+        source.add_disk("/dev/vdy", 64 * GIB, Some(PartitionTable::Gpt));
+        source.add_partition("/dev/vdy", 2, 513 * MIB, 32 * GIB);
+        source
+            .add_luks(
+                "/dev/vdy2",
+                "cryptdata-2",
+                "0a1b2c3d-4e5f-4a6b-8c7d-8e9fa0b1c2d3",
+            )
+            .set_fs("LVM2_member", "");
+        source.add_disk("/dev/vdx", 8 * GIB, Some(PartitionTable::Gpt));
+        source
+            .add_partition("/dev/vdx", 1, MIB, 4 * GIB)
+            .set_fs("LVM2_member", "");
+
+        source.lvm = Some(Report {
+            pvs: vec![
+                pv("/dev/dm-0", "data", "data-a"),
+                pv("/dev/dm-2", "data", "data-b"),
+                pv("/dev/vdx1", "other", "other-c"),
+            ],
+            vgs: vec![
+                vg("data", "data-a", 8191, 4095),
+                vg("data", "data-b", 8191, 8191),
+                vg("other", "other-c", 1023, 1023),
+            ],
+            lvs: vec![lv("data", "root", 16 * GIB)],
+        });
+
+        dm.reload(&mut source, &mut t);
+
+        let uuid = |vg: &str, uuid: &str| {
+            vec![VgActivation::Uuid {
+                vg: vg.into(),
+                uuid: uuid.into(),
+            }]
+        };
+
+        // The active `data` VG is on this PV, so it is not another VG.
+        assert_eq!(
+            dm.vg_activations("/dev/dm-0", &t).unwrap(),
+            uuid("data", "data-a")
+        );
+        assert_eq!(
+            dm.vg_activations("/dev/vdx1", &t).unwrap(),
+            uuid("other", "other-c")
+        );
+        assert!(dm.vg_activations("/dev/vdz1", &t).unwrap().is_empty());
+
+        let import = |name: &str| {
+            vec![VgActivation::Import {
+                vg: "data".into(),
+                uuid: "data-b".into(),
+                name: name.into(),
+                pvs: vec!["/dev/dm-2".into()],
+            }]
+        };
+
+        assert_eq!(
+            dm.vg_activations("/dev/dm-2", &t).unwrap(),
+            import("data_1")
+        );
+
+        // Names which are already in use by imported VGs are skipped.
+        dm.imported_vgs.insert("data_1".into(), "data".into());
+        assert_eq!(
+            dm.vg_activations("/dev/dm-2", &t).unwrap(),
+            import("data_2")
+        );
+    }
 }
@@ -0,0 +1,1429 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Pending partitioning operations which are previewed in memory, and written on commit.
+
+use crate::block_types::{BlockDevice, Disk, PartitionKind, PartitionTable};
+use crate::claim::ClaimError;
+use crate::disk_manager::{DiskManager, PartitionError};
+use crate::gpt::{self, GptError, GptPartition, GptTable};
+use crate::mbr::{self, MbrError, MbrPartition, MbrTable};
+use crate::mkfs::{Mkfs, MkfsError};
+use crate::resize::ResizeError;
+use crate::source::BlockSource;
+use crate::ACellOwner;
+use std::path::Path;
+
+/// Partitions created without an explicit start are aligned to 1 MiB.
+const ALIGNMENT: u64 = 1024 * 1024;
+
+/// A stable handle to a partition in a [`Layout`], which survives renumbering.
+pub type PartitionId = u32;
+
+#[derive(Debug, Error)]
+pub enum EditError {
+    #[error("the disk does not have a partition table")]
+    NoTable,
+    #[error("partition {0} does not exist in the pending layout")]
+    PartitionNotFound(PartitionId),
+    #[error("partition type or flags do not match the partition table")]
+    TableMismatch,
+    #[error("extended and logical partitions require an MBR partition table")]
+    KindUnsupported,
+    #[error("no unused partition entries remain")]
+    NoFreeEntries,
+    #[error("the disk already has an extended partition")]
+    ExtendedExists,
+    #[error("logical partitions require an extended partition")]
+    NoExtended,
+    #[error("no free region can hold {0} sectors")]
+    NoSpace(u64),
+    #[error("sectors {0}..={1} are outside of the usable area or overlap another partition")]
+    Overlap(u64, u64),
+    #[error("partition {0} contains a filesystem which cannot be moved or shrunk")]
+    ResizeUnsupported(PartitionId),
+    #[error("partition {0} cannot be shrunk below {1} bytes")]
+    TooSmall(PartitionId, u64),
+    #[error("logical partitions cannot be moved")]
+    MoveLogical,
+    #[error("MBR partitions cannot exceed 2^32 sectors")]
+    MbrLimit,
+    #[error("partition {0} was not found on the disk after committing")]
+    Missing(u32),
+    #[error("no partition on the disk starts at sector {0}")]
+    MissingAt(u64),
+    #[error("commit failed after {} change(s) were written to the disk", .0.len())]
+    Incomplete(Vec<Applied>, #[source] Box<EditError>),
+    #[error(transparent)]
+    Claim(#[from] ClaimError),
+    #[error(transparent)]
+    Mkfs(#[from] MkfsError),
+    #[error(transparent)]
+    Partition(#[from] PartitionError),
+    #[error(transparent)]
+    Resize(#[from] ResizeError),
+    #[error("GUID partition table error")]
+    Gpt(#[from] GptError),
+    #[error("MBR partition table error")]
+    Mbr(#[from] MbrError),
+}
+
+/// The type of a partition, which must match the partition table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PartitionType {
+    /// A partition type GUID in on-disk byte order.
+    Gpt([u8; 16]),
+    /// An MBR system ID.
+    Mbr(u8),
+}
+
+/// Flags of a partition, which must match the partition table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PartitionFlags {
+    /// GPT attribute bits.
+    Gpt(u64),
+    Mbr {
+        bootable: bool,
+    },
+}
+
+/// An operation that is queued against the in-memory layout of a disk.
+///
+/// Sectors are logical sectors of the disk being edited.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Operation {
+    /// Replace the partition table, discarding every partition.
+    NewTable(PartitionTable),
+    Create {
+        kind: PartitionKind,
+        type_: PartitionType,
+        name: String,
+        flags: Option<PartitionFlags>,
+        /// When `None`, the first aligned region which fits is used.
+        start: Option<u64>,
+        sectors: u64,
+        format: Option<Mkfs>,
+    },
+    Delete(PartitionId),
+    Resize {
+        id: PartitionId,
+        start: u64,
+        sectors: u64,
+    },
+    Format(PartitionId, Mkfs),
+    SetFlags(PartitionId, PartitionFlags),
+}
+
+/// A change which [`EditQueue::commit`] has written to the disk.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Applied {
+    /// Partitions were removed from the table, by their original numbers.
+    Deleted(Vec<u32>),
+    /// A partition was resized or moved, by its original `DEVNAME`.
+    Resized(String),
+    /// New partitions, types, and flags were written to the table.
+    Table,
+    /// A partition was formatted, by its `DEVNAME`.
+    Formatted(String),
+}
+
+/// Where a planned partition came from on the disk.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Origin {
+    pub devname: String,
+    pub number: u32,
+    pub start: u64,
+    pub sectors: u64,
+}
+
+/// A partition as it will exist once pending operations are committed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PlannedPartition {
+    pub id: PartitionId,
+    pub number: u32,
+    pub kind: PartitionKind,
+    pub start: u64,
+    pub sectors: u64,
+    /// Set if the partition is new, or its type is being changed.
+    pub type_: Option<PartitionType>,
+    /// Set if the partition is new, or its flags are being changed.
+    pub flags: Option<PartitionFlags>,
+    pub name: String,
+    /// The existing filesystem, or the filesystem it will be formatted with.
+    pub fs: Option<String>,
+    /// Bytes used by the existing filesystem, read from its superblock.
+    pub used: Option<u64>,
+    /// Smallest size in bytes that the existing filesystem can be shrunk to.
+    pub minimum: Option<u64>,
+    pub format: Option<Mkfs>,
+    /// Set if this partition exists on the disk today.
+    pub origin: Option<Origin>,
+}
+
+impl PlannedPartition {
+    pub fn end(&self) -> u64 {
+        self.start + self.sectors - 1
+    }
+}
+
+/// The layout of a disk after pending operations are applied.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Layout {
+    pub table: Option<PartitionTable>,
+    /// Whether the partition table will be replaced.
+    pub new_table: bool,
+    pub sector_size: u64,
+    pub sectors: u64,
+    /// Partitions sorted by their position on the disk.
+    pub partitions: Vec<PlannedPartition>,
+    next_id: PartitionId,
+}
+
+impl Layout {
+    /// The current layout of a disk.
+    pub fn from_disk(disk: &Disk, t: &ACellOwner) -> Self {
+        let sector_size = disk.sector_size;
+
+        let partitions = disk
+            .children
+            .iter()
+            .enumerate()
+            .map(|(id, child)| {
+                let part = child.ro(t);
+                let start = part.start(sector_size);
+                let sectors = part.sectors(sector_size);
+
+                let superblock = part
+                    .device
+                    .fs
+                    .as_ref()
+                    .and_then(|_| part.device.superblock().ok());
+
+                PlannedPartition {
+                    id: id as PartitionId,
+                    number: part.number,
+                    kind: part.kind,
+                    start,
+                    sectors,
+                    type_: None,
+                    flags: None,
+                    name: String::new(),
+                    fs: part.device.fs.as_ref().map(|fs| fs.type_.clone()),
+                    used: superblock.as_ref().map(|sb| sb.used),
+                    minimum: superblock.and_then(|sb| sb.minimum),
+                    format: None,
+                    origin: Some(Origin {
+                        devname: part.device.name.clone(),
+                        number: part.number,
+                        start,
+                        sectors,
+                    }),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut layout = Self {
+            table: disk.table,
+            new_table: false,
+            sector_size,
+            sectors: disk.sectors(),
+            next_id: partitions.len() as PartitionId,
+            partitions,
+        };
+
+        layout.sort();
+        layout
+    }
+
+    /// Fetch a planned partition by its ID.
+    pub fn partition(&self, id: PartitionId) -> Option<&PlannedPartition> {
+        self.partitions.iter().find(|part| part.id == id)
+    }
+
+    /// The first and last sectors that partitions may occupy.
+    pub fn usable_sectors(&self) -> (u64, u64) {
+        match self.table {
+            Some(table) => table.usable_sectors(self.sector_size, self.sectors),
+            None => (0, self.sectors.saturating_sub(1)),
+        }
+    }
+
+    /// The first and last sector of the extended partition, if there is one.
+    pub fn extended(&self) -> Option<(u64, u64)> {
+        self.partitions
+            .iter()
+            .find(|part| part.kind == PartitionKind::Extended)
+            .map(|part| (part.start, part.end()))
+    }
+
+    fn apply(&mut self, operation: &Operation) -> Result<(), EditError> {
+        match operation {
+            Operation::NewTable(table) => {
+                self.table = Some(*table);
+                self.new_table = true;
+                self.partitions.clear();
+            }
+
+            Operation::Create {
+                kind,
+                type_,
+                name,
+                flags,
+                start,
+                sectors,
+                format,
+            } => {
+                let table = self.table.ok_or(EditError::NoTable)?;
+                self.check_table(Some(type_), flags.as_ref())?;
+
+                if let Some(format) = format {
+                    format.validate()?;
+                }
+
+                if table == PartitionTable::Gpt && *kind != PartitionKind::Primary {
+                    return Err(EditError::KindUnsupported);
+                }
+
+                let number = self.next_number(table, *kind)?;
+
+                let start = match start {
+                    Some(start) => *start,
+                    None => self.find_place(*kind, *sectors)?,
+                };
+
+                self.check_region(None, *kind, start, *sectors)?;
+
+                let id = self.next_id;
+                self.next_id += 1;
+
+                self.partitions.push(PlannedPartition {
+                    id,
+                    number,
+                    kind: *kind,
+                    start,
+                    sectors: *sectors,
+                    type_: Some(*type_),
+                    flags: *flags,
+                    name: name.clone(),
+                    fs: format.as_ref().map(|format| format.fs.id().to_owned()),
+                    used: None,
+                    minimum: None,
+                    format: format.clone(),
+                    origin: None,
+                });
+            }
+
+            Operation::Delete(id) => {
+                let index = self.index(*id)?;
+                let removed = self.partitions.remove(index);
+
+                if removed.kind == PartitionKind::Extended {
+                    self.partitions
+                        .retain(|part| part.kind != PartitionKind::Logical);
+                }
+            }
+
+            Operation::Resize { id, start, sectors } => {
+                let part = &self.partitions[self.index(*id)?];
+                let kind = part.kind;
+
+                if kind == PartitionKind::Logical && *start != part.start {
+                    return Err(EditError::MoveLogical);
+                }
+
+                // Until the filesystem is reformatted, its contents must be preserved.
+                let preserves_contents = part.origin.is_some() && part.format.is_none();
+                if preserves_contents && *sectors < part.sectors {
+                    if let Some(fs) = part.fs.as_deref() {
+                        if !crate::resize::can_shrink(fs) {
+                            return Err(EditError::ResizeUnsupported(*id));
+                        }
+                    }
+
+                    if let Some(minimum) = part.minimum {
+                        if sectors.saturating_mul(self.sector_size) < minimum {
+                            return Err(EditError::TooSmall(*id, minimum));
+                        }
+                    }
+                }
+
+                self.check_region(Some(*id), kind, *start, *sectors)?;
+
+                if kind == PartitionKind::Extended {
+                    let end = start + sectors - 1;
+                    let outside = self.partitions.iter().find(|part| {
+                        part.kind == PartitionKind::Logical
+                            && (part.start <= *start || part.end() > end)
+                    });
+
+                    if let Some(part) = outside {
+                        return Err(EditError::Overlap(part.start, part.end()));
+                    }
+                }
+
+                let index = self.index(*id)?;
+                self.partitions[index].start = *start;
+                self.partitions[index].sectors = *sectors;
+            }
+
+            Operation::Format(id, format) => {
+                format.validate()?;
+                let index = self.index(*id)?;
+                let part = &mut self.partitions[index];
+                part.fs = Some(format.fs.id().to_owned());
+                part.used = None;
+                part.minimum = None;
+                part.format = Some(format.clone());
+            }
+
+            Operation::SetFlags(id, flags) => {
+                self.check_table(None, Some(flags))?;
+                let index = self.index(*id)?;
+                self.partitions[index].flags = Some(*flags);
+            }
+        }
+
+        self.sort();
+        Ok(())
+    }
+
+    fn index(&self, id: PartitionId) -> Result<usize, EditError> {
+        self.partitions
+            .iter()
+            .position(|part| part.id == id)
+            .ok_or(EditError::PartitionNotFound(id))
+    }
+
+    fn check_table(
+        &self,
+        type_: Option<&PartitionType>,
+        flags: Option<&PartitionFlags>,
+    ) -> Result<(), EditError> {
+        let matches = match self.table.ok_or(EditError::NoTable)? {
+            PartitionTable::Gpt => {
+                !matches!(type_, Some(PartitionType::Mbr(_)))
+                    && !matches!(flags, Some(PartitionFlags::Mbr { .. }))
+            }
+            PartitionTable::Mbr => {
+                !matches!(type_, Some(PartitionType::Gpt(_)))
+                    && !matches!(flags, Some(PartitionFlags::Gpt(_)))
+            }
+        };
+
+        if matches {
+            Ok(())
+        } else {
+            Err(EditError::TableMismatch)
+        }
+    }
+
+    /// The partition number that the partition table would assign to a new partition.
+    fn next_number(&self, table: PartitionTable, kind: PartitionKind) -> Result<u32, EditError> {
+        let unused = |range: std::ops::RangeInclusive<u32>| {
+            range
+                .into_iter()
+                .find(|&number| !self.partitions.iter().any(|part| part.number == number))
+                .ok_or(EditError::NoFreeEntries)
+        };
+
+        match (table, kind) {
+            (PartitionTable::Gpt, _) => unused(1..=128),
+            (PartitionTable::Mbr, PartitionKind::Logical) => {
+                self.extended().ok_or(EditError::NoExtended)?;
+                // Logical partitions are renumbered by position once placed.
+                Ok(5)
+            }
+            (PartitionTable::Mbr, PartitionKind::Extended) if self.extended().is_some() => {
+                Err(EditError::ExtendedExists)
+            }
+            (PartitionTable::Mbr, _) => unused(1..=4),
+        }
+    }
+
+    /// Regions which may not overlap a partition of the given kind.
+    ///
+    /// Logical partitions include the EBR sector which precedes them.
+    fn occupied(&self, except: Option<PartitionId>, kind: PartitionKind) -> Vec<(u64, u64)> {
+        let logical = kind == PartitionKind::Logical;
+
+        let mut regions = self
+            .partitions
+            .iter()
+            .filter(|part| Some(part.id) != except)
+            .filter(|part| (part.kind == PartitionKind::Logical) == logical)
+            .map(|part| {
+                if logical {
+                    (part.start - 1, part.end())
+                } else {
+                    (part.start, part.end())
+                }
+            })
+            .collect::<Vec<_>>();
+
+        regions.sort_unstable();
+        regions
+    }
+
+    /// The first and last sector that a partition of the given kind may occupy.
+    fn bounds(&self, kind: PartitionKind) -> Result<(u64, u64), EditError> {
+        if kind == PartitionKind::Logical {
+            self.extended().ok_or(EditError::NoExtended)
+        } else {
+            Ok(self.usable_sectors())
+        }
+    }
+
+    fn find_place(&self, kind: PartitionKind, sectors: u64) -> Result<u64, EditError> {
+        let align = (ALIGNMENT / self.sector_size).max(1);
+        let (first, last) = self.bounds(kind)?;
+
+        // Leave room for the EBR ahead of a logical partition.
+        let gap = if kind == PartitionKind::Logical { 1 } else { 0 };
+
+        let mut cursor = first;
+        let occupied = self.occupied(None, kind);
+
+        for (used_start, used_end) in occupied.into_iter().chain(Some((last + 1, last + 1))) {
+            let start = align_up(cursor + gap, align);
+            let end = start
+                .checked_add(sectors)
+                .ok_or(EditError::NoSpace(sectors))?;
+
+            if end <= used_start {
+                return Ok(start);
+            }
+
+            cursor = cursor.max(used_end + 1);
+        }
+
+        Err(EditError::NoSpace(sectors))
+    }
+
+    fn check_region(
+        &self,
+        except: Option<PartitionId>,
+        kind: PartitionKind,
+        start: u64,
+        sectors: u64,
+    ) -> Result<(), EditError> {
+        let end = start
+            .checked_add(sectors)
+            .ok_or(EditError::Overlap(start, u64::MAX))?
+            .saturating_sub(1);
+
+        let (first, last) = self.bounds(kind)?;
+
+        let (first, region_start) = if kind == PartitionKind::Logical {
+            (first + 1, start.saturating_sub(1))
+        } else {
+            (first, start)
+        };
+
+        let overlaps = self
+            .occupied(except, kind)
+            .into_iter()
+            .any(|(used_start, used_end)| region_start <= used_end && end >= used_start);
+
+        if sectors == 0 || start < first || end > last || overlaps {
+            return Err(EditError::Overlap(start, end));
+        }
+
+        if self.table == Some(PartitionTable::Mbr) && end > u64::from(u32::MAX) {
+            return Err(EditError::MbrLimit);
+        }
+
+        Ok(())
+    }
+
+    /// Sorts partitions by position, and numbers logical partitions in that order.
+    fn sort(&mut self) {
+        self.partitions.sort_by_key(|part| part.start);
+
+        let mut number = 5;
+        for part in self.partitions.iter_mut() {
+            if part.kind == PartitionKind::Logical {
+                part.number = number;
+                number += 1;
+            }
+        }
+    }
+}
+
+/// A queue of partitioning operations against a single disk, with undo and redo.
+///
+/// Nothing is written to the disk until `commit` is called.
+pub struct EditQueue {
+    disk: String,
+    operations: Vec<Operation>,
+    undone: Vec<Operation>,
+    /// The initial layout, followed by the layout after each operation.
+    history: Vec<Layout>,
+}
+
+impl EditQueue {
+    /// Begin queueing operations against a disk's current layout.
+    pub fn new(dm: &DiskManager, disk: &str, t: &ACellOwner) -> Result<Self, EditError> {
+        let layout = Layout::from_disk(dm.disk_by_devname(disk, t)?.ro(t), t);
+
+        Ok(Self {
+            disk: disk.to_owned(),
+            operations: Vec::new(),
+            undone: Vec::new(),
+            history: vec![layout],
+        })
+    }
+
+    /// The `DEVNAME` of the disk being edited.
+    pub fn disk(&self) -> &str {
+        &self.disk
+    }
+
+    /// The layout that the disk would have if committed now.
+    pub fn layout(&self) -> &Layout {
+        self.history
+            .last()
+            .expect("history always contains the initial layout")
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// Queue an operation, if it is valid against the current layout.
+    pub fn push(&mut self, operation: Operation) -> Result<(), EditError> {
+        let mut layout = self.layout().clone();
+        layout.apply(&operation)?;
+
+        self.history.push(layout);
+        self.operations.push(operation);
+        self.undone.clear();
+
+        Ok(())
+    }
+
+    /// Remove the most recent operation, so that it may be redone.
+    pub fn undo(&mut self) -> Option<&Operation> {
+        let operation = self.operations.pop()?;
+        self.history.pop();
+        self.undone.push(operation);
+        self.undone.last()
+    }
+
+    /// Reapply the most recently undone operation.
+    pub fn redo(&mut self) -> Result<Option<&Operation>, EditError> {
+        let operation = match self.undone.pop() {
+            Some(operation) => operation,
+            None => return Ok(None),
+        };
+
+        let mut layout = self.layout().clone();
+        if let Err(why) = layout.apply(&operation) {
+            self.undone.push(operation);
+            return Err(why);
+        }
+
+        self.history.push(layout);
+        self.operations.push(operation);
+        Ok(self.operations.last())
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.operations.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    /// Write the pending layout to the disk, then format partitions which request it.
+    ///
+    /// Deleted partitions are removed first, so that their space may be claimed by
+    /// partitions which are then resized or moved along with their filesystems. New
+    /// partitions, types, and flags are written last.
+    ///
+    /// On success, the queue is reset against the new layout of the disk. If the commit
+    /// fails after changes were written, the queue is also reset against the disk, and
+    /// [`EditError::Incomplete`] lists the changes which were applied.
+    pub fn commit(
+        &mut self,
+        dm: &mut DiskManager,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+    ) -> Result<(), EditError> {
+        if self.operations.is_empty() {
+            return Ok(());
+        }
+
+        let mut applied = Vec::new();
+
+        match self.write(dm, udev, t, &mut applied) {
+            Ok(()) => (),
+            Err(why) if applied.is_empty() => return Err(why),
+            Err(why) => {
+                crate::udev::settle();
+                dm.reload(udev, t);
+                *self = Self::new(dm, &self.disk, t)?;
+                return Err(EditError::Incomplete(applied, Box::new(why)));
+            }
+        }
+
+        *self = Self::new(dm, &self.disk, t)?;
+
+        Ok(())
+    }
+
+    /// Apply the pending layout, recording each change as it is written.
+    fn write(
+        &self,
+        dm: &mut DiskManager,
+        udev: &mut dyn BlockSource,
+        t: &mut ACellOwner,
+        applied: &mut Vec<Applied>,
+    ) -> Result<(), EditError> {
+        let base = &self.history[0];
+        let layout = self.layout();
+        let path = Path::new(&self.disk);
+        let table = layout.table.ok_or(EditError::NoTable)?;
+
+        // Partitions whose contents will be destroyed must not be in use.
+        if layout.new_table {
+            dm.ensure_unused(&self.disk, t)?;
+        } else {
+            let formatted = layout
+                .partitions
+                .iter()
+                .filter(|part| part.format.is_some())
+                .filter_map(|part| part.origin.as_ref());
+
+            for origin in removed(base, layout).chain(formatted) {
+                let devname = partition_devname(dm, &self.disk, origin.number, t)?;
+                dm.ensure_unused(&devname, t)?;
+            }
+
+            // Checked before anything is deleted, so that a busy partition cannot leave
+            // the commit half-applied.
+            let resized = layout
+                .partitions
+                .iter()
+                .filter(|part| part.format.is_none())
+                .filter_map(|part| part.origin.as_ref().map(|origin| (part, origin)))
+                .filter(|(part, origin)| {
+                    (origin.start, origin.sectors) != (part.start, part.sectors)
+                });
+
+            for (part, origin) in resized {
+                let devname = partition_devname(dm, &self.disk, origin.number, t)?;
+                crate::resize::ensure_unused(dm, &devname, part.start != origin.start, t)?;
+            }
+        }
+
+        // Keep a copy of the original table, in case the commit fails part-way.
+        let (_, claim) = dm.prepare_write(&self.disk, base.table.is_some(), t)?;
+
+        let deleted = !layout.new_table && removed(base, layout).next().is_some();
+
+        if deleted {
+            match table {
+                PartitionTable::Gpt => delete_gpt(path, base, layout)?,
+                PartitionTable::Mbr => delete_mbr(path, base, layout)?,
+            }
+
+            let numbers = removed(base, layout).map(|origin| origin.number).collect();
+            applied.push(Applied::Deleted(numbers));
+        }
+
+        // The claim must be released for udev to see the changes.
+        drop(claim);
+
+        if deleted {
+            crate::udev::settle();
+            dm.reload(udev, t);
+        }
+
+        if !layout.new_table {
+            resize_existing(dm, &self.disk, layout, udev, t, applied)?;
+        }
+
+        if has_table_changes(layout) {
+            let claim = dm.claim(&self.disk, t)?;
+
+            match table {
+                PartitionTable::Gpt => commit_gpt(path, layout)?,
+                PartitionTable::Mbr => commit_mbr(path, layout)?,
+            }
+
+            applied.push(Applied::Table);
+
+            drop(claim);
+            crate::udev::settle();
+            dm.reload(udev, t);
+        }
+
+        let formats = layout
+            .partitions
+            .iter()
+            .filter_map(|part| part.format.as_ref().map(|format| (part.number, format)))
+            .collect::<Vec<_>>();
+
+        if !formats.is_empty() {
+            for (number, format) in formats {
+                let devname = partition_devname(dm, &self.disk, number, t)?;
+                crate::mkfs::format(&devname, format)?;
+                applied.push(Applied::Formatted(devname));
+            }
+
+            crate::udev::settle();
+            dm.reload(udev, t);
+        }
+
+        Ok(())
+    }
+}
+
+/// Partitions of the initial layout which the pending layout no longer contains.
+fn removed<'a>(base: &'a Layout, layout: &'a Layout) -> impl Iterator<Item = &'a Origin> {
+    base.partitions
+        .iter()
+        .filter(move |part| layout.partition(part.id).is_none())
+        .filter_map(|part| part.origin.as_ref())
+}
+
+/// Whether the partition table needs to be written after deletions and resizes.
+fn has_table_changes(layout: &Layout) -> bool {
+    layout.new_table
+        || layout
+            .partitions
+            .iter()
+            .any(|part| part.origin.is_none() || part.type_.is_some() || part.flags.is_some())
+}
+
+fn delete_gpt(path: &Path, base: &Layout, layout: &Layout) -> Result<(), EditError> {
+    let mut table = GptTable::open(path, layout.sector_size)?;
+
+    for origin in removed(base, layout) {
+        table.remove(origin.number)?;
+    }
+
+    table.commit()?;
+
+    Ok(())
+}
+
+fn delete_mbr(path: &Path, base: &Layout, layout: &Layout) -> Result<(), EditError> {
+    let mut table = MbrTable::open(path, layout.sector_size)?;
+
+    // Logical partitions are renumbered as they are removed, so remove from the end.
+    let mut removed = removed(base, layout)
+        .map(|origin| origin.number)
+        .collect::<Vec<_>>();
+
+    removed.sort_unstable_by(|a, b| b.cmp(a));
+
+    for number in removed {
+        table.remove(number as usize)?;
+    }
+
+    table.commit()?;
+
+    Ok(())
+}
+
+/// Resize and move partitions which already exist.
+///
+/// Partitions are shrunk before others are grown, so that released space is available
+/// to be claimed. Grown partitions are handled from the end of the disk backwards, so
+/// that a partition moving right does not collide with its neighbor.
+fn resize_existing(
+    dm: &mut DiskManager,
+    disk: &str,
+    layout: &Layout,
+    udev: &mut dyn BlockSource,
+    t: &mut ACellOwner,
+    applied: &mut Vec<Applied>,
+) -> Result<(), EditError> {
+    let mut resized = layout
+        .partitions
+        .iter()
+        .filter_map(|part| part.origin.as_ref().map(|origin| (part, origin)))
+        .filter(|(part, origin)| (origin.start, origin.sectors) != (part.start, part.sectors))
+        .collect::<Vec<_>>();
+
+    resized.sort_by_key(|(part, origin)| match part.sectors >= origin.sectors {
+        true => (1, u64::MAX - part.start),
+        false => (0, part.start),
+    });
+
+    for (part, origin) in resized {
+        // Deletions may have renumbered logical partitions, so locate it by position.
+        let devname = partition_at(dm, disk, origin.start, layout.sector_size, t)?;
+
+        if part.format.is_some() {
+            crate::resize::resize_entry(dm, &devname, part.start, part.sectors, udev, t)?;
+        } else {
+            dm.resize_partition(&devname, part.start, part.sectors, udev, t)?;
+        }
+
+        applied.push(Applied::Resized(origin.devname.clone()));
+    }
+
+    Ok(())
+}
+
+fn commit_gpt(path: &Path, layout: &Layout) -> Result<(), EditError> {
+    let mut table = if layout.new_table {
+        GptTable::create(path, layout.sector_size)?
+    } else {
+        GptTable::open(path, layout.sector_size)?
+    };
+
+    for part in &layout.partitions {
+        let origin = match part.origin.as_ref() {
+            Some(origin) => origin,
+            None => continue,
+        };
+
+        if let Some(PartitionType::Gpt(type_guid)) = part.type_ {
+            table.set_type(origin.number, type_guid)?;
+        }
+
+        if let Some(PartitionFlags::Gpt(attributes)) = part.flags {
+            table.set_attributes(origin.number, attributes)?;
+        }
+    }
+
+    let mut created = layout
+        .partitions
+        .iter()
+        .filter(|part| part.origin.is_none())
+        .collect::<Vec<_>>();
+
+    created.sort_by_key(|part| part.number);
+
+    for part in created {
+        let type_guid = match part.type_ {
+            Some(PartitionType::Gpt(type_guid)) => type_guid,
+            _ => gpt::parse_guid(gpt::LINUX_FS).expect("valid GUID constant"),
+        };
+
+        let attributes = match part.flags {
+            Some(PartitionFlags::Gpt(attributes)) => attributes,
+            _ => 0,
+        };
+
+        // Partitions are formatted by the number they have in the layout.
+        let partition = GptPartition::new(type_guid, part.sectors)
+            .name(part.name.as_str())
+            .attributes(attributes)
+            .start(part.start)
+            .number(part.number);
+
+        table.add(&partition)?;
+    }
+
+    table.commit()?;
+
+    Ok(())
+}
+
+fn commit_mbr(path: &Path, layout: &Layout) -> Result<(), EditError> {
+    let mut table = if layout.new_table {
+        MbrTable::create(path, layout.sector_size)?
+    } else {
+        MbrTable::open(path, layout.sector_size)?
+    };
+
+    // Extended partitions must exist before logical partitions are added to them.
+    let mut created = layout
+        .partitions
+        .iter()
+        .filter(|part| part.origin.is_none())
+        .collect::<Vec<_>>();
+
+    created.sort_by_key(|part| (part.kind == PartitionKind::Logical, part.start));
+
+    for part in created {
+        let sys = match part.type_ {
+            Some(PartitionType::Mbr(sys)) => sys,
+            _ => mbr::LINUX,
+        };
+
+        let partition =
+            MbrPartition::new(part.kind, sys, part.sectors as u32).start(part.start as u32);
+
+        table.add(&partition)?;
+    }
+
+    // Numbers now match those of the pending layout.
+    for part in &layout.partitions {
+        if let Some(PartitionType::Mbr(sys)) = part.type_ {
+            if part.kind != PartitionKind::Extended {
+                table.set_sys(part.number as usize, sys)?;
+            }
+        }
+
+        if let Some(PartitionFlags::Mbr { bootable }) = part.flags {
+            table.set_bootable(part.number as usize, bootable)?;
+        }
+    }
+
+    table.commit()?;
+
+    Ok(())
+}
+
+/// Find the `DEVNAME` of a partition on a disk by its first sector.
+fn partition_at(
+    dm: &DiskManager,
+    disk: &str,
+    start: u64,
+    sector_size: u64,
+    t: &ACellOwner,
+) -> Result<String, EditError> {
+    let disk = dm.disk_by_devname(disk, t)?;
+
+    disk.ro(t)
+        .children
+        .iter()
+        .map(|child| child.ro(t))
+        .find(|part| part.start(sector_size) == start)
+        .map(|part| part.device.name.clone())
+        .ok_or(EditError::MissingAt(start))
+}
+
+/// Find the `DEVNAME` of a partition on a disk by its partition number.
+fn partition_devname(
+    dm: &DiskManager,
+    disk: &str,
+    number: u32,
+    t: &ACellOwner,
+) -> Result<String, EditError> {
+    dm.disk_by_devname(disk, t)?;
+
+    dm.partition_by_number(disk, number, t)
+        .map(|part| part.ro(t).device.name.clone())
+        .filter(|devname| matches!(dm.blocks.get(devname), Some(BlockDevice::Partition(_))))
+        .ok_or(EditError::Missing(number))
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mkfs::FileSystemType;
+    use crate::source::MemorySource;
+
+    const MIB: u64 = 1024 * 1024;
+    const GIB: u64 = 1024 * MIB;
+
+    /// Sectors of 512 bytes in `bytes`.
+    fn sectors(bytes: u64) -> u64 {
+        bytes / 512
+    }
+
+    fn linux() -> PartitionType {
+        PartitionType::Gpt(gpt::parse_guid(gpt::LINUX_FS).unwrap())
+    }
+
+    fn create(
+        kind: PartitionKind,
+        type_: PartitionType,
+        start: Option<u64>,
+        bytes: u64,
+    ) -> Operation {
+        Operation::Create {
+            kind,
+            type_,
+            name: String::new(),
+            flags: None,
+            start,
+            sectors: sectors(bytes),
+            format: None,
+        }
+    }
+
+    /// The partition numbers and starting sectors of the pending layout.
+    fn positions(queue: &EditQueue) -> Vec<(u32, u64)> {
+        let partitions = queue.layout().partitions.iter();
+        partitions.map(|part| (part.number, part.start)).collect()
+    }
+
+    /// An 8 GiB disk with an EFI partition and an ext4 partition.
+    fn gpt_queue(t: &mut ACellOwner) -> EditQueue {
+        let mut source = MemorySource::new();
+        source.add_disk("/dev/vdz", 8 * GIB, Some(PartitionTable::Gpt));
+        source
+            .add_partition("/dev/vdz", 1, MIB, 512 * MIB)
+            .set_fs("vfat", "ABCD-EF01");
+        source
+            .add_partition("/dev/vdz", 2, 513 * MIB, 2 * GIB)
+            .set_fs("ext4", "0f1e2d3c-4b5a-4968-8776-a5b4c3d2e1f0");
+
+        let mut dm = DiskManager::simulated();
+        dm.reload(&mut source, t);
+
+        EditQueue::new(&dm, "/dev/vdz", t).unwrap()
+    }
+
+    fn mbr_queue(bytes: u64, t: &mut ACellOwner) -> EditQueue {
+        let mut source = MemorySource::new();
+        source.add_disk("/dev/vdz", bytes, Some(PartitionTable::Mbr));
+
+        let mut dm = DiskManager::simulated();
+        dm.reload(&mut source, t);
+
+        EditQueue::new(&dm, "/dev/vdz", t).unwrap()
+    }
+
+    #[test]
+    fn layout_from_disk() {
+        let mut t = ACellOwner::wait_for_new();
+        let queue = gpt_queue(&mut t);
+        let layout = queue.layout();
+
+        assert_eq!(layout.table, Some(PartitionTable::Gpt));
+        assert_eq!(layout.sectors, sectors(8 * GIB));
+        assert_eq!(
+            positions(&queue),
+            [(1, sectors(MIB)), (2, sectors(513 * MIB))]
+        );
+
+        let root = layout.partition(1).unwrap();
+        assert_eq!(root.fs.as_deref(), Some("ext4"));
+        assert_eq!(root.origin.as_ref().unwrap().devname, "/dev/vdz2");
+        assert!(!queue.can_undo() && !queue.can_redo());
+    }
+
+    #[test]
+    fn create_undo_redo() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut queue = gpt_queue(&mut t);
+
+        let operation = Operation::Create {
+            kind: PartitionKind::Primary,
+            type_: linux(),
+            name: "home".into(),
+            flags: None,
+            start: None,
+            sectors: sectors(GIB),
+            format: Some(Mkfs::new(FileSystemType::Ext4)),
+        };
+
+        queue.push(operation).unwrap();
+
+        // Placed in the first aligned region after the existing partitions.
+        let created = (3, sectors(2561 * MIB));
+        assert_eq!(positions(&queue)[2], created);
+
+        let home = queue.layout().partition(2).unwrap();
+        assert_eq!(home.fs.as_deref(), Some("ext4"));
+        assert_eq!(home.name, "home");
+        assert!(home.origin.is_none());
+
+        assert!(queue.undo().is_some());
+        assert_eq!(queue.layout().partitions.len(), 2);
+        assert!(queue.can_redo());
+
+        assert!(queue.redo().unwrap().is_some());
+        assert_eq!(positions(&queue)[2], created);
+        assert!(queue.redo().unwrap().is_none());
+
+        // Queueing an operation discards those which were undone.
+        queue.undo();
+        queue
+            .push(create(PartitionKind::Primary, linux(), None, GIB))
+            .unwrap();
+        assert!(!queue.can_redo());
+        assert_eq!(queue.operations().len(), 1);
+    }
+
+    #[test]
+    fn delete_resize_format_flags() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut queue = gpt_queue(&mut t);
+
+        queue.push(Operation::Delete(0)).unwrap();
+        assert_eq!(positions(&queue), [(2, sectors(513 * MIB))]);
+
+        // The root partition moves into the space of the deleted partition, and grows.
+        queue
+            .push(Operation::Resize {
+                id: 1,
+                start: sectors(MIB),
+                sectors: sectors(4 * GIB),
+            })
+            .unwrap();
+
+        let root = queue.layout().partition(1).unwrap();
+        assert_eq!((root.start, root.sectors), (sectors(MIB), sectors(4 * GIB)));
+
+        queue
+            .push(Operation::Format(1, Mkfs::new(FileSystemType::Xfs)))
+            .unwrap();
+        assert_eq!(
+            queue.layout().partition(1).unwrap().fs.as_deref(),
+            Some("xfs")
+        );
+
+        queue
+            .push(Operation::SetFlags(1, PartitionFlags::Gpt(1)))
+            .unwrap();
+        assert_eq!(
+            queue.layout().partition(1).unwrap().flags,
+            Some(PartitionFlags::Gpt(1))
+        );
+
+        assert!(matches!(
+            queue.push(Operation::SetFlags(
+                1,
+                PartitionFlags::Mbr { bootable: true }
+            )),
+            Err(EditError::TableMismatch)
+        ));
+
+        assert!(matches!(
+            queue.push(Operation::Delete(0)),
+            Err(EditError::PartitionNotFound(0))
+        ));
+
+        // Each undo restores the layout from before the operation.
+        queue.undo();
+        assert_eq!(queue.layout().partition(1).unwrap().flags, None);
+        queue.undo();
+        assert_eq!(
+            queue.layout().partition(1).unwrap().fs.as_deref(),
+            Some("ext4")
+        );
+        queue.undo();
+        assert_eq!(positions(&queue), [(2, sectors(513 * MIB))]);
+        queue.undo();
+        assert_eq!(queue.layout().partitions.len(), 2);
+        assert!(!queue.can_undo());
+
+        // Every undone operation may be redone, in order.
+        while queue.redo().unwrap().is_some() {}
+        assert_eq!(queue.operations().len(), 4);
+    }
+
+    #[test]
+    fn region_checks() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut queue = gpt_queue(&mut t);
+
+        let primary = |start, bytes| create(PartitionKind::Primary, linux(), start, bytes);
+
+        // Before the first usable sector, and overlapping the EFI partition.
+        for start in &[0, sectors(2 * MIB)] {
+            assert!(matches!(
+                queue.push(primary(Some(*start), MIB)),
+                Err(EditError::Overlap(..))
+            ));
+        }
+
+        // Past the end of the disk.
+        assert!(matches!(
+            queue.push(primary(Some(sectors(8 * GIB)), MIB)),
+            Err(EditError::Overlap(..))
+        ));
+
+        assert!(matches!(
+            queue.push(primary(None, 6 * GIB)),
+            Err(EditError::NoSpace(_))
+        ));
+
+        let overflow = |start| Operation::Create {
+            kind: PartitionKind::Primary,
+            type_: linux(),
+            name: String::new(),
+            flags: None,
+            start,
+            sectors: u64::MAX,
+            format: None,
+        };
+
+        assert!(matches!(
+            queue.push(overflow(None)),
+            Err(EditError::NoSpace(u64::MAX))
+        ));
+        assert!(matches!(
+            queue.push(overflow(Some(sectors(3 * GIB)))),
+            Err(EditError::Overlap(..))
+        ));
+
+        assert!(matches!(
+            queue.push(Operation::Resize {
+                id: 1,
+                start: sectors(513 * MIB),
+                sectors: u64::MAX,
+            }),
+            Err(EditError::Overlap(..))
+        ));
+
+        assert!(matches!(
+            queue.push(primary(Some(sectors(3 * GIB)), 0)),
+            Err(EditError::Overlap(..))
+        ));
+
+        assert!(matches!(
+            queue.push(create(PartitionKind::Logical, linux(), None, MIB)),
+            Err(EditError::KindUnsupported)
+        ));
+
+        assert!(matches!(
+            queue.push(create(
+                PartitionKind::Primary,
+                PartitionType::Mbr(mbr::LINUX),
+                None,
+                MIB
+            )),
+            Err(EditError::TableMismatch)
+        ));
+
+        assert!(!queue.can_undo());
+    }
+
+    #[test]
+    fn mbr_extended_logical() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut queue = mbr_queue(8 * GIB, &mut t);
+
+        let linux = PartitionType::Mbr(mbr::LINUX);
+        let extended = PartitionType::Mbr(mbr::EXTENDED_LBA);
+
+        assert!(matches!(
+            queue.push(create(PartitionKind::Logical, linux, None, GIB)),
+            Err(EditError::NoExtended)
+        ));
+
+        queue
+            .push(create(PartitionKind::Extended, extended, None, 4 * GIB))
+            .unwrap();
+
+        assert!(matches!(
+            queue.push(create(PartitionKind::Extended, extended, None, GIB)),
+            Err(EditError::ExtendedExists)
+        ));
+
+        queue
+            .push(create(PartitionKind::Logical, linux, None, GIB))
+            .unwrap();
+        queue
+            .push(create(PartitionKind::Logical, linux, None, GIB))
+            .unwrap();
+
+        // Logical partitions leave room for the EBR ahead of them, and are numbered by
+        // their position.
+        assert_eq!(
+            positions(&queue),
+            [
+                (1, sectors(MIB)),
+                (5, sectors(2 * MIB)),
+                (6, sectors(1027 * MIB))
+            ]
+        );
+
+        // A logical partition placed ahead of the others takes the first number.
+        queue.undo();
+        queue.undo();
+        queue
+            .push(create(
+                PartitionKind::Logical,
+                linux,
+                Some(sectors(2 * GIB)),
+                GIB,
+            ))
+            .unwrap();
+        queue
+            .push(create(PartitionKind::Logical, linux, None, GIB))
+            .unwrap();
+        assert_eq!(
+            positions(&queue)[1..],
+            [(5, sectors(2 * MIB)), (6, sectors(2 * GIB))]
+        );
+
+        assert!(matches!(
+            queue.push(Operation::Resize {
+                id: 2,
+                start: sectors(3 * GIB),
+                sectors: sectors(GIB),
+            }),
+            Err(EditError::MoveLogical)
+        ));
+
+        // The extended partition may not shrink past its logical partitions.
+        assert!(matches!(
+            queue.push(Operation::Resize {
+                id: 0,
+                start: sectors(MIB),
+                sectors: sectors(2 * GIB),
+            }),
+            Err(EditError::Overlap(..))
+        ));
+
+        // Deleting the extended partition deletes its logical partitions.
+        queue.push(Operation::Delete(0)).unwrap();
+        assert!(queue.layout().partitions.is_empty());
+
+        for number in 1..=4 {
+            let start = number * GIB;
+            queue
+                .push(create(
+                    PartitionKind::Primary,
+                    linux,
+                    Some(sectors(start)),
+                    MIB,
+                ))
+                .unwrap();
+        }
+
+        assert!(matches!(
+            queue.push(create(PartitionKind::Primary, linux, None, MIB)),
+            Err(EditError::NoFreeEntries)
+        ));
+    }
+
+    #[test]
+    fn mbr_limit() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut queue = mbr_queue(4 * 1024 * GIB, &mut t);
+
+        let linux = PartitionType::Mbr(mbr::LINUX);
+        let start = u64::from(u32::MAX) - sectors(MIB);
+
+        assert!(matches!(
+            queue.push(create(PartitionKind::Primary, linux, Some(start), GIB)),
+            Err(EditError::MbrLimit)
+        ));
+    }
+
+    #[test]
+    fn new_table() {
+        let mut t = ACellOwner::wait_for_new();
+        let mut queue = gpt_queue(&mut t);
+
+        queue
+            .push(Operation::NewTable(PartitionTable::Mbr))
+            .unwrap();
+
+        let layout = queue.layout();
+        assert_eq!(layout.table, Some(PartitionTable::Mbr));
+        assert!(layout.new_table && layout.partitions.is_empty());
+
+        queue
+            .push(create(
+                PartitionKind::Primary,
+                PartitionType::Mbr(mbr::LINUX),
+                None,
+                GIB,
+            ))
+            .unwrap();
+        assert_eq!(positions(&queue), [(1, sectors(MIB))]);
+
+        queue.undo();
+        queue.undo();
+        assert_eq!(queue.layout().table, Some(PartitionTable::Gpt));
+        assert_eq!(queue.layout().partitions.len(), 2);
+    }
+}
@@ -0,0 +1,331 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Creation and modification of GUID partition tables with gptman.
+
+use gptman::{GPTPartitionEntry, GPT};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// EFI System Partition
+pub const ESP: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+/// BIOS boot partition used by GRUB on GPT disks
+pub const BIOS_BOOT: &str = "21686148-6449-6E6F-744E-656564454649";
+/// Linux filesystem data
+pub const LINUX_FS: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+/// Linux swap
+pub const LINUX_SWAP: &str = "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F";
+/// Linux LVM physical volume
+pub const LINUX_LVM: &str = "E6D6D379-F507-44C2-A23C-238F2A3DF928";
+/// Linux LUKS container
+pub const LINUX_LUKS: &str = "CA7D7CCB-63ED-4C53-861C-1742536059CC";
+/// Linux software RAID member
+pub const LINUX_RAID: &str = "A19D880F-05FC-4D3B-A006-743F0F84911E";
+/// Microsoft basic data, used by NTFS and FAT data partitions
+pub const MS_BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+
+#[derive(Debug, Error)]
+pub enum GptError {
+    #[error("failed to open {0:?}")]
+    Open(PathBuf, #[source] io::Error),
+    #[error("failed to read GUID partition table")]
+    Read(#[source] gptman::Error),
+    #[error("failed to write GUID partition table")]
+    Write(#[source] gptman::Error),
+    #[error("failed to sync partition table to disk")]
+    Sync(#[source] io::Error),
+    #[error("kernel failed to re-read the partition table")]
+    Reread(#[source] gptman::linux::BlockError),
+    #[error("partition {0} does not exist")]
+    PartitionNotFound(u32),
+    #[error("no unused partition entries remain")]
+    NoFreeEntries,
+    #[error("partition entry {0} is in use or does not exist")]
+    EntryInUse(u32),
+    #[error("no free region can hold {0} sectors")]
+    NoSpace(u64),
+    #[error("sectors {0}..={1} are outside of the usable area or overlap another partition")]
+    Overlap(u64, u64),
+}
+
+/// Describes a partition to be added to a GUID partition table.
+#[derive(Clone, Debug)]
+pub struct GptPartition {
+    /// Partition type GUID, such as [`LINUX_FS`], in on-disk byte order.
+    pub type_guid: [u8; 16],
+    /// Human-readable partition name, which is exposed as `PARTLABEL`.
+    pub name: String,
+    /// GPT attribute bits.
+    pub attributes: u64,
+    /// First LBA of the partition. When `None`, the optimal aligned position is used.
+    pub start: Option<u64>,
+    /// Length of the partition in logical sectors.
+    pub sectors: u64,
+    /// Partition entry to use. When `None`, the first unused entry is used.
+    pub number: Option<u32>,
+}
+
+impl GptPartition {
+    pub fn new(type_guid: [u8; 16], sectors: u64) -> Self {
+        Self {
+            type_guid,
+            name: String::new(),
+            attributes: 0,
+            start: None,
+            sectors,
+            number: None,
+        }
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn attributes(mut self, attributes: u64) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    pub fn start(mut self, start: u64) -> Self {
+        self.start = Some(start);
+        self
+    }
+
+    pub fn number(mut self, number: u32) -> Self {
+        self.number = Some(number);
+        self
+    }
+}
+
+/// An in-memory GUID partition table which is written to its disk on `commit`.
+pub struct GptTable {
+    file: File,
+    pub gpt: GPT,
+}
+
+impl GptTable {
+    /// Creates a new, empty partition table for the disk at `path`.
+    ///
+    /// Nothing is written to the disk until the table is committed.
+    pub fn create(path: &Path, sector_size: u64) -> Result<Self, GptError> {
+        let mut file = open(path)?;
+        let gpt = GPT::new_from(&mut file, sector_size, random_guid()).map_err(GptError::Read)?;
+        Ok(Self { file, gpt })
+    }
+
+    /// Reads the existing partition table of the disk at `path`.
+    pub fn open(path: &Path, sector_size: u64) -> Result<Self, GptError> {
+        let mut file = open(path)?;
+        let gpt = GPT::read_from(&mut file, sector_size).map_err(GptError::Read)?;
+        Ok(Self { file, gpt })
+    }
+
+    /// Adds a partition to the requested or first unused entry, returning its partition number.
+    pub fn add(&mut self, partition: &GptPartition) -> Result<u32, GptError> {
+        let number = match partition.number {
+            Some(number) => {
+                let unused = self
+                    .gpt
+                    .iter()
+                    .any(|(n, entry)| n == number && entry.is_unused());
+
+                if !unused {
+                    return Err(GptError::EntryInUse(number));
+                }
+
+                number
+            }
+            None => self
+                .gpt
+                .iter()
+                .find(|(_, entry)| entry.is_unused())
+                .map(|(number, _)| number)
+                .ok_or(GptError::NoFreeEntries)?,
+        };
+
+        let start = match partition.start {
+            Some(start) => start,
+            // Zero-length partitions are rejected below, without searching for a place.
+            None if partition.sectors == 0 => 0,
+            None => self
+                .gpt
+                .find_optimal_place(partition.sectors)
+                .ok_or(GptError::NoSpace(partition.sectors))?,
+        };
+
+        let end = end_of(start, partition.sectors)?;
+
+        if !self.is_free(start, end, None) {
+            return Err(GptError::Overlap(start, end));
+        }
+
+        self.gpt[number] = GPTPartitionEntry {
+            partition_type_guid: partition.type_guid,
+            unique_partition_guid: random_guid(),
+            starting_lba: start,
+            ending_lba: end,
+            attribute_bits: partition.attributes,
+            partition_name: partition.name.as_str().into(),
+        };
+
+        Ok(number)
+    }
+
+    /// Removes the partition with the given partition number.
+    pub fn remove(&mut self, number: u32) -> Result<(), GptError> {
+        self.entry(number)?;
+        self.gpt[number] = GPTPartitionEntry::empty();
+        Ok(())
+    }
+
+    /// Moves or resizes the table entry of a partition, without touching its contents.
+    pub fn resize(&mut self, number: u32, start: u64, sectors: u64) -> Result<(), GptError> {
+        self.entry(number)?;
+
+        let end = end_of(start, sectors)?;
+
+        if !self.is_free(start, end, Some(number)) {
+            return Err(GptError::Overlap(start, end));
+        }
+
+        let entry = &mut self.gpt[number];
+        entry.starting_lba = start;
+        entry.ending_lba = end;
+
+        Ok(())
+    }
+
+    /// Replaces the attribute bits of a partition.
+    pub fn set_attributes(&mut self, number: u32, attributes: u64) -> Result<(), GptError> {
+        self.entry_mut(number)?.attribute_bits = attributes;
+        Ok(())
+    }
+
+    /// Renames a partition.
+    pub fn set_name(&mut self, number: u32, name: &str) -> Result<(), GptError> {
+        self.entry_mut(number)?.partition_name = name.into();
+        Ok(())
+    }
+
+    /// Changes the partition type GUID of a partition.
+    pub fn set_type(&mut self, number: u32, type_guid: [u8; 16]) -> Result<(), GptError> {
+        self.entry_mut(number)?.partition_type_guid = type_guid;
+        Ok(())
+    }
+
+    /// Fetches a partition entry which is in use.
+    pub fn entry(&self, number: u32) -> Result<&GPTPartitionEntry, GptError> {
+        self.gpt
+            .iter()
+            .find(|(n, entry)| *n == number && entry.is_used())
+            .map(|(_, entry)| entry)
+            .ok_or(GptError::PartitionNotFound(number))
+    }
+
+    fn entry_mut(&mut self, number: u32) -> Result<&mut GPTPartitionEntry, GptError> {
+        self.entry(number)?;
+        Ok(&mut self.gpt[number])
+    }
+
+    /// Checks if the sectors from `start` to `end` are usable and unallocated, ignoring
+    /// the partition numbered `except`.
+    fn is_free(&self, start: u64, end: u64, except: Option<u32>) -> bool {
+        start <= end
+            && start >= self.gpt.header.first_usable_lba
+            && end <= self.gpt.header.last_usable_lba
+            && !self.gpt.iter().any(|(number, entry)| {
+                Some(number) != except
+                    && entry.is_used()
+                    && start <= entry.ending_lba
+                    && end >= entry.starting_lba
+            })
+    }
+
+    /// Writes the protective MBR and partition table to the disk, and has the kernel re-read it.
+    pub fn commit(mut self) -> Result<(), GptError> {
+        eprintln!("writing GUID partition table");
+
+        GPT::write_protective_mbr_into(&mut self.file, self.gpt.sector_size)
+            .map_err(GptError::Write)?;
+
+        self.gpt
+            .write_into(&mut self.file)
+            .map_err(GptError::Write)?;
+
+        self.file.sync_all().map_err(GptError::Sync)?;
+
+        gptman::linux::reread_partition_table(&mut self.file).map_err(GptError::Reread)
+    }
+}
+
+/// The last sector of a partition, which must not be empty or extend past the last LBA.
+fn end_of(start: u64, sectors: u64) -> Result<u64, GptError> {
+    sectors
+        .checked_sub(1)
+        .and_then(|length| start.checked_add(length))
+        .ok_or_else(|| {
+            let end = start.saturating_add(sectors).saturating_sub(1);
+            GptError::Overlap(start, end)
+        })
+}
+
+/// Converts a textual GUID into its mixed-endian on-disk representation.
+pub fn parse_guid(guid: &str) -> Option<[u8; 16]> {
+    let hex = guid.chars().filter(|&c| c != '-').collect::<String>();
+
+    if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut bytes = [0u8; 16];
+    for (id, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[id * 2..id * 2 + 2], 16).ok()?;
+    }
+
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+
+    Some(bytes)
+}
+
+/// Converts a GUID in its mixed-endian on-disk representation into text.
+pub fn format_guid(guid: &[u8; 16]) -> String {
+    let mut bytes = *guid;
+
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<String>();
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Generates a random version 4 GUID in its on-disk representation.
+pub fn random_guid() -> [u8; 16] {
+    let mut guid = rand::random::<[u8; 16]>();
+    guid[7] = (guid[7] & 0x0F) | 0x40;
+    guid[8] = (guid[8] & 0x3F) | 0x80;
+    guid
+}
+
+fn open(path: &Path) -> Result<File, GptError> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|why| GptError::Open(path.to_owned(), why))
+}
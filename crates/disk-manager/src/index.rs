@@ -0,0 +1,76 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Lookup tables from the identifiers of block devices to their device names.
+
+use crate::block_types::*;
+use crate::disk_manager::DiskManager;
+use crate::ACellOwner;
+use std::collections::BTreeMap;
+
+/// Device names keyed by an identifier, which may be shared by several devices.
+pub type Index = BTreeMap<String, Vec<String>>;
+
+/// Indexes of every block device in a [`DiskManager`], which are rebuilt on each reload.
+///
+/// UUIDs and partition type GUIDs are stored in lowercase.
+#[derive(Clone, Debug, Default)]
+pub struct BlockIndex {
+    /// Filesystem UUIDs
+    pub uuid: Index,
+    /// Filesystem labels
+    pub label: Index,
+    /// GPT partition UUIDs, and MBR disk signatures with partition numbers
+    pub part_uuid: Index,
+    /// GPT partition names
+    pub part_label: Index,
+    /// GPT partition type GUIDs, and MBR system IDs
+    pub part_type: Index,
+    /// Symlinks to the device, such as those in `/dev/disk/by-id` and `/dev/disk/by-path`
+    pub links: Index,
+}
+
+impl BlockIndex {
+    pub fn new(dm: &DiskManager, t: &ACellOwner) -> Self {
+        let mut index = Self::default();
+
+        for (devname, block) in &dm.blocks {
+            let device = DiskManager::device_from_block(block, t);
+
+            let insert = |index: &mut Index, key: &str| {
+                index
+                    .entry(key.to_owned())
+                    .or_default()
+                    .push(devname.clone());
+            };
+
+            if let Some(fs) = device.fs.as_ref() {
+                insert(&mut index.uuid, &fs.uuid.to_ascii_lowercase());
+
+                if let Some(label) = fs.label.as_ref() {
+                    insert(&mut index.label, label);
+                }
+            }
+
+            for link in &device.links {
+                insert(&mut index.links, link);
+            }
+
+            if let BlockDevice::Partition(part) = block {
+                let part = part.ro(t);
+
+                insert(&mut index.part_uuid, &part.uuid.to_ascii_lowercase());
+
+                if let Some(label) = part.label.as_ref() {
+                    insert(&mut index.part_label, label);
+                }
+
+                if let Some(type_) = part.type_.as_ref() {
+                    insert(&mut index.part_type, &type_.to_ascii_lowercase());
+                }
+            }
+        }
+
+        index
+    }
+}
@@ -8,12 +8,28 @@ extern crate thiserror;
 
 use qcell::{TCell, TCellOwner};
 
+pub mod backup;
 mod block_types;
+pub mod claim;
+pub mod command;
+pub mod convert;
 mod disk_manager;
+pub mod edit_queue;
+pub mod gpt;
+pub mod index;
 pub mod luks;
 pub mod lvm;
+pub mod mbr;
+pub mod md;
+pub mod mkfs;
+pub mod mounts;
 pub mod os_probe;
+pub mod resize;
+pub mod snapshot;
+pub mod source;
+pub mod superblock;
 mod udev;
+pub mod udev_db;
 
 pub struct CellMarker;
 
@@ -0,0 +1,275 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+pub mod report;
+
+use self::report::ReportError;
+use crate::claim::ClaimError;
+use crate::command::{self, CommandError};
+
+#[derive(Debug, Error)]
+pub enum LvmError {
+    #[error("cannot modify a device which does not exist")]
+    DeviceNotFound,
+    #[error("{0} holds the live installation media")]
+    LiveMedia(String),
+    #[error(transparent)]
+    Claim(#[from] ClaimError),
+    #[error("LVM VG {0} does not exist")]
+    VgNotFound(String),
+    #[error("LVM LV {0}/{1} does not exist")]
+    LvNotFound(String, String),
+    #[error("another LVM VG named {0} is active, and no temporary name is free for this one")]
+    DuplicateVg(String),
+    #[error("LVM LV {0}/{1} is not a snapshot")]
+    NotASnapshot(String, String),
+    #[error("LVM VG {0} has {2} free extents, but {1} are required")]
+    InsufficientExtents(String, u64, u64),
+    #[error("LVM LV {0}/{1} may only be shrunk along with its filesystem")]
+    ShrinkWithoutFs(String, String),
+    #[error("LVM LV {0}/{1} cannot be shrunk below the {2} bytes that its filesystem needs")]
+    BelowMinimum(String, String, u64),
+    #[error(transparent)]
+    Command(#[from] CommandError),
+    #[error("failed to refresh the LVM report")]
+    Report(#[from] ReportError),
+}
+
+/// How [`DiskManager::vg_activate_on`](crate::DiskManager::vg_activate_on) activates a VG.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VgActivation {
+    /// The VG is activated by its UUID, without modifying it.
+    Uuid { vg: String, uuid: String },
+    /// Another VG with the same name is active, so the VG on `pvs` is imported under the
+    /// temporary `name`. It is given back its name when its LUKS volume is locked, but
+    /// keeps the new UUIDs which the import gives it and its PVs.
+    Import {
+        vg: String,
+        uuid: String,
+        name: String,
+        pvs: Vec<String>,
+    },
+}
+
+/// The size of a new or resized LV.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub enum LvSize {
+    /// Size in bytes, which LVM rounds up to a multiple of the extent size.
+    Bytes(u64),
+    /// Percentage of the free space in the VG.
+    PercentFree(u8),
+}
+
+impl LvSize {
+    fn args(self) -> [String; 2] {
+        match self {
+            LvSize::Bytes(bytes) => ["-L".into(), [&bytes.to_string(), "b"].concat()],
+            LvSize::PercentFree(percent) => ["-l".into(), [&percent.to_string(), "%FREE"].concat()],
+        }
+    }
+}
+
+pub fn lv_deactivate(lv: &str) -> Result<(), LvmError> {
+    eprintln!("deactivating LVM LV {}", lv);
+    run("lvchange", &["-an", lv])
+}
+
+pub fn lv_activate(lv: &str) -> Result<(), LvmError> {
+    eprintln!("activating LVM LV {}", lv);
+    run("lvchange", &["-ay", lv])
+}
+
+pub fn vg_deactivate(vg: &str) -> Result<(), LvmError> {
+    eprintln!("deactivating LVM VG {}", vg);
+    run("vgchange", &["-an", vg])
+}
+
+pub fn vg_activate(vg: &str) -> Result<(), LvmError> {
+    eprintln!("activating LVM VG {}", vg);
+    run("vgchange", &["-ay", vg])
+}
+
+pub fn vg_activate_all() -> Result<(), LvmError> {
+    eprintln!("activating all LVM VGs");
+    run("vgchange", &["-ay"])
+}
+
+/// Activate a VG by its UUID, for when several VGs share its name.
+pub fn vg_activate_uuid(uuid: &str) -> Result<(), LvmError> {
+    eprintln!("activating LVM VG with UUID {}", uuid);
+    run(
+        "vgchange",
+        &["-ay", "--select", &["vg_uuid=", uuid].concat()],
+    )
+}
+
+/// Rename the VG on a set of PVs to `base_name`, and give it and its PVs new UUIDs, so that
+/// it no longer collides with another VG which it was cloned from or shares a name with.
+pub fn vg_import_clone(base_name: &str, pvs: &[&str]) -> Result<(), LvmError> {
+    eprintln!("importing LVM VG on {:?} as {}", pvs, base_name);
+
+    let mut args = vec!["--basevgname", base_name];
+    args.extend_from_slice(pvs);
+
+    run("vgimportclone", &args)
+}
+
+/// Rename a VG while LVM only sees its PVs, so that it may take the name of another VG,
+/// such as the name it had before [`vg_import_clone`].
+pub fn vg_rename_on(pvs: &[&str], vg: &str, new_name: &str) -> Result<(), LvmError> {
+    eprintln!("renaming LVM VG {} on {:?} to {}", vg, pvs, new_name);
+
+    let accept = pvs
+        .iter()
+        .map(|&pv| ["\"a|^", pv, "$|\", "].concat())
+        .collect::<String>();
+
+    let config = [
+        "devices { filter = [ ",
+        accept.as_str(),
+        "\"r|.*|\" ] global_filter = [ ",
+        accept.as_str(),
+        "\"r|.*|\" ] }",
+    ]
+    .concat();
+
+    run("vgrename", &["--config", &config, vg, new_name])
+}
+
+/// Initialize a device as an LVM PV.
+pub fn pv_create(device: &str) -> Result<(), LvmError> {
+    eprintln!("creating LVM PV on {}", device);
+    run("pvcreate", &["--yes", device])
+}
+
+/// Wipe the LVM label from a PV which is not in a VG.
+pub fn pv_remove(device: &str) -> Result<(), LvmError> {
+    eprintln!("removing LVM PV from {}", device);
+    run("pvremove", &["--yes", device])
+}
+
+/// Create a VG from one or more PVs.
+pub fn vg_create(vg: &str, pvs: &[&str]) -> Result<(), LvmError> {
+    eprintln!("creating LVM VG {} on {:?}", vg, pvs);
+
+    let mut args = vec![vg];
+    args.extend_from_slice(pvs);
+
+    run("vgcreate", &args)
+}
+
+pub fn vg_rename(vg: &str, new_name: &str) -> Result<(), LvmError> {
+    eprintln!("renaming LVM VG {} to {}", vg, new_name);
+    run("vgrename", &[vg, new_name])
+}
+
+/// Remove a VG, along with every LV in it.
+pub fn vg_remove(vg: &str) -> Result<(), LvmError> {
+    eprintln!("removing LVM VG {}", vg);
+    run("vgremove", &["--force", vg])
+}
+
+/// Create an LV in a VG, wiping any signatures found where it is placed.
+pub fn lv_create(vg: &str, lv: &str, size: LvSize) -> Result<(), LvmError> {
+    eprintln!("creating LVM LV {}/{} of {:?}", vg, lv, size);
+
+    let [flag, size] = size.args();
+    run("lvcreate", &["--yes", "-n", lv, &flag, &size, vg])
+}
+
+/// Grow or shrink an LV, resizing its filesystem with it if `resize_fs` is set.
+pub fn lv_resize(vg: &str, lv: &str, size: LvSize, resize_fs: bool) -> Result<(), LvmError> {
+    eprintln!("resizing LVM LV {}/{} to {:?}", vg, lv, size);
+
+    let path = [vg, "/", lv].concat();
+    let [flag, size] = size.args();
+
+    let mut args = vec!["--yes", flag.as_str(), size.as_str()];
+
+    if resize_fs {
+        args.push("--resizefs");
+    }
+
+    args.push(path.as_str());
+
+    run("lvresize", &args)
+}
+
+pub fn lv_rename(vg: &str, lv: &str, new_name: &str) -> Result<(), LvmError> {
+    eprintln!("renaming LVM LV {}/{} to {}", vg, lv, new_name);
+    run("lvrename", &[vg, lv, new_name])
+}
+
+pub fn lv_remove(vg: &str, lv: &str) -> Result<(), LvmError> {
+    eprintln!("removing LVM LV {}/{}", vg, lv);
+    run("lvremove", &["--force", &[vg, "/", lv].concat()])
+}
+
+/// Create a snapshot of an LV, which may grow to `size` with changes to either LV.
+pub fn snapshot_create(vg: &str, origin: &str, name: &str, size: LvSize) -> Result<(), LvmError> {
+    eprintln!("creating LVM snapshot {} of {}/{}", name, vg, origin);
+
+    let origin = [vg, "/", origin].concat();
+    let [flag, size] = size.args();
+    run(
+        "lvcreate",
+        &["--yes", "--snapshot", "-n", name, &flag, &size, &origin],
+    )
+}
+
+/// Create a snapshot of a thin volume, which is allocated from the same thin pool.
+pub fn thin_snapshot_create(vg: &str, origin: &str, name: &str) -> Result<(), LvmError> {
+    eprintln!("creating LVM thin snapshot {} of {}/{}", name, vg, origin);
+
+    let origin = [vg, "/", origin].concat();
+    run("lvcreate", &["--yes", "--snapshot", "-n", name, &origin])
+}
+
+/// Roll the origin of a snapshot back to it, removing the snapshot.
+///
+/// If the origin is in use, the merge is deferred until it is next activated.
+pub fn snapshot_merge(vg: &str, snapshot: &str) -> Result<(), LvmError> {
+    eprintln!("merging LVM snapshot {}/{}", vg, snapshot);
+    run(
+        "lvconvert",
+        &["--yes", "--merge", &[vg, "/", snapshot].concat()],
+    )
+}
+
+/// Create a thin pool in a VG, from which thin volumes are allocated on demand.
+pub fn thin_pool_create(vg: &str, pool: &str, size: LvSize) -> Result<(), LvmError> {
+    eprintln!("creating LVM thin pool {}/{} of {:?}", vg, pool, size);
+
+    let [flag, size] = size.args();
+    run(
+        "lvcreate",
+        &["--yes", "--type", "thin-pool", "-n", pool, &flag, &size, vg],
+    )
+}
+
+/// Create a thin volume with a virtual size of `bytes`, which may exceed the size of its pool.
+pub fn thin_create(vg: &str, pool: &str, name: &str, bytes: u64) -> Result<(), LvmError> {
+    eprintln!("creating LVM thin volume {}/{} in {}", vg, name, pool);
+
+    let size = [&bytes.to_string(), "b"].concat();
+    run(
+        "lvcreate",
+        &[
+            "--yes",
+            "--type",
+            "thin",
+            "-n",
+            name,
+            "-V",
+            &size,
+            "--thinpool",
+            pool,
+            vg,
+        ],
+    )
+}
+
+fn run(program: &'static str, args: &[&str]) -> Result<(), LvmError> {
+    command::run(program, args)?;
+    Ok(())
+}
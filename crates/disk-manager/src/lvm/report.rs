@@ -0,0 +1,426 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! LVM physical volumes, volume groups, and logical volumes, as reported by `pvs`, `vgs`,
+//! and `lvs`. Unlike udev, these also describe volume groups which are not active.
+
+use crate::command::{self, CommandError};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Error)]
+pub enum ReportError {
+    #[error(transparent)]
+    Command(#[from] CommandError),
+    #[error("failed to parse the report of {0}")]
+    Json(&'static str, #[source] serde_json::Error),
+}
+
+const PV_FIELDS: &str = "pv_name,pv_uuid,vg_name,vg_uuid,pv_size,pv_free,pv_tags";
+
+const VG_FIELDS: &str = "vg_name,vg_uuid,vg_size,vg_free,vg_extent_size,vg_extent_count,\
+                         vg_free_count,vg_tags,pv_count,lv_count";
+
+const LV_FIELDS: &str = "lv_name,lv_uuid,vg_name,lv_size,lv_path,lv_dm_path,lv_active,lv_attr,\
+                         lv_tags,origin,pool_lv,segtype";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PhysicalVolume {
+    /// The device which holds the PV, such as `/dev/sda2` or `/dev/dm-0`.
+    pub devname: String,
+    pub uuid: String,
+    /// The VG which the PV belongs to, if any.
+    pub vg_name: Option<String>,
+    /// The UUID of the VG, which tells apart VGs that share a name.
+    pub vg_uuid: Option<String>,
+    /// Size in bytes.
+    pub size: u64,
+    /// Unallocated bytes.
+    pub free: u64,
+    pub tags: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VolumeGroup {
+    pub name: String,
+    pub uuid: String,
+    /// Size in bytes.
+    pub size: u64,
+    /// Unallocated bytes.
+    pub free: u64,
+    /// Size of an extent in bytes.
+    pub extent_size: u64,
+    pub extent_count: u64,
+    pub free_extents: u64,
+    pub pv_count: u32,
+    pub lv_count: u32,
+    pub tags: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LogicalVolume {
+    pub name: String,
+    pub uuid: String,
+    pub vg_name: String,
+    /// Size in bytes.
+    pub size: u64,
+    /// Path of the LV, such as `/dev/vg/lv`, which is empty for hidden LVs.
+    pub path: String,
+    /// Path of the device map, such as `/dev/mapper/vg-lv`.
+    pub dm_path: String,
+    pub active: bool,
+    /// Attribute characters, as described by `lvs(8)`.
+    pub attr: String,
+    pub tags: Vec<String>,
+    /// The LV which this LV is a snapshot of.
+    pub origin: Option<String>,
+    /// The thin pool which a thin volume is allocated from.
+    pub pool: Option<String>,
+    /// Types of each of the LV's segments, such as `linear`, `striped`, or `thin`.
+    pub segment_types: Vec<String>,
+}
+
+impl VolumeGroup {
+    /// The number of extents needed to hold `bytes`.
+    pub fn extents_for(&self, bytes: u64) -> u64 {
+        match self.extent_size {
+            0 => 0,
+            extent_size => bytes / extent_size + (bytes % extent_size != 0) as u64,
+        }
+    }
+}
+
+impl LogicalVolume {
+    pub fn is_snapshot(&self) -> bool {
+        self.origin.is_some()
+    }
+
+    pub fn is_thin_pool(&self) -> bool {
+        self.segment_types.iter().any(|type_| type_ == "thin-pool")
+    }
+
+    pub fn is_thin(&self) -> bool {
+        self.pool.is_some()
+    }
+}
+
+/// Every PV, VG, and LV which LVM knows of.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Report {
+    pub pvs: Vec<PhysicalVolume>,
+    pub vgs: Vec<VolumeGroup>,
+    pub lvs: Vec<LogicalVolume>,
+}
+
+impl Report {
+    /// The PV on a device.
+    pub fn pv(&self, devname: &str) -> Option<&PhysicalVolume> {
+        self.pvs.iter().find(|pv| pv.devname == devname)
+    }
+
+    pub fn vg(&self, name: &str) -> Option<&VolumeGroup> {
+        self.vgs.iter().find(|vg| vg.name == name)
+    }
+
+    pub fn lv(&self, vg: &str, name: &str) -> Option<&LogicalVolume> {
+        self.lvs
+            .iter()
+            .find(|lv| lv.vg_name == vg && lv.name == name)
+    }
+
+    /// The PVs which a VG is made of.
+    pub fn pvs_of<'a>(&'a self, vg: &'a str) -> impl Iterator<Item = &'a PhysicalVolume> + 'a {
+        self.pvs
+            .iter()
+            .filter(move |pv| pv.vg_name.as_deref() == Some(vg))
+    }
+
+    /// The snapshots of an LV.
+    pub fn snapshots_of<'a>(
+        &'a self,
+        vg: &'a str,
+        origin: &'a str,
+    ) -> impl Iterator<Item = &'a LogicalVolume> + 'a {
+        self.lvs_of(vg)
+            .filter(move |lv| lv.origin.as_deref() == Some(origin))
+    }
+
+    /// The LVs in a VG.
+    pub fn lvs_of<'a>(&'a self, vg: &'a str) -> impl Iterator<Item = &'a LogicalVolume> + 'a {
+        self.lvs.iter().filter(move |lv| lv.vg_name == vg)
+    }
+}
+
+/// Query LVM for every PV, VG, and LV.
+pub fn report() -> Result<Report, ReportError> {
+    let pvs = parse_pvs(&run("pvs", PV_FIELDS)?)?
+        .into_iter()
+        .map(|mut pv| {
+            // PVs on device maps are reported by their `/dev/mapper` symlinks.
+            pv.devname = crate::mounts::canonical_source(&pv.devname);
+            pv
+        })
+        .collect();
+
+    Ok(Report {
+        pvs,
+        vgs: parse_vgs(&run("vgs", VG_FIELDS)?)?,
+        lvs: parse_lvs(&run("lvs", LV_FIELDS)?)?,
+    })
+}
+
+/// Parse the output of `pvs --reportformat json`.
+pub fn parse_pvs(json: &str) -> Result<Vec<PhysicalVolume>, ReportError> {
+    let pvs = rows("pvs", json, "pv")?
+        .iter()
+        .map(|row| PhysicalVolume {
+            devname: string(row, "pv_name"),
+            uuid: string(row, "pv_uuid"),
+            vg_name: optional(row, "vg_name"),
+            vg_uuid: optional(row, "vg_uuid"),
+            size: number(row, "pv_size"),
+            free: number(row, "pv_free"),
+            tags: tags(row, "pv_tags"),
+        })
+        .collect();
+
+    Ok(pvs)
+}
+
+/// Parse the output of `vgs --reportformat json`.
+pub fn parse_vgs(json: &str) -> Result<Vec<VolumeGroup>, ReportError> {
+    let vgs = rows("vgs", json, "vg")?
+        .iter()
+        .map(|row| VolumeGroup {
+            name: string(row, "vg_name"),
+            uuid: string(row, "vg_uuid"),
+            size: number(row, "vg_size"),
+            free: number(row, "vg_free"),
+            extent_size: number(row, "vg_extent_size"),
+            extent_count: number(row, "vg_extent_count"),
+            free_extents: number(row, "vg_free_count"),
+            pv_count: number(row, "pv_count") as u32,
+            lv_count: number(row, "lv_count") as u32,
+            tags: tags(row, "vg_tags"),
+        })
+        .collect();
+
+    Ok(vgs)
+}
+
+/// Parse the output of `lvs --reportformat json`.
+///
+/// Segment fields cause a row to be reported for each segment, so rows are merged by UUID.
+pub fn parse_lvs(json: &str) -> Result<Vec<LogicalVolume>, ReportError> {
+    let mut lvs: Vec<LogicalVolume> = Vec::new();
+
+    for row in rows("lvs", json, "lv")? {
+        let uuid = string(&row, "lv_uuid");
+        let segtype = string(&row, "segtype");
+
+        if let Some(lv) = lvs.iter_mut().find(|lv| lv.uuid == uuid) {
+            lv.segment_types.push(segtype);
+            continue;
+        }
+
+        lvs.push(LogicalVolume {
+            name: string(&row, "lv_name"),
+            uuid,
+            vg_name: string(&row, "vg_name"),
+            size: number(&row, "lv_size"),
+            path: string(&row, "lv_path"),
+            dm_path: string(&row, "lv_dm_path"),
+            active: string(&row, "lv_active") == "active",
+            attr: string(&row, "lv_attr"),
+            tags: tags(&row, "lv_tags"),
+            origin: optional(&row, "origin"),
+            pool: optional(&row, "pool_lv"),
+            segment_types: vec![segtype],
+        });
+    }
+
+    Ok(lvs)
+}
+
+type Row = BTreeMap<String, String>;
+
+#[derive(Deserialize)]
+struct Output {
+    report: Vec<BTreeMap<String, Vec<Row>>>,
+}
+
+/// The rows of a report, where `kind` is the key which the rows are listed under.
+fn rows(program: &'static str, json: &str, kind: &str) -> Result<Vec<Row>, ReportError> {
+    let output =
+        serde_json::from_str::<Output>(json).map_err(|why| ReportError::Json(program, why))?;
+
+    let rows = output
+        .report
+        .into_iter()
+        .filter_map(|mut report| report.remove(kind))
+        .flatten()
+        .collect();
+
+    Ok(rows)
+}
+
+fn string(row: &Row, field: &str) -> String {
+    row.get(field)
+        .map(|value| value.trim().to_owned())
+        .unwrap_or_default()
+}
+
+fn optional(row: &Row, field: &str) -> Option<String> {
+    Some(string(row, field)).filter(|value| !value.is_empty())
+}
+
+fn number(row: &Row, field: &str) -> u64 {
+    row.get(field)
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or_default()
+}
+
+fn tags(row: &Row, field: &str) -> Vec<String> {
+    row.get(field)
+        .map(|tags| {
+            tags.split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn run(program: &'static str, fields: &str) -> Result<String, ReportError> {
+    let args = [
+        "--reportformat",
+        "json",
+        "--units",
+        "b",
+        "--nosuffix",
+        "-o",
+        fields,
+    ];
+
+    Ok(command::run(program, &args)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PVS: &str = r#"
+  {
+      "report": [
+          {
+              "pv": [
+                  {"pv_name":"/dev/mapper/cryptdata", "pv_uuid":"pv-uuid-0", "vg_name":"data", "vg_uuid":"vg-uuid-0", "pv_size":"34355544064", "pv_free":"0", "pv_tags":""},
+                  {"pv_name":"/dev/sdb1", "pv_uuid":"pv-uuid-1", "vg_name":"", "vg_uuid":"", "pv_size":"1073741824", "pv_free":"1073741824", "pv_tags":"spare,fast"}
+              ]
+          }
+      ]
+  }
+"#;
+
+    const VGS: &str = r#"
+  {
+      "report": [
+          {
+              "vg": [
+                  {"vg_name":"data", "vg_uuid":"vg-uuid-0", "vg_size":"34355544064", "vg_free":"4194304", "vg_extent_size":"4194304", "vg_extent_count":"8191", "vg_free_count":"1", "vg_tags":"", "pv_count":"1", "lv_count":"3"}
+              ]
+          }
+      ]
+  }
+"#;
+
+    const LVS: &str = r#"
+  {
+      "report": [
+          {
+              "lv": [
+                  {"lv_name":"root", "lv_uuid":"lv-uuid-0", "vg_name":"data", "lv_size":"17179869184", "lv_path":"/dev/data/root", "lv_dm_path":"/dev/mapper/data-root", "lv_active":"active", "lv_attr":"owi-aos---", "lv_tags":"", "origin":"", "pool_lv":"", "segtype":"linear"},
+                  {"lv_name":"root", "lv_uuid":"lv-uuid-0", "vg_name":"data", "lv_size":"17179869184", "lv_path":"/dev/data/root", "lv_dm_path":"/dev/mapper/data-root", "lv_active":"active", "lv_attr":"owi-aos---", "lv_tags":"", "origin":"", "pool_lv":"", "segtype":"striped"},
+                  {"lv_name":"root-snap", "lv_uuid":"lv-uuid-1", "vg_name":"data", "lv_size":"1073741824", "lv_path":"/dev/data/root-snap", "lv_dm_path":"/dev/mapper/data-root--snap", "lv_active":"", "lv_attr":"swi---s---", "lv_tags":"before-upgrade", "origin":"root", "pool_lv":"", "segtype":"linear"},
+                  {"lv_name":"pool", "lv_uuid":"lv-uuid-2", "vg_name":"data", "lv_size":"8589934592", "lv_path":"", "lv_dm_path":"/dev/mapper/data-pool", "lv_active":"active", "lv_attr":"twi-aotz--", "lv_tags":"", "origin":"", "pool_lv":"", "segtype":"thin-pool"}
+              ]
+          }
+      ]
+  }
+"#;
+
+    #[test]
+    fn physical_volumes() {
+        let pvs = parse_pvs(PVS).unwrap();
+        assert_eq!(pvs.len(), 2);
+
+        assert_eq!(pvs[0].devname, "/dev/mapper/cryptdata");
+        assert_eq!(pvs[0].vg_name.as_deref(), Some("data"));
+        assert_eq!(pvs[0].size, 34355544064);
+
+        // PVs outside of a VG report empty names.
+        assert_eq!(pvs[1].vg_name, None);
+        assert_eq!(pvs[1].vg_uuid, None);
+        assert_eq!(pvs[1].free, 1073741824);
+        assert_eq!(pvs[1].tags, ["spare", "fast"]);
+    }
+
+    #[test]
+    fn volume_groups() {
+        let vgs = parse_vgs(VGS).unwrap();
+        assert_eq!(vgs.len(), 1);
+
+        let vg = &vgs[0];
+        assert_eq!(vg.name, "data");
+        assert_eq!(vg.extent_size, 4194304);
+        assert_eq!((vg.extent_count, vg.free_extents), (8191, 1));
+        assert_eq!((vg.pv_count, vg.lv_count), (1, 3));
+        assert!(vg.tags.is_empty());
+
+        assert_eq!(vg.extents_for(0), 0);
+        assert_eq!(vg.extents_for(1), 1);
+        assert_eq!(vg.extents_for(4194304), 1);
+        assert_eq!(vg.extents_for(4194305), 2);
+        assert_eq!(vg.extents_for(u64::MAX), u64::MAX / 4194304 + 1);
+    }
+
+    #[test]
+    fn logical_volumes() {
+        let report = Report {
+            lvs: parse_lvs(LVS).unwrap(),
+            ..Report::default()
+        };
+
+        // Rows for each segment of an LV are merged.
+        assert_eq!(report.lvs.len(), 3);
+
+        let root = report.lv("data", "root").unwrap();
+        assert!(root.active);
+        assert_eq!(root.segment_types, ["linear", "striped"]);
+        assert!(!root.is_snapshot());
+
+        let snap = report.lv("data", "root-snap").unwrap();
+        assert!(!snap.active);
+        assert!(snap.is_snapshot());
+        assert_eq!(snap.tags, ["before-upgrade"]);
+
+        let snapshots = report.snapshots_of("data", "root").collect::<Vec<_>>();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].name, "root-snap");
+
+        let pool = report.lv("data", "pool").unwrap();
+        assert!(pool.is_thin_pool());
+        assert!(pool.path.is_empty());
+
+        assert_eq!(report.lvs_of("data").count(), 3);
+        assert!(report.lv("other", "root").is_none());
+    }
+
+    #[test]
+    fn invalid_json() {
+        assert!(matches!(
+            parse_pvs("pvs: not json"),
+            Err(ReportError::Json("pvs", _))
+        ));
+    }
+}
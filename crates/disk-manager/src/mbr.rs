@@ -0,0 +1,445 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Creation and modification of MBR partition tables with mbrman.
+
+use crate::block_types::PartitionKind;
+use mbrman::{MBRPartitionEntry, BOOT_ACTIVE, BOOT_INACTIVE, CHS, MBR};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const FAT32_LBA: u8 = 0x0C;
+pub const EXTENDED_LBA: u8 = 0x0F;
+pub const NTFS: u8 = 0x07;
+pub const LINUX_SWAP: u8 = 0x82;
+pub const LINUX: u8 = 0x83;
+pub const LINUX_LVM: u8 = 0x8E;
+pub const EFI: u8 = 0xEF;
+pub const LINUX_RAID: u8 = 0xFD;
+
+/// Whether the system ID describes an extended partition container.
+pub fn is_extended(sys: u8) -> bool {
+    matches!(sys, 0x05 | 0x0F | 0x85)
+}
+
+#[derive(Debug, Error)]
+pub enum MbrError {
+    #[error("failed to open {0:?}")]
+    Open(PathBuf, #[source] io::Error),
+    #[error("failed to read MBR partition table")]
+    Read(#[source] mbrman::Error),
+    #[error("failed to write MBR partition table")]
+    Write(#[source] mbrman::Error),
+    #[error("failed to sync partition table to disk")]
+    Sync(#[source] io::Error),
+    #[error("kernel failed to re-read the partition table")]
+    Reread(#[source] gptman::linux::BlockError),
+    #[error("partition {0} does not exist")]
+    PartitionNotFound(usize),
+    #[error("all four primary partition entries are in use")]
+    NoFreeEntries,
+    #[error("the disk already has an extended partition")]
+    ExtendedExists,
+    #[error("logical partitions require an extended partition")]
+    NoExtended,
+    #[error("no free region can hold {0} sectors")]
+    NoSpace(u32),
+    #[error("sectors {0}..={1} are outside of the usable area or overlap another partition")]
+    Overlap(u32, u32),
+    #[error("logical partitions cannot be moved, as their EBR would also need to be moved")]
+    MoveLogical,
+    #[error("failed to add logical partition")]
+    Logical(#[source] mbrman::Error),
+}
+
+/// Describes a partition to be added to an MBR partition table.
+#[derive(Clone, Debug)]
+pub struct MbrPartition {
+    pub kind: PartitionKind,
+    /// The system ID, such as [`LINUX`]. Ignored for extended partitions.
+    pub sys: u8,
+    pub bootable: bool,
+    /// First LBA of the partition. When `None`, the optimal aligned position is used.
+    pub start: Option<u32>,
+    /// Length of the partition in logical sectors.
+    pub sectors: u32,
+}
+
+impl MbrPartition {
+    pub fn new(kind: PartitionKind, sys: u8, sectors: u32) -> Self {
+        Self {
+            kind,
+            sys,
+            bootable: false,
+            start: None,
+            sectors,
+        }
+    }
+
+    pub fn bootable(mut self, bootable: bool) -> Self {
+        self.bootable = bootable;
+        self
+    }
+
+    pub fn start(mut self, start: u32) -> Self {
+        self.start = Some(start);
+        self
+    }
+}
+
+/// An in-memory MBR partition table which is written to its disk on `commit`.
+pub struct MbrTable {
+    file: File,
+    pub mbr: MBR,
+}
+
+impl MbrTable {
+    /// Creates a new, empty partition table for the disk at `path`.
+    ///
+    /// Nothing is written to the disk until the table is committed.
+    pub fn create(path: &Path, sector_size: u64) -> Result<Self, MbrError> {
+        let mut file = open(path)?;
+        let signature = rand::random::<[u8; 4]>();
+        let mbr =
+            MBR::new_from(&mut file, sector_size as u32, signature).map_err(MbrError::Read)?;
+        Ok(Self { file, mbr })
+    }
+
+    /// Reads the existing partition table of the disk at `path`.
+    pub fn open(path: &Path, sector_size: u64) -> Result<Self, MbrError> {
+        let mut file = open(path)?;
+        let mbr = MBR::read_from(&mut file, sector_size as u32).map_err(MbrError::Read)?;
+        Ok(Self { file, mbr })
+    }
+
+    /// Adds a partition, returning its partition number.
+    ///
+    /// Primary and extended partitions take numbers 1 through 4, and logical partitions
+    /// are numbered from 5 in the order that they appear in the EBR chain.
+    pub fn add(&mut self, partition: &MbrPartition) -> Result<usize, MbrError> {
+        match partition.kind {
+            PartitionKind::Logical => self.add_logical(partition),
+            PartitionKind::Extended => {
+                if self.extended().is_some() {
+                    return Err(MbrError::ExtendedExists);
+                }
+
+                let mut partition = partition.clone();
+                partition.sys = EXTENDED_LBA;
+                partition.bootable = false;
+                self.add_primary(&partition)
+            }
+            PartitionKind::Primary => self.add_primary(partition),
+        }
+    }
+
+    fn add_primary(&mut self, partition: &MbrPartition) -> Result<usize, MbrError> {
+        let number = (1..=4)
+            .find(|&number| self.mbr[number].is_unused())
+            .ok_or(MbrError::NoFreeEntries)?;
+
+        let start = match partition.start {
+            Some(start) => start,
+            // Zero-length partitions are rejected below, without searching for a place.
+            None if partition.sectors == 0 => 0,
+            None => self
+                .mbr
+                .find_optimal_place(partition.sectors)
+                .ok_or(MbrError::NoSpace(partition.sectors))?,
+        };
+
+        let end = end_of(start, partition.sectors)?;
+
+        let overlaps = (1..=4).any(|number| {
+            let entry = &self.mbr[number];
+            entry.is_used() && start <= last_sector(entry) && end >= entry.starting_lba
+        });
+
+        if start == 0 || end >= self.mbr.disk_size || start > end || overlaps {
+            return Err(MbrError::Overlap(start, end));
+        }
+
+        if partition.bootable {
+            self.clear_bootable();
+        }
+
+        self.mbr[number] = entry(partition, start);
+
+        Ok(number)
+    }
+
+    fn add_logical(&mut self, partition: &MbrPartition) -> Result<usize, MbrError> {
+        let (ext_start, ext_end) = self.extended().ok_or(MbrError::NoExtended)?;
+
+        // Each logical partition is preceded by its EBR, so at least one sector must be
+        // left free ahead of it.
+        let used = self.logical_regions();
+
+        let start = match partition.start {
+            Some(start) => start,
+            None => {
+                let align = self.mbr.align.max(1);
+                let mut cursor = ext_start;
+                let mut found = None;
+
+                let last = (ext_end.saturating_add(1), ext_end);
+
+                for &(used_start, used_end) in used.iter().chain(Some(&last)) {
+                    let candidate = align_up(cursor.saturating_add(1), align);
+                    let fits = candidate
+                        .checked_add(partition.sectors)
+                        .map_or(false, |next| next <= used_start);
+
+                    if fits {
+                        found = Some(candidate);
+                        break;
+                    }
+
+                    cursor = used_end.saturating_add(1);
+                }
+
+                found.ok_or(MbrError::NoSpace(partition.sectors))?
+            }
+        };
+
+        let end = end_of(start, partition.sectors)?;
+
+        let overlaps = used
+            .iter()
+            .any(|&(used_start, used_end)| start <= used_end && end >= used_start);
+
+        if start <= ext_start || end > ext_end || start > end || overlaps {
+            return Err(MbrError::Overlap(start, end));
+        }
+
+        let logical = self
+            .mbr
+            .push(partition.sys, start, partition.sectors)
+            .map_err(MbrError::Logical)?;
+
+        logical.partition.boot = if partition.bootable {
+            BOOT_ACTIVE
+        } else {
+            BOOT_INACTIVE
+        };
+
+        self.sort_logical();
+
+        let number = self
+            .mbr
+            .logical_partitions
+            .iter()
+            .position(|logical| logical.partition.starting_lba == start)
+            .map_or(5, |id| id + 5);
+
+        Ok(number)
+    }
+
+    /// Removes a partition by its number.
+    ///
+    /// Removing the extended partition also removes every logical partition inside of it.
+    pub fn remove(&mut self, number: usize) -> Result<(), MbrError> {
+        match number {
+            1..=4 => {
+                if self.mbr[number].is_unused() {
+                    return Err(MbrError::PartitionNotFound(number));
+                }
+
+                if is_extended(self.mbr[number].sys) {
+                    self.mbr.logical_partitions.clear();
+                }
+
+                self.mbr[number] = MBRPartitionEntry::empty();
+            }
+            _ => {
+                if number < 5 || number - 5 >= self.mbr.logical_partitions.len() {
+                    return Err(MbrError::PartitionNotFound(number));
+                }
+
+                self.mbr.logical_partitions.remove(number - 5);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves or resizes the table entry of a partition, without touching its contents.
+    ///
+    /// Logical partitions may only change in length.
+    pub fn resize(&mut self, number: usize, start: u32, sectors: u32) -> Result<(), MbrError> {
+        let current = self.entry(number)?.starting_lba;
+        let end = end_of(start, sectors)?;
+
+        if number >= 5 {
+            if start != current {
+                return Err(MbrError::MoveLogical);
+            }
+
+            let (_, ext_end) = self.extended().ok_or(MbrError::NoExtended)?;
+            let next = self
+                .logical_regions()
+                .into_iter()
+                .map(|(ebr, _)| ebr)
+                .find(|&ebr| ebr > start)
+                .unwrap_or_else(|| ext_end.saturating_add(1));
+
+            if start > end || end >= next {
+                return Err(MbrError::Overlap(start, end));
+            }
+        } else {
+            let overlaps = (1..=4).any(|other| {
+                let entry = &self.mbr[other];
+                other != number
+                    && entry.is_used()
+                    && start <= last_sector(entry)
+                    && end >= entry.starting_lba
+            });
+
+            if start == 0 || end >= self.mbr.disk_size || start > end || overlaps {
+                return Err(MbrError::Overlap(start, end));
+            }
+        }
+
+        let entry = self.entry_mut(number)?;
+        entry.starting_lba = start;
+        entry.sectors = sectors;
+
+        Ok(())
+    }
+
+    /// Sets the bootable flag of a partition, clearing it from every other partition.
+    pub fn set_bootable(&mut self, number: usize, bootable: bool) -> Result<(), MbrError> {
+        self.entry(number)?;
+
+        if bootable {
+            self.clear_bootable();
+        }
+
+        self.entry_mut(number)?.boot = if bootable { BOOT_ACTIVE } else { BOOT_INACTIVE };
+        Ok(())
+    }
+
+    /// Changes the system ID of a partition.
+    pub fn set_sys(&mut self, number: usize, sys: u8) -> Result<(), MbrError> {
+        self.entry_mut(number)?.sys = sys;
+        Ok(())
+    }
+
+    /// Fetches a partition entry which is in use.
+    pub fn entry(&self, number: usize) -> Result<&MBRPartitionEntry, MbrError> {
+        match number {
+            1..=4 if self.mbr[number].is_used() => Ok(&self.mbr[number]),
+            _ if number >= 5 => self
+                .mbr
+                .logical_partitions
+                .get(number - 5)
+                .map(|logical| &logical.partition)
+                .ok_or(MbrError::PartitionNotFound(number)),
+            _ => Err(MbrError::PartitionNotFound(number)),
+        }
+    }
+
+    fn entry_mut(&mut self, number: usize) -> Result<&mut MBRPartitionEntry, MbrError> {
+        self.entry(number)?;
+
+        if number <= 4 {
+            Ok(&mut self.mbr[number])
+        } else {
+            Ok(&mut self.mbr.logical_partitions[number - 5].partition)
+        }
+    }
+
+    /// The first and last sector of the extended partition, if there is one.
+    pub fn extended(&self) -> Option<(u32, u32)> {
+        (1..=4)
+            .map(|number| &self.mbr[number])
+            .find(|entry| entry.is_used() && is_extended(entry.sys))
+            .map(|entry| (entry.starting_lba, last_sector(entry)))
+    }
+
+    /// Regions occupied by logical partitions and their EBRs, sorted by position.
+    fn logical_regions(&self) -> Vec<(u32, u32)> {
+        let mut regions = self
+            .mbr
+            .logical_partitions
+            .iter()
+            .map(|logical| (logical.absolute_ebr_lba, last_sector(&logical.partition)))
+            .collect::<Vec<_>>();
+
+        regions.sort_unstable();
+        regions
+    }
+
+    /// Keeps the EBR chain in on-disk order, so that numbering follows position.
+    fn sort_logical(&mut self) {
+        self.mbr
+            .logical_partitions
+            .sort_by_key(|logical| logical.partition.starting_lba);
+    }
+
+    fn clear_bootable(&mut self) {
+        for number in 1..=4 {
+            self.mbr[number].boot = BOOT_INACTIVE;
+        }
+
+        for logical in self.mbr.logical_partitions.iter_mut() {
+            logical.partition.boot = BOOT_INACTIVE;
+        }
+    }
+
+    /// Writes the partition table and EBR chain to the disk, and has the kernel re-read it.
+    pub fn commit(mut self) -> Result<(), MbrError> {
+        eprintln!("writing MBR partition table");
+
+        self.mbr
+            .write_into(&mut self.file)
+            .map_err(MbrError::Write)?;
+
+        self.file.sync_all().map_err(MbrError::Sync)?;
+
+        gptman::linux::reread_partition_table(&mut self.file).map_err(MbrError::Reread)
+    }
+}
+
+fn entry(partition: &MbrPartition, start: u32) -> MBRPartitionEntry {
+    MBRPartitionEntry {
+        boot: if partition.bootable {
+            BOOT_ACTIVE
+        } else {
+            BOOT_INACTIVE
+        },
+        first_chs: CHS::empty(),
+        sys: partition.sys,
+        last_chs: CHS::empty(),
+        starting_lba: start,
+        sectors: partition.sectors,
+    }
+}
+
+fn last_sector(entry: &MBRPartitionEntry) -> u32 {
+    entry.starting_lba.saturating_add(entry.sectors.max(1) - 1)
+}
+
+/// The last sector of a partition, which must not be empty or extend past the last LBA.
+fn end_of(start: u32, sectors: u32) -> Result<u32, MbrError> {
+    sectors
+        .checked_sub(1)
+        .and_then(|length| start.checked_add(length))
+        .ok_or_else(|| {
+            let end = start.saturating_add(sectors).saturating_sub(1);
+            MbrError::Overlap(start, end)
+        })
+}
+
+fn align_up(value: u32, align: u32) -> u32 {
+    value.saturating_add(align - 1) / align * align
+}
+
+fn open(path: &Path) -> Result<File, MbrError> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|why| MbrError::Open(path.to_owned(), why))
+}
@@ -0,0 +1,104 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Assembling, creating, and stopping Linux software RAID arrays with mdadm.
+
+use crate::claim::ClaimError;
+use crate::command::{self, CommandError};
+
+#[derive(Debug, Error)]
+pub enum MdError {
+    #[error("cannot modify a device which does not exist")]
+    DeviceNotFound,
+    #[error("{0} is not an MD RAID array")]
+    NotAnArray(String),
+    #[error("{0} holds the live installation media")]
+    LiveMedia(String),
+    #[error(transparent)]
+    Claim(#[from] ClaimError),
+    #[error(transparent)]
+    Command(#[from] CommandError),
+}
+
+/// RAID levels which arrays may be created with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum MdLevel {
+    Raid0,
+    Raid1,
+    Raid5,
+    Raid6,
+    Raid10,
+}
+
+impl MdLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MdLevel::Raid0 => "raid0",
+            MdLevel::Raid1 => "raid1",
+            MdLevel::Raid5 => "raid5",
+            MdLevel::Raid6 => "raid6",
+            MdLevel::Raid10 => "raid10",
+        }
+    }
+}
+
+/// Assemble every array which is found on the system's devices.
+pub fn assemble_scan() -> Result<(), MdError> {
+    eprintln!("assembling all MD RAID arrays");
+    run(&["--assemble", "--scan"])
+}
+
+/// Assemble an array from its members.
+pub fn assemble(array: &str, members: &[&str]) -> Result<(), MdError> {
+    eprintln!("assembling MD RAID array {} from {:?}", array, members);
+
+    let mut args = vec!["--assemble", array];
+    args.extend_from_slice(members);
+
+    run(&args)
+}
+
+/// Create an array from `members`, which are overwritten with its superblock.
+pub fn create(array: &str, level: MdLevel, members: &[&str]) -> Result<(), MdError> {
+    eprintln!(
+        "creating {} MD RAID array {} from {:?}",
+        level.as_str(),
+        array,
+        members
+    );
+
+    let level = ["--level=", level.as_str()].concat();
+    let count = ["--raid-devices=", &members.len().to_string()].concat();
+
+    let mut args = vec![
+        "--create",
+        array,
+        "--run",
+        "--metadata=1.2",
+        level.as_str(),
+        count.as_str(),
+    ];
+
+    args.extend_from_slice(members);
+
+    run(&args)
+}
+
+/// Stop an array, releasing its members.
+pub fn stop(array: &str) -> Result<(), MdError> {
+    eprintln!("stopping MD RAID array {}", array);
+    run(&["--stop", array])
+}
+
+/// Normalize an array UUID, which mdadm separates with colons and blkid with hyphens.
+pub(crate) fn uuid_key(uuid: &str) -> String {
+    uuid.chars()
+        .filter(char::is_ascii_hexdigit)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn run(args: &[&str]) -> Result<(), MdError> {
+    command::run("mdadm", args)?;
+    Ok(())
+}
@@ -0,0 +1,192 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Creation of filesystems on block devices.
+
+use crate::claim::ClaimError;
+use crate::command::{self, CommandError};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Error)]
+pub enum MkfsError {
+    #[error("cannot format a device which does not exist")]
+    DeviceNotFound,
+    #[error("{0} holds the live installation media")]
+    LiveMedia(String),
+    #[error("{0} filesystems are not supported")]
+    Unsupported(String),
+    #[error("{0:?} is not a valid {1} label")]
+    InvalidLabel(String, FileSystemType),
+    #[error("{0:?} is not a valid {1} UUID")]
+    InvalidUuid(String, FileSystemType),
+    #[error(transparent)]
+    Claim(#[from] ClaimError),
+    #[error(transparent)]
+    Command(#[from] CommandError),
+}
+
+/// A filesystem which can be created by [`format`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FileSystemType {
+    Btrfs,
+    Ext4,
+    F2fs,
+    Ntfs,
+    Swap,
+    /// FAT32, as used by EFI system partitions.
+    Vfat,
+    Xfs,
+}
+
+impl FileSystemType {
+    /// The name of the filesystem as reported by udev's `ID_FS_TYPE`.
+    pub fn id(self) -> &'static str {
+        match self {
+            FileSystemType::Btrfs => "btrfs",
+            FileSystemType::Ext4 => "ext4",
+            FileSystemType::F2fs => "f2fs",
+            FileSystemType::Ntfs => "ntfs",
+            FileSystemType::Swap => "swap",
+            FileSystemType::Vfat => "vfat",
+            FileSystemType::Xfs => "xfs",
+        }
+    }
+
+    /// Maximum length of a label in bytes.
+    fn label_limit(self) -> usize {
+        match self {
+            FileSystemType::Btrfs => 255,
+            FileSystemType::Ext4 => 16,
+            FileSystemType::F2fs => 512,
+            FileSystemType::Ntfs => 128,
+            FileSystemType::Swap => 15,
+            FileSystemType::Vfat => 11,
+            FileSystemType::Xfs => 12,
+        }
+    }
+}
+
+impl fmt::Display for FileSystemType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.id())
+    }
+}
+
+impl FromStr for FileSystemType {
+    type Err = MkfsError;
+
+    fn from_str(fs: &str) -> Result<Self, Self::Err> {
+        let fs = match fs {
+            "btrfs" => FileSystemType::Btrfs,
+            "ext4" => FileSystemType::Ext4,
+            "f2fs" => FileSystemType::F2fs,
+            "ntfs" => FileSystemType::Ntfs,
+            "swap" => FileSystemType::Swap,
+            "vfat" | "fat32" => FileSystemType::Vfat,
+            "xfs" => FileSystemType::Xfs,
+            _ => return Err(MkfsError::Unsupported(fs.to_owned())),
+        };
+
+        Ok(fs)
+    }
+}
+
+/// Describes a filesystem to be created.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Mkfs {
+    pub fs: FileSystemType,
+    pub label: Option<String>,
+    /// When `None`, the formatting tool generates a random UUID.
+    ///
+    /// FAT volume IDs are given as `XXXX-XXXX`.
+    pub uuid: Option<String>,
+}
+
+impl Mkfs {
+    pub fn new(fs: FileSystemType) -> Self {
+        Self {
+            fs,
+            label: None,
+            uuid: None,
+        }
+    }
+
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn uuid(mut self, uuid: impl Into<String>) -> Self {
+        self.uuid = Some(uuid.into());
+        self
+    }
+
+    /// Checks that the label and UUID are accepted by the filesystem.
+    pub fn validate(&self) -> Result<(), MkfsError> {
+        if let Some(label) = self.label.as_ref() {
+            let valid = !label.is_empty()
+                && label.len() <= self.fs.label_limit()
+                && !label.contains(char::is_control)
+                && (self.fs != FileSystemType::Vfat || label.is_ascii());
+
+            if !valid {
+                return Err(MkfsError::InvalidLabel(label.clone(), self.fs));
+            }
+        }
+
+        if let Some(uuid) = self.uuid.as_ref() {
+            let hex = uuid.chars().filter(|&c| c != '-').collect::<String>();
+
+            let valid = match self.fs {
+                FileSystemType::Ntfs => false,
+                FileSystemType::Vfat => hex.len() == 8,
+                _ => hex.len() == 32,
+            } && hex.chars().all(|c| c.is_ascii_hexdigit());
+
+            if !valid {
+                return Err(MkfsError::InvalidUuid(uuid.clone(), self.fs));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Create a filesystem on a block device, destroying its existing contents.
+pub fn format(devname: &str, options: &Mkfs) -> Result<(), MkfsError> {
+    options.validate()?;
+
+    let (program, args, label_arg): (&'static str, &[&str], &str) = match options.fs {
+        FileSystemType::Btrfs => ("mkfs.btrfs", &["-f"], "-L"),
+        FileSystemType::Ext4 => ("mkfs.ext4", &["-F", "-q"], "-L"),
+        FileSystemType::F2fs => ("mkfs.f2fs", &["-f", "-q"], "-l"),
+        FileSystemType::Ntfs => ("mkfs.ntfs", &["--fast", "--force", "--quiet"], "-L"),
+        FileSystemType::Swap => ("mkswap", &["-f"], "-L"),
+        FileSystemType::Vfat => ("mkfs.fat", &["-F", "32"], "-n"),
+        FileSystemType::Xfs => ("mkfs.xfs", &["-f", "-q"], "-L"),
+    };
+
+    eprintln!("formatting {} as {}", devname, options.fs);
+
+    let uuid = options.uuid.as_ref().map(|uuid| match options.fs {
+        FileSystemType::Vfat => ("-i", uuid.replace('-', "")),
+        FileSystemType::Xfs => ("-m", format!("uuid={}", uuid)),
+        _ => ("-U", uuid.clone()),
+    });
+
+    let mut args = args.to_vec();
+
+    if let Some(label) = options.label.as_ref() {
+        args.extend_from_slice(&[label_arg, label.as_str()]);
+    }
+
+    if let Some((flag, uuid)) = uuid.as_ref() {
+        args.extend_from_slice(&[*flag, uuid.as_str()]);
+    }
+
+    args.push(devname);
+
+    command::run(program, &args)?;
+    Ok(())
+}
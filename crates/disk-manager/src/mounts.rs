@@ -0,0 +1,236 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Mounted filesystems and active swap, from `/proc/self/mountinfo` and `/proc/swaps`.
+
+use crate::command::{self, CommandError};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Error)]
+pub enum MountsError {
+    #[error("cannot unmount a device which does not exist")]
+    DeviceNotFound,
+    #[error("failed to unmount {0:?}")]
+    Unmount(PathBuf, #[source] io::Error),
+    #[error("failed to disable swap on {0}")]
+    Swapoff(String, #[source] CommandError),
+}
+
+/// A line of `/proc/self/mountinfo`.
+#[derive(Clone, Debug)]
+pub struct MountInfo {
+    pub id: u32,
+    pub parent_id: u32,
+    /// The directory within the filesystem which is mounted, such as a btrfs subvolume.
+    pub root: PathBuf,
+    pub target: PathBuf,
+    pub options: String,
+    pub fs_type: String,
+    /// The mounted device, such as `/dev/sda1`, or a name like `tmpfs` for virtual filesystems.
+    pub source: String,
+}
+
+/// A line of `/proc/swaps`.
+#[derive(Clone, Debug)]
+pub struct SwapInfo {
+    pub source: String,
+    /// Either `partition` or `file`.
+    pub kind: String,
+    /// Size in KiB.
+    pub size: u64,
+    /// Used space in KiB.
+    pub used: u64,
+    pub priority: i32,
+}
+
+/// Every mounted filesystem in the mount namespace of this process.
+pub fn mountinfo() -> io::Result<Vec<MountInfo>> {
+    fs::read_to_string("/proc/self/mountinfo").map(|text| parse_mountinfo(&text))
+}
+
+/// Every active swap device and file.
+pub fn swaps() -> io::Result<Vec<SwapInfo>> {
+    fs::read_to_string("/proc/swaps").map(|text| parse_swaps(&text))
+}
+
+/// Parse the contents of `/proc/self/mountinfo`.
+pub fn parse_mountinfo(text: &str) -> Vec<MountInfo> {
+    text.lines().filter_map(parse_mountinfo_line).collect()
+}
+
+fn parse_mountinfo_line(line: &str) -> Option<MountInfo> {
+    // Optional fields are terminated by a lone hyphen.
+    let (mount, filesystem) = line.split_once(" - ")?;
+
+    let mut fields = mount.split(' ');
+    let id = fields.next()?.parse::<u32>().ok()?;
+    let parent_id = fields.next()?.parse::<u32>().ok()?;
+    let _devnum = fields.next()?;
+    let root = PathBuf::from(unescape(fields.next()?));
+    let target = PathBuf::from(unescape(fields.next()?));
+    let options = fields.next()?.to_owned();
+
+    let mut fields = filesystem.split(' ');
+    let fs_type = fields.next()?.to_owned();
+    let source = unescape(fields.next()?);
+
+    Some(MountInfo {
+        id,
+        parent_id,
+        root,
+        target,
+        options,
+        fs_type,
+        source,
+    })
+}
+
+/// Parse the contents of `/proc/swaps`.
+pub fn parse_swaps(text: &str) -> Vec<SwapInfo> {
+    text.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+
+            Some(SwapInfo {
+                source: unescape(fields.next()?),
+                kind: fields.next()?.to_owned(),
+                size: fields.next()?.parse::<u64>().ok()?,
+                used: fields.next()?.parse::<u64>().ok()?,
+                priority: fields.next()?.parse::<i32>().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Resolve symlinks such as `/dev/mapper/cryptdata` to the device node they point to.
+pub fn canonical_source(source: &str) -> String {
+    match fs::canonicalize(source) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => source.to_owned(),
+    }
+}
+
+/// Whether a directory is a mount point.
+pub fn is_mounted(target: &Path) -> bool {
+    mountinfo().map_or(false, |mounts| {
+        mounts.iter().any(|mount| mount.target == target)
+    })
+}
+
+/// Unmount the filesystem mounted at `target`.
+pub fn unmount(target: &Path) -> Result<(), MountsError> {
+    eprintln!("unmounting {:?}", target);
+
+    sys_mount::unmount(target, sys_mount::UnmountFlags::empty())
+        .map_err(|why| MountsError::Unmount(target.to_owned(), why))
+}
+
+/// Stop using a device or file as swap.
+pub fn swapoff(source: &str) -> Result<(), MountsError> {
+    eprintln!("disabling swap on {}", source);
+
+    command::run("swapoff", &[source])
+        .map(|_| ())
+        .map_err(|why| MountsError::Swapoff(source.to_owned(), why))
+}
+
+/// Decode the octal escapes used for spaces, tabs, newlines, and backslashes.
+fn unescape(field: &str) -> String {
+    if !field.contains('\\') {
+        return field.to_owned();
+    }
+
+    let bytes = field.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let escape = match bytes[index] {
+            b'\\' => bytes.get(index + 1..index + 4).and_then(|octal| {
+                let octal = std::str::from_utf8(octal).ok()?;
+                u8::from_str_radix(octal, 8).ok()
+            }),
+            _ => None,
+        };
+
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 4;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw,errors=remount-ro
+25 22 259:1 / /boot/efi rw,relatime shared:2 - vfat /dev/nvme0n1p1 rw,fmask=0077
+31 22 0:26 /@home /home rw,relatime shared:3 master:1 - btrfs /dev/mapper/data-home rw
+40 22 8:17 / /media/My\\040Drive rw,nosuid - ntfs3 /dev/sdb1 rw
+41 22 0:5 / /dev rw,nosuid - devtmpfs udev rw,size=8000000k
+malformed line
+";
+
+    const SWAPS: &str = "\
+Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/nvme0n1p3                          partition\t4194300\t\t1024\t\t-2
+/swap\\040file                           file\t\t1048572\t\t0\t\t-3
+";
+
+    #[test]
+    fn mountinfo_lines() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        assert_eq!(mounts.len(), 5);
+
+        let root = &mounts[0];
+        assert_eq!((root.id, root.parent_id), (22, 1));
+        assert_eq!(root.target, Path::new("/"));
+        assert_eq!(root.fs_type, "ext4");
+        assert_eq!(root.source, "/dev/nvme0n1p2");
+        assert_eq!(root.options, "rw,relatime");
+
+        // Optional fields before the separator are skipped.
+        let home = &mounts[2];
+        assert_eq!(home.root, Path::new("/@home"));
+        assert_eq!(home.target, Path::new("/home"));
+        assert_eq!(home.source, "/dev/mapper/data-home");
+
+        assert_eq!(mounts[3].target, Path::new("/media/My Drive"));
+        assert_eq!(mounts[4].source, "udev");
+    }
+
+    #[test]
+    fn swaps_lines() {
+        let swaps = parse_swaps(SWAPS);
+        assert_eq!(swaps.len(), 2);
+
+        assert_eq!(swaps[0].source, "/dev/nvme0n1p3");
+        assert_eq!(swaps[0].kind, "partition");
+        assert_eq!((swaps[0].size, swaps[0].used), (4194300, 1024));
+        assert_eq!(swaps[0].priority, -2);
+
+        assert_eq!(swaps[1].source, "/swap file");
+        assert_eq!(swaps[1].kind, "file");
+    }
+
+    #[test]
+    fn octal_escapes() {
+        assert_eq!(unescape("plain"), "plain");
+        assert_eq!(unescape("a\\011b\\012c\\134d"), "a\tb\nc\\d");
+        // Incomplete escapes are kept as they are.
+        assert_eq!(unescape("end\\04"), "end\\04");
+    }
+}
@@ -3,6 +3,7 @@
 
 use crate::block_types::BlockDevice;
 use crate::disk_manager::DiskManager;
+use crate::mounts;
 use crate::ACellOwner;
 use os_release::OsRelease;
 use std::fs;
@@ -28,9 +29,9 @@ pub fn boot_entries(disk_manager: &DiskManager, t: &ACellOwner) -> Vec<OsEntry>
 
     let entry_path = target_mount.join("loader/entries/");
 
-    if target_mount.exists() {
+    if mounts::is_mounted(target_mount) {
         let _ = sys_mount::unmount(&target_mount, sys_mount::UnmountFlags::DETACH);
-    } else {
+    } else if !target_mount.exists() {
         let _ = fs::create_dir(target_mount);
     }
 
@@ -46,6 +47,12 @@ pub fn boot_entries(disk_manager: &DiskManager, t: &ACellOwner) -> Vec<OsEntry>
                 continue;
             }
 
+            // Partitions which are already mounted, such as at `/boot/efi`, are read in place.
+            if let Some(target) = device.mounts.first() {
+                let entries = target.join("loader/entries/");
+                return read_boot_entries(&Path::new(devname), &entries).unwrap_or_default();
+            }
+
             return locate_boot_entries(&Path::new(devname), target_mount, &entry_path);
         }
     }
@@ -56,7 +63,10 @@ pub fn boot_entries(disk_manager: &DiskManager, t: &ACellOwner) -> Vec<OsEntry>
 /// Locate a Linux installation on a partition by its /etc/os-release.
 pub fn linux(partition: &Path) -> Option<LinuxOS> {
     let target_mount = Path::new("/tmp/distinst_os_probe");
-    let _ = sys_mount::unmount(&target_mount, sys_mount::UnmountFlags::DETACH);
+    if mounts::is_mounted(target_mount) {
+        let _ = sys_mount::unmount(&target_mount, sys_mount::UnmountFlags::DETACH);
+    }
+
     scoped_mount(partition, target_mount, move || {
         let release = ward::ward!(OsRelease::new_from(&*target_mount.join("etc/os-release")).ok(), else {
             return None;
@@ -68,38 +78,42 @@ pub fn linux(partition: &Path) -> Option<LinuxOS> {
 
 fn locate_boot_entries(partition: &Path, mount_at: &Path, entry_path: &Path) -> Vec<OsEntry> {
     let result = scoped_mount(partition, mount_at, move || {
-        let dir = match fs::read_dir(entry_path) {
-            Ok(dir) => dir,
-            Err(_) => return None,
-        };
-
-        let entries = dir
-            .filter_map(Result::ok)
-            .filter_map(|entry| {
-                let path = entry.path();
-                // let file_name = ward::ward!(path.file_name().and_then(OsStr::to_str), else {
-                //     return None;
-                // });
-
-                let entry = ward::ward!(fs::read_to_string(&path).ok(), else { return None });
-
-                entry
-                    .lines()
-                    .find_map(|l| l.strip_prefix("options "))
-                    .and_then(|line| {
-                        line.split_ascii_whitespace()
-                            .find_map(|f| f.strip_prefix("root=UUID="))
-                            .map(String::from)
-                    })
-                    .map(|uuid| OsEntry {
-                        path: partition.to_string_lossy().to_owned().to_string(),
-                        uuid,
-                    })
-            })
-            .collect::<Vec<OsEntry>>();
-
-        Some(entries)
+        read_boot_entries(partition, entry_path)
     });
 
     result.ok().flatten().unwrap_or_default()
 }
+
+fn read_boot_entries(partition: &Path, entry_path: &Path) -> Option<Vec<OsEntry>> {
+    let dir = match fs::read_dir(entry_path) {
+        Ok(dir) => dir,
+        Err(_) => return None,
+    };
+
+    let entries = dir
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let path = entry.path();
+            // let file_name = ward::ward!(path.file_name().and_then(OsStr::to_str), else {
+            //     return None;
+            // });
+
+            let entry = ward::ward!(fs::read_to_string(&path).ok(), else { return None });
+
+            entry
+                .lines()
+                .find_map(|l| l.strip_prefix("options "))
+                .and_then(|line| {
+                    line.split_ascii_whitespace()
+                        .find_map(|f| f.strip_prefix("root=UUID="))
+                        .map(String::from)
+                })
+                .map(|uuid| OsEntry {
+                    path: partition.to_string_lossy().to_owned().to_string(),
+                    uuid,
+                })
+        })
+        .collect::<Vec<OsEntry>>();
+
+    Some(entries)
+}
@@ -0,0 +1,583 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Filesystem-aware resizing and moving of partitions.
+
+use crate::block_types::{BlockDevice, PartitionTable};
+use crate::claim::{ClaimError, Holder};
+use crate::command::{self, CommandError};
+use crate::disk_manager::{DiskManager, PartitionError};
+use crate::gpt::GptTable;
+use crate::mbr::MbrTable;
+use crate::source::BlockSource;
+use crate::superblock;
+use crate::ACellOwner;
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use sys_mount::scoped_mount;
+
+#[derive(Debug, Error)]
+pub enum ResizeError {
+    #[error("cannot resize a device which does not exist")]
+    DeviceNotFound,
+    #[error("{0} is not a partition")]
+    NotAPartition(String),
+    #[error("{0} filesystems cannot be shrunk")]
+    ShrinkUnsupported(String),
+    #[error("{0} filesystem cannot be shrunk below {1} bytes")]
+    CannotShrink(String, u64),
+    #[error("LUKS container must be unlocked to resize its contents")]
+    LockedLuks,
+    #[error("sectors {0}..+{1} cannot be addressed by an MBR partition table")]
+    MbrRange(u64, u64),
+    #[error(transparent)]
+    Command(#[from] CommandError),
+    #[error(transparent)]
+    Claim(#[from] ClaimError),
+    #[error("failed to mount {0} for resizing")]
+    Mount(String),
+    #[error("failed to create a temporary mount point")]
+    MountPoint(#[source] io::Error),
+    #[error("failed to move partition data")]
+    Move(#[source] io::Error),
+    #[error(transparent)]
+    Partition(#[from] PartitionError),
+}
+
+/// Whether the contents of a partition with this filesystem can be shrunk.
+pub fn can_shrink(fs: &str) -> bool {
+    matches!(
+        fs,
+        "ext2"
+            | "ext3"
+            | "ext4"
+            | "btrfs"
+            | "vfat"
+            | "ntfs"
+            | "swap"
+            | "crypto_LUKS"
+            | "LVM2_member"
+    )
+}
+
+/// An unlocked LUKS container and the device map inside of it.
+struct Luks {
+    dm_name: String,
+    devname: String,
+    fs: Option<String>,
+    /// Space consumed by the LUKS header.
+    header: u64,
+}
+
+/// Everything about a partition that is needed to resize it.
+struct Target {
+    devname: String,
+    disk: String,
+    sector_size: u64,
+    table: Option<PartitionTable>,
+    number: u32,
+    start: u64,
+    sectors: u64,
+    fs: Option<String>,
+    uuid: Option<String>,
+    luks: Option<Luks>,
+}
+
+impl Target {
+    fn new(dm: &DiskManager, devname: &str, t: &ACellOwner) -> Result<Self, ResizeError> {
+        let part = match dm.blocks.get(devname) {
+            Some(BlockDevice::Partition(part)) => part.ro(t),
+            Some(_) => return Err(ResizeError::NotAPartition(devname.to_owned())),
+            None => return Err(ResizeError::DeviceNotFound),
+        };
+
+        let disk = dm
+            .disk_of_partition(devname, t)
+            .ok_or(ResizeError::DeviceNotFound)?;
+
+        let disk = disk.ro(t);
+        let sector_size = disk.sector_size;
+
+        let fs = part.device.fs.as_ref();
+
+        let luks = if fs.map_or(false, |fs| fs.type_ == "crypto_LUKS") {
+            part.device.children.first().map(|child| {
+                let child = child.ro(t);
+                Luks {
+                    dm_name: child.name.clone(),
+                    devname: child.device.name.clone(),
+                    fs: child.device.fs.as_ref().map(|fs| fs.type_.clone()),
+                    header: part.device.size.saturating_sub(child.device.size) * 512,
+                }
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            devname: devname.to_owned(),
+            disk: disk.device.name.clone(),
+            sector_size,
+            table: disk.table,
+            number: part.number,
+            start: part.start(sector_size),
+            sectors: part.sectors(sector_size),
+            fs: fs.map(|fs| fs.type_.clone()),
+            uuid: fs.map(|fs| fs.uuid.clone()),
+            luks,
+        })
+    }
+}
+
+/// Move and/or resize a partition, resizing the filesystem inside of it to match.
+///
+/// `start` and `sectors` are in logical sectors of the disk. When shrinking, the
+/// filesystem is shrunk before the partition; when growing, the partition is grown
+/// before the filesystem. Partitions are moved while at their smaller size.
+pub fn resize(
+    dm: &mut DiskManager,
+    partition: &str,
+    start: u64,
+    sectors: u64,
+    udev: &mut dyn BlockSource,
+    t: &mut ACellOwner,
+) -> Result<(), ResizeError> {
+    let target = Target::new(dm, partition, t)?;
+
+    if start == target.start && sectors == target.sectors {
+        return Ok(());
+    }
+
+    validate(dm, &target, start, sectors, t)?;
+
+    let ss = target.sector_size;
+    let bytes = sectors * ss;
+
+    if sectors < target.sectors {
+        shrink_contents(&target, bytes)?;
+
+        if start != target.start {
+            move_data(&target.disk, target.start * ss, start * ss, bytes)?;
+        }
+
+        set_table_entry(dm, &target, start, sectors, udev, t)?;
+    } else {
+        if start != target.start {
+            move_data(
+                &target.disk,
+                target.start * ss,
+                start * ss,
+                target.sectors * ss,
+            )?;
+        }
+
+        set_table_entry(dm, &target, start, sectors, udev, t)?;
+        grow_contents(&target, bytes)?;
+    }
+
+    crate::udev::settle();
+    dm.reload(udev, t);
+
+    Ok(())
+}
+
+/// Move and/or resize the table entry of a partition, without regard for its contents.
+///
+/// This is intended for partitions which are about to be reformatted.
+pub fn resize_entry(
+    dm: &mut DiskManager,
+    partition: &str,
+    start: u64,
+    sectors: u64,
+    udev: &mut dyn BlockSource,
+    t: &mut ACellOwner,
+) -> Result<(), ResizeError> {
+    let mut target = Target::new(dm, partition, t)?;
+    target.fs = None;
+
+    validate(dm, &target, start, sectors, t)?;
+    set_table_entry(dm, &target, start, sectors, udev, t)?;
+    dm.reload(udev, t);
+
+    Ok(())
+}
+
+/// Estimate the size in bytes that a filesystem may be shrunk to, if it can be determined.
+///
+/// The estimate from the filesystem's superblock is used, unless the filesystem's own
+/// tooling requires more space.
+pub fn minimum_size(devname: &str, fs: &str) -> Option<u64> {
+    let native = superblock::read(Path::new(devname), Some(fs))
+        .ok()
+        .and_then(|sb| sb.minimum);
+
+    let tool = match fs {
+        "ext2" | "ext3" | "ext4" => (|| {
+            let blocks = run("resize2fs", &["-P", devname]).ok()?;
+            let blocks = field(&blocks, "Estimated minimum size of the filesystem:")?;
+            let header = run("dumpe2fs", &["-h", devname]).ok()?;
+            let block_size = field(&header, "Block size:")?;
+            Some(blocks * block_size)
+        })(),
+        "ntfs" => run(
+            "ntfsresize",
+            &["--info", "--force", "--no-progress-bar", devname],
+        )
+        .ok()
+        .and_then(|info| field(&info, "You might resize at")),
+        _ => None,
+    };
+
+    native.max(tool)
+}
+
+fn shrink_contents(target: &Target, bytes: u64) -> Result<(), ResizeError> {
+    let fs = match target.fs.as_deref() {
+        Some(fs) => fs,
+        None => return Ok(()),
+    };
+
+    match fs {
+        // Recreated once the partition has been resized.
+        "swap" => Ok(()),
+        "crypto_LUKS" => {
+            let luks = target.luks.as_ref().ok_or(ResizeError::LockedLuks)?;
+            let inner = bytes.saturating_sub(luks.header);
+
+            if let Some(fs) = luks.fs.as_deref() {
+                shrink_fs(&luks.devname, fs, inner)?;
+            }
+
+            let sectors = (inner / 512).to_string();
+            run("cryptsetup", &["resize", "--size", &sectors, &luks.dm_name]).map(|_| ())
+        }
+        fs => shrink_fs(&target.devname, fs, bytes),
+    }
+}
+
+fn grow_contents(target: &Target, bytes: u64) -> Result<(), ResizeError> {
+    let fs = match target.fs.as_deref() {
+        Some(fs) => fs,
+        None => return Ok(()),
+    };
+
+    match fs {
+        "swap" => recreate_swap(target),
+        "crypto_LUKS" => {
+            let luks = target.luks.as_ref().ok_or(ResizeError::LockedLuks)?;
+            run("cryptsetup", &["resize", &luks.dm_name])?;
+
+            match luks.fs.as_deref() {
+                Some(fs) => grow_fs(&luks.devname, fs, bytes.saturating_sub(luks.header)),
+                None => Ok(()),
+            }
+        }
+        fs => grow_fs(&target.devname, fs, bytes),
+    }
+}
+
+fn shrink_fs(devname: &str, fs: &str, bytes: u64) -> Result<(), ResizeError> {
+    if !can_shrink(fs) {
+        return Err(ResizeError::ShrinkUnsupported(fs.to_owned()));
+    }
+
+    if let Some(minimum) = minimum_size(devname, fs) {
+        if bytes < minimum {
+            return Err(ResizeError::CannotShrink(fs.to_owned(), minimum));
+        }
+    }
+
+    eprintln!(
+        "shrinking {} filesystem on {} to {} bytes",
+        fs, devname, bytes
+    );
+
+    match fs {
+        "ext2" | "ext3" | "ext4" => {
+            check_ext(devname)?;
+            let size = format!("{}K", bytes / 1024);
+            run("resize2fs", &[devname, &size]).map(|_| ())
+        }
+        "btrfs" => with_mount(devname, |mount| {
+            let size = bytes.to_string();
+            run(
+                "btrfs",
+                &["filesystem", "resize", &size, &mount.to_string_lossy()],
+            )
+            .map(|_| ())
+        }),
+        "vfat" => run("fatresize", &["--size", &bytes.to_string(), devname]).map(|_| ()),
+        "ntfs" => resize_ntfs(devname, bytes),
+        "LVM2_member" => {
+            let size = format!("{}B", bytes);
+            run(
+                "pvresize",
+                &["-y", "--setphysicalvolumesize", &size, devname],
+            )
+            .map(|_| ())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn grow_fs(devname: &str, fs: &str, bytes: u64) -> Result<(), ResizeError> {
+    eprintln!(
+        "growing {} filesystem on {} to {} bytes",
+        fs, devname, bytes
+    );
+
+    match fs {
+        "ext2" | "ext3" | "ext4" => {
+            check_ext(devname)?;
+            run("resize2fs", &[devname]).map(|_| ())
+        }
+        "btrfs" => with_mount(devname, |mount| {
+            run(
+                "btrfs",
+                &["filesystem", "resize", "max", &mount.to_string_lossy()],
+            )
+            .map(|_| ())
+        }),
+        "xfs" => with_mount(devname, |mount| {
+            run("xfs_growfs", &[&mount.to_string_lossy()]).map(|_| ())
+        }),
+        "vfat" => run("fatresize", &["--size", &bytes.to_string(), devname]).map(|_| ()),
+        "ntfs" => resize_ntfs(devname, bytes),
+        "LVM2_member" => run("pvresize", &[devname]).map(|_| ()),
+        // The partition may grow, but the filesystem will not use the space.
+        _ => Ok(()),
+    }
+}
+
+/// Swap has no contents worth keeping, so it is recreated with its original UUID.
+fn recreate_swap(target: &Target) -> Result<(), ResizeError> {
+    let mut args = vec!["-f"];
+
+    if let Some(uuid) = target.uuid.as_deref() {
+        args.extend_from_slice(&["-U", uuid]);
+    }
+
+    args.push(&target.devname);
+    run("mkswap", &args).map(|_| ())
+}
+
+/// e2fsck exits with 1 when it has corrected errors, which is still a success.
+fn check_ext(devname: &str) -> Result<(), ResizeError> {
+    execute("e2fsck", &["-f", "-y", devname], None, |code| code <= 1).map(|_| ())
+}
+
+fn resize_ntfs(devname: &str, bytes: u64) -> Result<(), ResizeError> {
+    let size = bytes.to_string();
+    execute(
+        "ntfsresize",
+        &[
+            "--force",
+            "--force",
+            "--no-progress-bar",
+            "--size",
+            &size,
+            devname,
+        ],
+        Some("y\n"),
+        |code| code == 0,
+    )
+    .map(|_| ())
+}
+
+fn set_table_entry(
+    dm: &mut DiskManager,
+    target: &Target,
+    start: u64,
+    sectors: u64,
+    udev: &mut dyn BlockSource,
+    t: &mut ACellOwner,
+) -> Result<(), ResizeError> {
+    let number = target.number;
+
+    match target.table {
+        Some(PartitionTable::Gpt) => {
+            dm.gpt_edit(&target.disk, udev, t, |table| {
+                table.resize(number, start, sectors)
+            })?;
+        }
+        Some(PartitionTable::Mbr) => {
+            let (start, sectors) = mbr_range(start, sectors)?;
+            dm.mbr_edit(&target.disk, udev, t, |table| {
+                table.resize(number as usize, start, sectors)
+            })?;
+        }
+        None => return Err(ResizeError::NotAPartition(target.devname.clone())),
+    }
+
+    // The filesystem must now be resized through a device of the new size.
+    crate::udev::settle();
+
+    if target.fs.as_deref() == Some("swap") && sectors < target.sectors {
+        recreate_swap(target)?;
+    }
+
+    Ok(())
+}
+
+/// Check that a partition may be moved or resized to its new range, before any of its
+/// data is touched.
+fn validate(
+    dm: &DiskManager,
+    target: &Target,
+    start: u64,
+    sectors: u64,
+    t: &ACellOwner,
+) -> Result<(), ResizeError> {
+    dm.ensure_writable(&target.disk, t)?;
+    ensure_unused(dm, &target.devname, start != target.start, t)?;
+
+    // Apply the change to a copy of the table which is never committed.
+    let disk = Path::new(&target.disk);
+    let number = target.number;
+
+    match target.table {
+        Some(PartitionTable::Gpt) => GptTable::open(disk, target.sector_size)
+            .and_then(|mut table| table.resize(number, start, sectors))
+            .map_err(PartitionError::from)?,
+        Some(PartitionTable::Mbr) => {
+            let (start, sectors) = mbr_range(start, sectors)?;
+            MbrTable::open(disk, target.sector_size)
+                .and_then(|mut table| table.resize(number as usize, start, sectors))
+                .map_err(PartitionError::from)?
+        }
+        None => return Err(ResizeError::NotAPartition(target.devname.clone())),
+    }
+
+    Ok(())
+}
+
+/// Fail with [`ClaimError::Busy`] if a partition is in use, such that it cannot be resized,
+/// or moved if `moving` is set.
+///
+/// Unlocked LUKS volumes and LVM PVs are resized in place with their device maps active,
+/// but nothing may be using the partition while its data is moved.
+pub(crate) fn ensure_unused(
+    dm: &DiskManager,
+    partition: &str,
+    moving: bool,
+    t: &ACellOwner,
+) -> Result<(), ClaimError> {
+    let holders = dm
+        .busy(partition, t)
+        .into_iter()
+        .filter(|holder| {
+            moving || !matches!(holder, Holder::Luks { .. } | Holder::DeviceMap { .. })
+        })
+        .collect::<Vec<_>>();
+
+    if holders.is_empty() {
+        Ok(())
+    } else {
+        Err(ClaimError::Busy(partition.to_owned(), holders))
+    }
+}
+
+/// MBR partition tables address sectors with 32-bit integers.
+fn mbr_range(start: u64, sectors: u64) -> Result<(u32, u32), ResizeError> {
+    match (u32::try_from(start), u32::try_from(sectors)) {
+        (Ok(start), Ok(sectors)) => Ok((start, sectors)),
+        _ => Err(ResizeError::MbrRange(start, sectors)),
+    }
+}
+
+/// Copy `length` bytes within a disk from one offset to another.
+///
+/// Data is copied in the direction that prevents an overlapping source from being
+/// overwritten before it has been read.
+fn move_data(disk: &str, from: u64, to: u64, length: u64) -> Result<(), ResizeError> {
+    const CHUNK: u64 = 4 * 1024 * 1024;
+
+    eprintln!(
+        "moving {} bytes on {} from {} to {}",
+        length, disk, from, to
+    );
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(disk)
+        .map_err(ResizeError::Move)?;
+
+    let mut offsets = (0..length).step_by(CHUNK as usize).collect::<Vec<u64>>();
+    if to > from {
+        offsets.reverse();
+    }
+
+    let mut buffer = vec![0u8; CHUNK as usize];
+
+    let mut copy = |offset: u64| -> io::Result<()> {
+        let buffer = &mut buffer[..CHUNK.min(length - offset) as usize];
+        file.seek(SeekFrom::Start(from + offset))?;
+        file.read_exact(buffer)?;
+        file.seek(SeekFrom::Start(to + offset))?;
+        file.write_all(buffer)
+    };
+
+    for offset in offsets {
+        copy(offset).map_err(ResizeError::Move)?;
+    }
+
+    file.sync_all().map_err(ResizeError::Move)
+}
+
+/// Temporarily mount a filesystem which can only be resized while mounted.
+fn with_mount<T>(
+    devname: &str,
+    func: impl FnOnce(&Path) -> Result<T, ResizeError>,
+) -> Result<T, ResizeError> {
+    let target = mount_point()?;
+
+    let result = scoped_mount(Path::new(devname), &target, || func(&target))
+        .map_err(|_| ResizeError::Mount(devname.to_owned()));
+
+    let _ = fs::remove_dir(&target);
+
+    result?
+}
+
+/// Create an empty directory to mount at, which no other resize will be using.
+fn mount_point() -> Result<PathBuf, ResizeError> {
+    loop {
+        let name = format!(
+            "distinst_resize.{}.{:08x}",
+            std::process::id(),
+            rand::random::<u32>()
+        );
+
+        let path = std::env::temp_dir().join(name);
+
+        match fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            Err(why) if why.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(why) => return Err(ResizeError::MountPoint(why)),
+        }
+    }
+}
+
+/// Parse the number which follows `key` in the output of a command.
+fn field(output: &str, key: &str) -> Option<u64> {
+    output.lines().find_map(|line| {
+        let value = line.trim().strip_prefix(key)?;
+        value.split_whitespace().next()?.parse::<u64>().ok()
+    })
+}
+
+fn run(program: &'static str, args: &[&str]) -> Result<String, ResizeError> {
+    Ok(command::run(program, args)?)
+}
+
+fn execute(
+    program: &'static str,
+    args: &[&str],
+    stdin: Option<&str>,
+    success: fn(i32) -> bool,
+) -> Result<String, ResizeError> {
+    Ok(command::execute(program, args, stdin, success)?)
+}
@@ -0,0 +1,251 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Serializable copies of the block device tree, for DBus clients and tooling.
+//!
+//! DBus has no representation for optional or recursive values, so absent values are
+//! empty strings, and device maps are listed once and referred to by their device name.
+
+use crate::block_types::*;
+use crate::disk_manager::DiskManager;
+use crate::ACellOwner;
+use zvariant::Type;
+
+#[derive(Clone, Debug, Deserialize, Serialize, Type)]
+pub struct DeviceSnapshot {
+    pub name: String,
+    /// Number of 512-byte sectors.
+    pub size: u64,
+    /// Empty if the device does not contain a filesystem.
+    pub fs_type: String,
+    pub fs_uuid: String,
+    pub fs_label: String,
+    /// Symlinks to the device, such as those in `/dev/disk/by-id`.
+    pub links: Vec<String>,
+    /// Where the filesystem on the device is mounted.
+    pub mounts: Vec<String>,
+    pub swap: bool,
+    /// Device names of the device maps on top of this device.
+    pub children: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Type)]
+pub struct PartitionSnapshot {
+    pub device: DeviceSnapshot,
+    /// One of `primary`, `extended`, or `logical`.
+    pub kind: String,
+    pub number: u32,
+    /// Offset in 512-byte sectors.
+    pub offset: u64,
+    pub uuid: String,
+    /// GPT partition name
+    pub label: String,
+    /// GPT partition type GUID, or MBR system ID
+    pub type_: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Type)]
+pub struct DiskSnapshot {
+    pub device: DeviceSnapshot,
+    pub sector_size: u64,
+    pub model: String,
+    pub serial: String,
+    /// One of `gpt` or `mbr`, or empty if the disk has no partition table.
+    pub table: String,
+    pub physical_sector_size: u64,
+    pub minimum_io_size: u64,
+    pub optimal_io_size: u64,
+    pub alignment_offset: u64,
+    pub transport: String,
+    pub rotational: bool,
+    pub removable: bool,
+    pub read_only: bool,
+    pub discard: bool,
+    pub live_media: bool,
+    pub partitions: Vec<PartitionSnapshot>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Type)]
+pub struct DeviceMapSnapshot {
+    pub device: DeviceSnapshot,
+    pub name: String,
+    pub lv_name: String,
+    pub vg_name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Type)]
+pub struct LoopSnapshot {
+    pub device: DeviceSnapshot,
+    pub backing_file: String,
+    pub live_media: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Type)]
+pub struct MdArraySnapshot {
+    /// The array as a disk, with the partitions on it.
+    pub disk: DiskSnapshot,
+    pub level: String,
+    pub uuid: String,
+    /// Device names of the members of the array.
+    pub members: Vec<String>,
+    pub raid_disks: u32,
+    pub degraded: u32,
+    pub state: String,
+}
+
+/// Every disk, partition, device map, loop device, and MD RAID array known to a
+/// [`DiskManager`].
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Type)]
+pub struct Snapshot {
+    pub disks: Vec<DiskSnapshot>,
+    pub device_maps: Vec<DeviceMapSnapshot>,
+    pub loops: Vec<LoopSnapshot>,
+    pub md_arrays: Vec<MdArraySnapshot>,
+}
+
+impl Snapshot {
+    pub fn new(dm: &DiskManager, t: &ACellOwner) -> Self {
+        let mut snapshot = Self::default();
+
+        for block in dm.blocks.values() {
+            match block {
+                BlockDevice::Disk(disk) => {
+                    let disk = disk.ro(t);
+
+                    match disk.md.as_ref() {
+                        Some(md) => snapshot.md_arrays.push(MdArraySnapshot {
+                            disk: self::disk(disk, t),
+                            level: md.level.clone(),
+                            uuid: md.uuid.clone(),
+                            members: md.members.clone(),
+                            raid_disks: md.raid_disks,
+                            degraded: md.degraded,
+                            state: md.state.clone(),
+                        }),
+                        None => snapshot.disks.push(self::disk(disk, t)),
+                    }
+                }
+
+                BlockDevice::DeviceMap(map) => {
+                    let map = map.ro(t);
+
+                    snapshot.device_maps.push(DeviceMapSnapshot {
+                        device: device(&map.device, t),
+                        name: map.name.clone(),
+                        lv_name: map.lv_name.clone().unwrap_or_default(),
+                        vg_name: map.vg_name.clone().unwrap_or_default(),
+                    });
+                }
+
+                BlockDevice::Loop(lo) => {
+                    let lo = lo.ro(t);
+
+                    snapshot.loops.push(LoopSnapshot {
+                        device: device(&lo.device, t),
+                        backing_file: lo.backing_file.display().to_string(),
+                        live_media: lo.live_media,
+                    });
+                }
+
+                BlockDevice::Partition(_) => (),
+            }
+        }
+
+        snapshot
+    }
+
+    /// Serialize the snapshot as pretty-printed JSON.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+fn disk(disk: &Disk, t: &ACellOwner) -> DiskSnapshot {
+    DiskSnapshot {
+        device: device(&disk.device, t),
+        sector_size: disk.sector_size,
+        model: disk.model.clone(),
+        serial: disk.serial.clone(),
+        table: match disk.table {
+            Some(PartitionTable::Gpt) => "gpt",
+            Some(PartitionTable::Mbr) => "mbr",
+            None => "",
+        }
+        .to_owned(),
+        physical_sector_size: disk.topology.physical_sector_size,
+        minimum_io_size: disk.topology.minimum_io_size,
+        optimal_io_size: disk.topology.optimal_io_size,
+        alignment_offset: disk.topology.alignment_offset,
+        transport: transport(disk.transport).to_owned(),
+        rotational: disk.rotational,
+        removable: disk.removable,
+        read_only: disk.read_only,
+        discard: disk.discard,
+        live_media: disk.live_media,
+        partitions: disk
+            .children
+            .iter()
+            .map(|child| partition(child.ro(t), t))
+            .collect(),
+    }
+}
+
+fn device(device: &Device, t: &ACellOwner) -> DeviceSnapshot {
+    let (fs_type, fs_uuid, fs_label) = match device.fs.as_ref() {
+        Some(fs) => (
+            fs.type_.clone(),
+            fs.uuid.clone(),
+            fs.label.clone().unwrap_or_default(),
+        ),
+        None => (String::new(), String::new(), String::new()),
+    };
+
+    DeviceSnapshot {
+        name: device.name.clone(),
+        size: device.size,
+        fs_type,
+        fs_uuid,
+        fs_label,
+        links: device.links.clone(),
+        mounts: device
+            .mounts
+            .iter()
+            .map(|target| target.display().to_string())
+            .collect(),
+        swap: device.swap,
+        children: device
+            .children
+            .iter()
+            .map(|child| child.ro(t).device.name.clone())
+            .collect(),
+    }
+}
+
+fn partition(part: &PartitionEntry, t: &ACellOwner) -> PartitionSnapshot {
+    PartitionSnapshot {
+        device: device(&part.device, t),
+        kind: match part.kind {
+            PartitionKind::Primary => "primary",
+            PartitionKind::Extended => "extended",
+            PartitionKind::Logical => "logical",
+        }
+        .to_owned(),
+        number: part.number,
+        offset: part.offset,
+        uuid: part.uuid.clone(),
+        label: part.label.clone().unwrap_or_default(),
+        type_: part.type_.clone().unwrap_or_default(),
+    }
+}
+
+fn transport(transport: Transport) -> &'static str {
+    match transport {
+        Transport::Nvme => "nvme",
+        Transport::Sata => "sata",
+        Transport::Scsi => "scsi",
+        Transport::Usb => "usb",
+        Transport::Mmc => "mmc",
+        Transport::Virtio => "virtio",
+        Transport::Unknown => "",
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Sources of block device information for [`DiskManager::reload`].
+//!
+//! [`DiskManager::reload`]: crate::DiskManager::reload
+
+use crate::block_types::PartitionTable;
+use crate::lvm::report::Report;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Read access to the udev properties and sysfs attributes of a block device.
+pub trait DeviceInfo {
+    fn property(&self, name: &str) -> Option<&str>;
+
+    fn attribute(&self, name: &str) -> Option<&str>;
+}
+
+/// Enumerates block devices, such as [`UDev`](crate::UDev) for the devices of this system.
+pub trait BlockSource {
+    /// Calls `func` with each block device, and the `DEVNAME` of each of its parents.
+    ///
+    /// Parents must be visited before their children.
+    fn for_each(&mut self, func: &mut dyn FnMut(&dyn DeviceInfo, &[&str]));
+
+    /// The `DEVNAME` of each mounted device, and where it is mounted.
+    ///
+    /// Sources which do not describe the devices of this system have nothing mounted
+    /// unless they say otherwise.
+    fn mounts(&mut self) -> Vec<(String, PathBuf)> {
+        Vec::new()
+    }
+
+    /// The `DEVNAME` of each device which is in use as swap.
+    fn swaps(&mut self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// LVM's view of its PVs, VGs, and LVs, if the source describes them.
+    ///
+    /// When `None`, a reload keeps the report which the disk manager already has.
+    fn lvm_report(&mut self) -> Option<Report> {
+        None
+    }
+}
+
+/// A block device described by a [`MemorySource`].
+#[derive(Clone, Debug, Default)]
+pub struct MemoryDevice {
+    pub properties: BTreeMap<String, String>,
+    pub attributes: BTreeMap<String, String>,
+    /// The `DEVNAME` of each device which this device is on top of.
+    pub parents: Vec<String>,
+    /// Where the filesystem on the device is mounted.
+    pub mounts: Vec<PathBuf>,
+    /// Whether the device is in use as swap.
+    pub swap: bool,
+}
+
+impl MemoryDevice {
+    pub fn set_property(&mut self, name: &str, value: impl ToString) -> &mut Self {
+        self.properties.insert(name.to_owned(), value.to_string());
+        self
+    }
+
+    pub fn set_attribute(&mut self, name: &str, value: impl ToString) -> &mut Self {
+        self.attributes.insert(name.to_owned(), value.to_string());
+        self
+    }
+
+    /// Describes the filesystem on the device.
+    pub fn set_fs(&mut self, type_: &str, uuid: &str) -> &mut Self {
+        self.set_property("ID_FS_TYPE", type_)
+            .set_property("ID_FS_UUID", uuid)
+    }
+
+    /// Mount the filesystem on the device at `target`.
+    pub fn mount(&mut self, target: impl AsRef<Path>) -> &mut Self {
+        self.mounts.push(target.as_ref().to_owned());
+        self
+    }
+
+    /// Use the device as swap.
+    pub fn swapon(&mut self) -> &mut Self {
+        self.swap = true;
+        self
+    }
+}
+
+impl DeviceInfo for MemoryDevice {
+    fn property(&self, name: &str) -> Option<&str> {
+        self.properties.get(name).map(String::as_str)
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(String::as_str)
+    }
+}
+
+/// Block devices which are described in memory, for tests and simulations.
+///
+/// Devices are visited in the order that they were added, so parents must be added first.
+#[derive(Clone, Debug, Default)]
+pub struct MemorySource {
+    pub devices: Vec<MemoryDevice>,
+    /// The LVM report which is given to the disk manager on each reload.
+    pub lvm: Option<Report>,
+    device_maps: u32,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a disk of `bytes` in size, with 512-byte sectors.
+    pub fn add_disk(
+        &mut self,
+        devname: &str,
+        bytes: u64,
+        table: Option<PartitionTable>,
+    ) -> &mut MemoryDevice {
+        let device = self.add(devname, "disk", bytes, &[]);
+
+        device.set_attribute("queue/logical_block_size", 512);
+
+        if let Some(table) = table {
+            let table = match table {
+                PartitionTable::Gpt => "gpt",
+                PartitionTable::Mbr => "dos",
+            };
+
+            device.set_property("ID_PART_TABLE_TYPE", table);
+        }
+
+        device
+    }
+
+    /// Add a loop device of `bytes` in size, which is backed by a file.
+    pub fn add_loop(&mut self, devname: &str, bytes: u64, backing_file: &str) -> &mut MemoryDevice {
+        self.add(devname, "disk", bytes, &[])
+            .set_attribute("loop/backing_file", backing_file)
+    }
+
+    /// Add a partition to a disk, at an `offset` in bytes from the start of the disk.
+    pub fn add_partition(
+        &mut self,
+        disk: &str,
+        number: u32,
+        offset: u64,
+        bytes: u64,
+    ) -> &mut MemoryDevice {
+        let scheme = self
+            .find(disk)
+            .and_then(|disk| disk.properties.get("ID_PART_TABLE_TYPE"))
+            .cloned()
+            .unwrap_or_else(|| "gpt".to_owned());
+
+        let separator = if disk.ends_with(|c: char| c.is_ascii_digit()) {
+            "p"
+        } else {
+            ""
+        };
+
+        let devname = format!("{}{}{}", disk, separator, number);
+
+        self.add(&devname, "partition", bytes, &[disk])
+            .set_attribute("partition", number)
+            .set_property("ID_PART_ENTRY_SCHEME", scheme)
+            .set_property("ID_PART_ENTRY_NUMBER", number)
+            .set_property("ID_PART_ENTRY_OFFSET", offset / 512)
+            .set_property("ID_PART_ENTRY_SIZE", bytes / 512)
+            .set_property(
+                "ID_PART_ENTRY_UUID",
+                format!("00000000-0000-4000-8000-{:012x}", u64::from(number)),
+            )
+    }
+
+    /// Add an unlocked LUKS volume named `name` on top of `parent`.
+    pub fn add_luks(&mut self, parent: &str, name: &str, uuid: &str) -> &mut MemoryDevice {
+        let bytes = self.size_of(parent).saturating_sub(16 * 1024 * 1024);
+
+        if let Some(parent) = self.find_mut(parent) {
+            parent.set_fs("crypto_LUKS", uuid);
+        }
+
+        let uuid = format!("CRYPT-LUKS2-{}-{}", uuid.replace('-', ""), name);
+
+        self.add_device_map(parent, name, bytes)
+            .set_property("DM_UUID", uuid)
+    }
+
+    /// Add a logical volume of `bytes` in size, on the physical volume `pv`.
+    pub fn add_lv(&mut self, pv: &str, vg: &str, lv: &str, bytes: u64) -> &mut MemoryDevice {
+        if let Some(pv) = self.find_mut(pv) {
+            if !pv.properties.contains_key("ID_FS_TYPE") {
+                pv.set_fs("LVM2_member", "");
+            }
+        }
+
+        let name = format!("{}-{}", vg.replace('-', "--"), lv.replace('-', "--"));
+
+        self.add_device_map(pv, &name, bytes)
+            .set_property("DM_VG_NAME", vg)
+            .set_property("DM_LV_NAME", lv)
+    }
+
+    /// Add an assembled MD RAID array of `bytes` in size, built from `members`.
+    pub fn add_md(
+        &mut self,
+        devname: &str,
+        level: &str,
+        uuid: &str,
+        members: &[&str],
+        bytes: u64,
+    ) -> &mut MemoryDevice {
+        for member in members {
+            if let Some(member) = self.find_mut(member) {
+                member.set_fs("linux_raid_member", uuid);
+            }
+        }
+
+        self.add(devname, "disk", bytes, members)
+            .set_attribute("queue/logical_block_size", 512)
+            .set_property("MD_LEVEL", level)
+            .set_property("MD_UUID", uuid)
+            .set_property("MD_DEVICES", members.len())
+            .set_attribute("md/level", level)
+            .set_attribute("md/array_state", "clean")
+            .set_attribute("md/raid_disks", members.len())
+            .set_attribute("md/degraded", 0)
+    }
+
+    fn add_device_map(&mut self, parent: &str, name: &str, bytes: u64) -> &mut MemoryDevice {
+        let devname = format!("/dev/dm-{}", self.device_maps);
+        self.device_maps += 1;
+
+        self.add(&devname, "disk", bytes, &[parent])
+            .set_property("DM_NAME", name)
+    }
+
+    fn add(
+        &mut self,
+        devname: &str,
+        devtype: &str,
+        bytes: u64,
+        parents: &[&str],
+    ) -> &mut MemoryDevice {
+        let mut device = MemoryDevice {
+            parents: parents.iter().map(|&parent| parent.to_owned()).collect(),
+            ..MemoryDevice::default()
+        };
+
+        device
+            .set_property("DEVNAME", devname)
+            .set_property("DEVTYPE", devtype)
+            .set_property("SUBSYSTEM", "block")
+            .set_attribute("size", bytes / 512);
+
+        self.devices.push(device);
+        self.devices.last_mut().expect("device was just added")
+    }
+
+    fn find(&self, devname: &str) -> Option<&MemoryDevice> {
+        self.devices
+            .iter()
+            .find(|device| device.property("DEVNAME") == Some(devname))
+    }
+
+    fn find_mut(&mut self, devname: &str) -> Option<&mut MemoryDevice> {
+        self.devices
+            .iter_mut()
+            .find(|device| device.property("DEVNAME") == Some(devname))
+    }
+
+    fn size_of(&self, devname: &str) -> u64 {
+        self.find(devname)
+            .and_then(|device| device.attribute("size"))
+            .and_then(|size| size.parse::<u64>().ok())
+            .map_or(0, |sectors| sectors * 512)
+    }
+}
+
+impl BlockSource for MemorySource {
+    fn for_each(&mut self, func: &mut dyn FnMut(&dyn DeviceInfo, &[&str])) {
+        for device in &self.devices {
+            let parents = device
+                .parents
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            func(device, &parents);
+        }
+    }
+
+    fn mounts(&mut self) -> Vec<(String, PathBuf)> {
+        let mut mounts = Vec::new();
+
+        for device in &self.devices {
+            if let Some(devname) = device.property("DEVNAME") {
+                let targets = device.mounts.iter().cloned();
+                mounts.extend(targets.map(|target| (devname.to_owned(), target)));
+            }
+        }
+
+        mounts
+    }
+
+    fn swaps(&mut self) -> Vec<String> {
+        self.devices
+            .iter()
+            .filter(|device| device.swap)
+            .filter_map(|device| device.property("DEVNAME"))
+            .map(String::from)
+            .collect()
+    }
+
+    fn lvm_report(&mut self) -> Option<Report> {
+        self.lvm.clone()
+    }
+}
@@ -0,0 +1,631 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Native superblock readers, for inspecting filesystems without mounting them.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+#[derive(Debug, Error)]
+pub enum SuperblockError {
+    #[error("failed to read superblock")]
+    Io(#[from] io::Error),
+    #[error("no supported filesystem was found")]
+    Unrecognized,
+    #[error("{0} superblock is corrupt")]
+    Corrupt(&'static str),
+}
+
+/// Filesystem details which were read directly from its superblock.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Superblock {
+    /// Filesystem type, using the same names as udev's `ID_FS_TYPE`.
+    pub fs: String,
+    pub label: Option<String>,
+    pub block_size: u64,
+    /// Size of the filesystem in bytes.
+    pub total: u64,
+    /// Bytes occupied by data and metadata.
+    pub used: u64,
+    /// An estimate of the smallest size in bytes that the filesystem can be shrunk to.
+    ///
+    /// This is `None` for filesystems which cannot be shrunk.
+    pub minimum: Option<u64>,
+}
+
+/// Read the superblock of the filesystem on a block device.
+///
+/// If the filesystem type is not known, each supported type is probed for.
+pub fn read(path: &Path, fs: Option<&str>) -> Result<Superblock, SuperblockError> {
+    let file = File::open(path)?;
+
+    match fs {
+        Some("ext2") | Some("ext3") | Some("ext4") => ext(&file),
+        Some("btrfs") => btrfs(&file),
+        Some("vfat") => vfat(&file),
+        Some("ntfs") => ntfs(&file),
+        Some("xfs") => xfs(&file),
+        Some("swap") => swap(&file),
+        Some(_) => Err(SuperblockError::Unrecognized),
+        None => {
+            let readers: [fn(&File) -> Result<Superblock, SuperblockError>; 6] =
+                [ext, btrfs, xfs, ntfs, vfat, swap];
+
+            readers
+                .iter()
+                .find_map(|reader| reader(&file).ok())
+                .ok_or(SuperblockError::Unrecognized)
+        }
+    }
+}
+
+fn ext(file: &File) -> Result<Superblock, SuperblockError> {
+    const COMPAT_HAS_JOURNAL: u32 = 0x4;
+    const INCOMPAT_EXTENTS: u32 = 0x40;
+    const INCOMPAT_64BIT: u32 = 0x80;
+    const INCOMPAT_FLEX_BG: u32 = 0x200;
+
+    let sb = read_at(file, 1024, 1024)?;
+
+    if LittleEndian::read_u16(&sb[0x38..]) != 0xEF53 {
+        return Err(SuperblockError::Unrecognized);
+    }
+
+    let log_block_size = LittleEndian::read_u32(&sb[0x18..]);
+    if log_block_size > 6 {
+        return Err(SuperblockError::Corrupt("ext"));
+    }
+
+    let block_size = 1024u64 << log_block_size;
+    let compat = LittleEndian::read_u32(&sb[0x5C..]);
+    let incompat = LittleEndian::read_u32(&sb[0x60..]);
+
+    let count = |lo: usize, hi: usize| {
+        let lo = u64::from(LittleEndian::read_u32(&sb[lo..]));
+        if incompat & INCOMPAT_64BIT != 0 {
+            lo | u64::from(LittleEndian::read_u32(&sb[hi..])) << 32
+        } else {
+            lo
+        }
+    };
+
+    let blocks = count(0x04, 0x150);
+    let free = count(0x0C, 0x158);
+
+    let fs = if incompat & (INCOMPAT_EXTENTS | INCOMPAT_64BIT | INCOMPAT_FLEX_BG) != 0 {
+        "ext4"
+    } else if compat & COMPAT_HAS_JOURNAL != 0 {
+        "ext3"
+    } else {
+        "ext2"
+    };
+
+    let total = blocks * block_size;
+    let used = blocks.saturating_sub(free) * block_size;
+
+    Ok(Superblock {
+        fs: fs.into(),
+        label: string(&sb[0x78..0x88]),
+        block_size,
+        total,
+        used,
+        minimum: Some(with_margin(used, block_size).min(total)),
+    })
+}
+
+fn btrfs(file: &File) -> Result<Superblock, SuperblockError> {
+    let sb = read_at(file, 0x10000, 0x1000)?;
+
+    if &sb[0x40..0x48] != b"_BHRfS_M" {
+        return Err(SuperblockError::Unrecognized);
+    }
+
+    let total = LittleEndian::read_u64(&sb[0x70..]);
+    let used = LittleEndian::read_u64(&sb[0x78..]);
+    let block_size = u64::from(LittleEndian::read_u32(&sb[0x90..]));
+    if block_size == 0 {
+        return Err(SuperblockError::Corrupt("btrfs"));
+    }
+
+    // Space allocated to chunks on this device, from the embedded device item.
+    let allocated = LittleEndian::read_u64(&sb[0xC9 + 0x10..]);
+
+    Ok(Superblock {
+        fs: "btrfs".into(),
+        label: string(&sb[0x12B..0x12B + 256]),
+        block_size,
+        total,
+        used,
+        minimum: Some(with_margin(allocated.max(used), block_size).min(total)),
+    })
+}
+
+fn vfat(file: &File) -> Result<Superblock, SuperblockError> {
+    let bs = read_at(file, 0, 512)?;
+
+    let jump = bs[0] == 0xEB || bs[0] == 0xE9;
+    if !jump || bs[510..512] != [0x55, 0xAA] || &bs[3..11] == b"NTFS    " {
+        return Err(SuperblockError::Unrecognized);
+    }
+
+    let bytes_per_sector = u64::from(LittleEndian::read_u16(&bs[0x0B..]));
+    let sectors_per_cluster = u64::from(bs[0x0D]);
+    let reserved = u64::from(LittleEndian::read_u16(&bs[0x0E..]));
+    let fats = u64::from(bs[0x10]);
+    let root_entries = u64::from(LittleEndian::read_u16(&bs[0x11..]));
+
+    if !bytes_per_sector.is_power_of_two() || sectors_per_cluster == 0 || fats == 0 {
+        return Err(SuperblockError::Unrecognized);
+    }
+
+    let total_sectors = match LittleEndian::read_u16(&bs[0x13..]) {
+        0 => u64::from(LittleEndian::read_u32(&bs[0x20..])),
+        sectors => u64::from(sectors),
+    };
+
+    let fat_sectors = match LittleEndian::read_u16(&bs[0x16..]) {
+        0 => u64::from(LittleEndian::read_u32(&bs[0x24..])),
+        sectors => u64::from(sectors),
+    };
+
+    let root_sectors = (root_entries * 32 + bytes_per_sector - 1) / bytes_per_sector;
+    let metadata = reserved + fats * fat_sectors + root_sectors;
+
+    let clusters = total_sectors
+        .checked_sub(metadata)
+        .ok_or(SuperblockError::Corrupt("vfat"))?
+        / sectors_per_cluster;
+
+    let fat32 = clusters >= 65525;
+
+    let (signature, label) = if fat32 { (0x42, 0x47) } else { (0x26, 0x2B) };
+    let label = if bs[signature] == 0x29 {
+        string(&bs[label..label + 11]).filter(|label| label != "NO NAME")
+    } else {
+        None
+    };
+
+    let free = match fat32_free_clusters(file, &bs, bytes_per_sector, clusters) {
+        Some(free) => free,
+        None => {
+            let fat = read_at(
+                file,
+                reserved * bytes_per_sector,
+                fat_sectors * bytes_per_sector,
+            )?;
+            (2..clusters + 2)
+                .filter(|&cluster| fat_entry(&fat, cluster, clusters) == Some(0))
+                .count() as u64
+        }
+    };
+
+    let cluster_size = sectors_per_cluster * bytes_per_sector;
+    let total = total_sectors * bytes_per_sector;
+    let used = metadata * bytes_per_sector + clusters.saturating_sub(free) * cluster_size;
+
+    Ok(Superblock {
+        fs: "vfat".into(),
+        label,
+        block_size: cluster_size,
+        total,
+        used,
+        minimum: Some(with_margin(used, cluster_size).min(total)),
+    })
+}
+
+/// The free cluster count cached in the FAT32 FSInfo sector, if it is valid.
+fn fat32_free_clusters(
+    file: &File,
+    bs: &[u8],
+    bytes_per_sector: u64,
+    clusters: u64,
+) -> Option<u64> {
+    if clusters < 65525 {
+        return None;
+    }
+
+    let sector = u64::from(LittleEndian::read_u16(&bs[0x30..]));
+    let info = read_at(file, sector * bytes_per_sector, 512).ok()?;
+
+    let valid = LittleEndian::read_u32(&info[0..]) == 0x4161_5252
+        && LittleEndian::read_u32(&info[0x1E4..]) == 0x6141_7272;
+
+    let free = u64::from(LittleEndian::read_u32(&info[0x1E8..]));
+
+    if valid && free <= clusters {
+        Some(free)
+    } else {
+        None
+    }
+}
+
+/// Read an entry from a FAT12, FAT16, or FAT32 allocation table.
+fn fat_entry(fat: &[u8], cluster: u64, clusters: u64) -> Option<u32> {
+    let cluster = cluster as usize;
+
+    if clusters < 4085 {
+        let offset = cluster + cluster / 2;
+        let pair = u32::from(LittleEndian::read_u16(fat.get(offset..offset + 2)?));
+        Some(if cluster % 2 == 0 {
+            pair & 0xFFF
+        } else {
+            pair >> 4
+        })
+    } else if clusters < 65525 {
+        let offset = cluster * 2;
+        Some(u32::from(LittleEndian::read_u16(
+            fat.get(offset..offset + 2)?,
+        )))
+    } else {
+        let offset = cluster * 4;
+        Some(LittleEndian::read_u32(fat.get(offset..offset + 4)?) & 0x0FFF_FFFF)
+    }
+}
+
+fn ntfs(file: &File) -> Result<Superblock, SuperblockError> {
+    const MFT_VOLUME: u64 = 3;
+    const MFT_BITMAP: u64 = 6;
+    const ATTR_VOLUME_NAME: u32 = 0x60;
+    const ATTR_DATA: u32 = 0x80;
+
+    let bs = read_at(file, 0, 512)?;
+
+    if &bs[3..11] != b"NTFS    " {
+        return Err(SuperblockError::Unrecognized);
+    }
+
+    let bytes_per_sector = u64::from(LittleEndian::read_u16(&bs[0x0B..]));
+    let sectors_per_cluster = match bs[0x0D] {
+        raw if raw > 0x80 => 1u64 << (256 - u32::from(raw)),
+        raw => u64::from(raw),
+    };
+
+    if bytes_per_sector == 0 || sectors_per_cluster == 0 {
+        return Err(SuperblockError::Corrupt("ntfs"));
+    }
+
+    let cluster_size = bytes_per_sector * sectors_per_cluster;
+    let total_sectors = LittleEndian::read_u64(&bs[0x28..]);
+    let clusters = total_sectors / sectors_per_cluster;
+    let mft = LittleEndian::read_u64(&bs[0x30..]) * cluster_size;
+
+    let record_size = match bs[0x40] as i8 {
+        raw if raw < 0 => 1u64 << -i32::from(raw),
+        raw => raw as u64 * cluster_size,
+    };
+
+    let record = |number: u64| -> Option<Vec<u8>> {
+        let mut record = read_at(file, mft + number * record_size, record_size).ok()?;
+        ntfs_fixup(&mut record, bytes_per_sector as usize)?;
+        Some(record)
+    };
+
+    let label = record(MFT_VOLUME)
+        .as_deref()
+        .and_then(|record| ntfs_attribute(record, ATTR_VOLUME_NAME))
+        .and_then(ntfs_resident_value)
+        .map(|name| {
+            let name = name
+                .chunks_exact(2)
+                .map(LittleEndian::read_u16)
+                .collect::<Vec<u16>>();
+            String::from_utf16_lossy(&name)
+        })
+        .filter(|name| !name.is_empty());
+
+    // Used clusters are counted from the $Bitmap system file.
+    let bitmap = record(MFT_BITMAP).ok_or(SuperblockError::Corrupt("ntfs"))?;
+    let data = ntfs_attribute(&bitmap, ATTR_DATA).ok_or(SuperblockError::Corrupt("ntfs"))?;
+    let runs = ntfs_data_runs(data).ok_or(SuperblockError::Corrupt("ntfs"))?;
+
+    let mut remaining = (clusters + 7) / 8;
+    let mut used_clusters = 0u64;
+    let mut bit = 0u64;
+
+    for (lcn, length) in runs {
+        if remaining == 0 {
+            break;
+        }
+
+        let bytes = (length * cluster_size).min(remaining);
+        let chunk = read_at(file, lcn * cluster_size, bytes)?;
+        remaining -= bytes;
+
+        for byte in chunk {
+            for shift in 0..8 {
+                if bit < clusters && byte & (1 << shift) != 0 {
+                    used_clusters += 1;
+                }
+
+                bit += 1;
+            }
+        }
+    }
+
+    let total = total_sectors * bytes_per_sector;
+    let used = used_clusters * cluster_size;
+
+    Ok(Superblock {
+        fs: "ntfs".into(),
+        label,
+        block_size: cluster_size,
+        total,
+        used,
+        minimum: Some(with_margin(used, cluster_size).min(total)),
+    })
+}
+
+/// Restore the bytes which the update sequence array replaced at the end of each sector.
+fn ntfs_fixup(record: &mut [u8], sector_size: usize) -> Option<()> {
+    if record.get(0..4)? != b"FILE" {
+        return None;
+    }
+
+    let usa_offset = usize::from(LittleEndian::read_u16(&record[4..]));
+    let usa_count = usize::from(LittleEndian::read_u16(&record[6..]));
+
+    for sector in 1..usa_count {
+        let end = sector * sector_size - 2;
+        let usa = usa_offset + sector * 2;
+        let value = [*record.get(usa)?, *record.get(usa + 1)?];
+        record.get_mut(end..end + 2)?.copy_from_slice(&value);
+    }
+
+    Some(())
+}
+
+/// Locate an attribute of the given type in an MFT record.
+fn ntfs_attribute(record: &[u8], kind: u32) -> Option<&[u8]> {
+    let mut offset = usize::from(LittleEndian::read_u16(record.get(0x14..0x16)?));
+
+    while let Some(header) = record.get(offset..offset + 8) {
+        let type_ = LittleEndian::read_u32(header);
+        let length = LittleEndian::read_u32(&header[4..]) as usize;
+
+        if type_ == 0xFFFF_FFFF || length == 0 {
+            break;
+        }
+
+        let attribute = record.get(offset..offset + length)?;
+
+        if type_ == kind {
+            return Some(attribute);
+        }
+
+        offset += length;
+    }
+
+    None
+}
+
+fn ntfs_resident_value(attribute: &[u8]) -> Option<&[u8]> {
+    if *attribute.get(8)? != 0 {
+        return None;
+    }
+
+    let length = LittleEndian::read_u32(attribute.get(0x10..0x14)?) as usize;
+    let offset = usize::from(LittleEndian::read_u16(attribute.get(0x14..0x16)?));
+    attribute.get(offset..offset + length)
+}
+
+/// Decode the data runs of a non-resident attribute into (LCN, length) pairs.
+fn ntfs_data_runs(attribute: &[u8]) -> Option<Vec<(u64, u64)>> {
+    if *attribute.get(8)? == 0 {
+        return None;
+    }
+
+    let mut offset = usize::from(LittleEndian::read_u16(attribute.get(0x20..0x22)?));
+    let mut lcn = 0i64;
+    let mut runs = Vec::new();
+
+    loop {
+        let header = *attribute.get(offset)?;
+        if header == 0 {
+            break;
+        }
+
+        let length_size = usize::from(header & 0x0F);
+        let offset_size = usize::from(header >> 4);
+        offset += 1;
+
+        if length_size == 0 || length_size > 8 || offset_size > 8 {
+            return None;
+        }
+
+        let length =
+            LittleEndian::read_uint(attribute.get(offset..offset + length_size)?, length_size);
+        offset += length_size;
+
+        // Sparse runs have no offset, and occupy no clusters.
+        if offset_size == 0 {
+            continue;
+        }
+
+        lcn += LittleEndian::read_int(attribute.get(offset..offset + offset_size)?, offset_size);
+        offset += offset_size;
+
+        runs.push((lcn as u64, length));
+    }
+
+    Some(runs)
+}
+
+fn xfs(file: &File) -> Result<Superblock, SuperblockError> {
+    let sb = read_at(file, 0, 512)?;
+
+    if &sb[0..4] != b"XFSB" {
+        return Err(SuperblockError::Unrecognized);
+    }
+
+    let block_size = u64::from(BigEndian::read_u32(&sb[4..]));
+    if block_size == 0 {
+        return Err(SuperblockError::Corrupt("xfs"));
+    }
+    let blocks = BigEndian::read_u64(&sb[8..]);
+    let free = BigEndian::read_u64(&sb[144..]);
+
+    Ok(Superblock {
+        fs: "xfs".into(),
+        label: string(&sb[108..120]),
+        block_size,
+        total: blocks * block_size,
+        used: blocks.saturating_sub(free) * block_size,
+        minimum: None,
+    })
+}
+
+fn swap(file: &File) -> Result<Superblock, SuperblockError> {
+    for &page_size in &[4096u64, 8192, 16384, 65536] {
+        let signature = match read_at(file, page_size - 10, 10) {
+            Ok(signature) => signature,
+            Err(_) => break,
+        };
+
+        if signature != b"SWAPSPACE2" && signature != b"SWAP-SPACE" {
+            continue;
+        }
+
+        let header = read_at(file, 1024, 64)?;
+        let last_page = u64::from(LittleEndian::read_u32(&header[4..]));
+
+        return Ok(Superblock {
+            fs: "swap".into(),
+            label: string(&header[28..44]),
+            block_size: page_size,
+            total: (last_page + 1) * page_size,
+            used: 0,
+            // Swap is recreated rather than shrunk, and mkswap requires ten pages.
+            minimum: Some(10 * page_size),
+        });
+    }
+
+    Err(SuperblockError::Unrecognized)
+}
+
+fn read_at(file: &File, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0; length as usize];
+    file.read_exact_at(&mut buffer, offset)?;
+    Ok(buffer)
+}
+
+/// Convert a NUL or space padded label into a string.
+fn string(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let string = String::from_utf8_lossy(&bytes[..end]).trim_end().to_owned();
+
+    if string.is_empty() {
+        None
+    } else {
+        Some(string)
+    }
+}
+
+/// Add headroom to used space when estimating how far a filesystem can be shrunk.
+///
+/// `block_size` must not be zero, which the parsers reject as corrupt.
+fn with_margin(used: u64, block_size: u64) -> u64 {
+    let size = used.saturating_add(used / 10);
+    let blocks = size / block_size + u64::from(size % block_size != 0);
+    blocks.saturating_mul(block_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Write an image to a temporary file, which is removed when dropped.
+    struct Image(PathBuf);
+
+    impl Image {
+        fn new(name: &str, data: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "distinst-superblock-{}-{}",
+                std::process::id(),
+                name
+            ));
+
+            fs::write(&path, data).unwrap();
+            Image(path)
+        }
+    }
+
+    impl Drop for Image {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn ext4(log_block_size: u32, blocks: u32, free: u32) -> Vec<u8> {
+        let mut image = vec![0u8; 4096];
+        let sb = &mut image[1024..2048];
+
+        LittleEndian::write_u32(&mut sb[0x04..], blocks);
+        LittleEndian::write_u32(&mut sb[0x0C..], free);
+        LittleEndian::write_u32(&mut sb[0x18..], log_block_size);
+        LittleEndian::write_u16(&mut sb[0x38..], 0xEF53);
+        LittleEndian::write_u32(&mut sb[0x60..], 0x40);
+        sb[0x78..0x7E].copy_from_slice(b"rootfs");
+
+        image
+    }
+
+    #[test]
+    fn ext4_superblock() {
+        let image = Image::new("ext4", &ext4(2, 1000, 400));
+        let sb = read(&image.0, None).unwrap();
+
+        assert_eq!(sb.fs, "ext4");
+        assert_eq!(sb.label.as_deref(), Some("rootfs"));
+        assert_eq!(sb.block_size, 4096);
+        assert_eq!(sb.total, 1000 * 4096);
+        assert_eq!(sb.used, 600 * 4096);
+        assert_eq!(sb.minimum, Some(660 * 4096));
+    }
+
+    #[test]
+    fn ext_block_size_out_of_range() {
+        let image = Image::new("ext-corrupt", &ext4(7, 1000, 400));
+
+        assert!(matches!(
+            read(&image.0, Some("ext4")),
+            Err(SuperblockError::Corrupt("ext"))
+        ));
+    }
+
+    #[test]
+    fn xfs_zero_block_size() {
+        let mut image = vec![0u8; 512];
+        image[0..4].copy_from_slice(b"XFSB");
+        BigEndian::write_u64(&mut image[8..], 1000);
+
+        let image = Image::new("xfs-corrupt", &image);
+
+        assert!(matches!(
+            read(&image.0, Some("xfs")),
+            Err(SuperblockError::Corrupt("xfs"))
+        ));
+    }
+
+    #[test]
+    fn unrecognized() {
+        let image = Image::new("zeroes", &[0u8; 128 * 1024]);
+
+        assert!(matches!(
+            read(&image.0, None),
+            Err(SuperblockError::Unrecognized)
+        ));
+    }
+
+    #[test]
+    fn margin() {
+        assert_eq!(with_margin(0, 4096), 0);
+        assert_eq!(with_margin(1000, 4096), 4096);
+        assert_eq!(with_margin(40960, 4096), 45056);
+        assert_eq!(with_margin(u64::MAX, 4096), u64::MAX);
+    }
+}
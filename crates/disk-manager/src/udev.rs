@@ -3,12 +3,44 @@
 
 use crate::block_types::*;
 use crate::disk_manager::DiskManager;
+use crate::lvm::report::Report;
+use crate::source::{BlockSource, DeviceInfo};
 use crate::{ACell, ACellOwner};
 use libudev::Device as UDevice;
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+/// Sysfs attributes which are read from block devices.
+pub const ATTRIBUTES: &[&str] = &[
+    "size",
+    "partition",
+    "ro",
+    "removable",
+    "alignment_offset",
+    "queue/logical_block_size",
+    "queue/physical_block_size",
+    "queue/minimum_io_size",
+    "queue/optimal_io_size",
+    "queue/rotational",
+    "queue/discard_max_bytes",
+    "loop/backing_file",
+    "md/level",
+    "md/array_state",
+    "md/raid_disks",
+    "md/degraded",
+];
+
+impl DeviceInfo for UDevice {
+    fn property(&self, name: &str) -> Option<&str> {
+        self.property_value(name).and_then(OsStr::to_str)
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attribute_value(name).and_then(OsStr::to_str)
+    }
+}
+
 pub struct UDev {
     pub context: libudev::Context,
     pub enumerator: libudev::Enumerator,
@@ -17,176 +49,335 @@ pub struct UDev {
 impl UDev {
     /// Appends relevant information about this device to the disk manager.
     pub fn append(&self, dm: &mut DiskManager, device: &UDevice, t: &mut ACellOwner) {
-        match device.devtype().and_then(OsStr::to_str) {
-            Some("disk") => match property(device, "DM_NAME") {
-                Some(dm_name) => self.append_dm(dm, device, dm_name.to_owned(), t),
-                None => self.append_disk(dm, device, t),
-            },
-            Some("partition") => self.append_partition(dm, device, t),
-            _ => (),
+        let parents = self.parents(device);
+        let parents = parents.iter().map(String::as_str).collect::<Vec<_>>();
+        append(dm, device, &parents, t);
+    }
+
+    /// The `DEVNAME` of each parent of a device.
+    fn parents(&self, device: &UDevice) -> Vec<String> {
+        let syspath = ward::ward!(device.syspath(), else { return Vec::new() });
+
+        parents(syspath)
+            .filter_map(
+                |parent| match UDevice::from_syspath(&self.context, &parent) {
+                    Ok(dev) => dev.property("DEVNAME").map(String::from),
+                    Err(why) => {
+                        eprintln!("{:?}: libudev device without syspath: {}", parent, why);
+                        None
+                    }
+                },
+            )
+            .collect()
+    }
+}
+
+impl BlockSource for UDev {
+    fn for_each(&mut self, func: &mut dyn FnMut(&dyn DeviceInfo, &[&str])) {
+        let devices = match self.enumerator.scan_devices() {
+            Ok(devices) => devices.collect::<Vec<_>>(),
+            Err(why) => {
+                eprintln!("failed to scan block devices: {}", why);
+                return;
+            }
+        };
+
+        for device in devices {
+            let parents = self.parents(&device);
+            let parents = parents.iter().map(String::as_str).collect::<Vec<_>>();
+            func(&device, &parents);
         }
     }
 
-    /// Append a device which we have determined to be a physical disk.
-    fn append_disk(&self, dm: &mut DiskManager, device: &UDevice, _t: &mut ACellOwner) {
-        let dev = ward::ward!(disk_manager_device(device), else { return });
+    fn mounts(&mut self) -> Vec<(String, PathBuf)> {
+        crate::mounts::mountinfo()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|mount| mount.source.starts_with('/'))
+            .map(|mount| (crate::mounts::canonical_source(&mount.source), mount.target))
+            .collect()
+    }
 
-        if dev.name.contains("loop") {
-            return;
+    fn swaps(&mut self) -> Vec<String> {
+        crate::mounts::swaps()
+            .unwrap_or_default()
+            .iter()
+            .map(|swap| crate::mounts::canonical_source(&swap.source))
+            .collect()
+    }
+
+    fn lvm_report(&mut self) -> Option<Report> {
+        match crate::lvm::report::report() {
+            Ok(report) => Some(report),
+            Err(why) => {
+                eprintln!("failed to query LVM: {}", why);
+                None
+            }
         }
+    }
+}
+
+/// Appends a device to the disk manager, given the `DEVNAME` of each of its parents.
+pub(crate) fn append<D: DeviceInfo + ?Sized>(
+    dm: &mut DiskManager,
+    device: &D,
+    parents: &[&str],
+    t: &mut ACellOwner,
+) {
+    match device.property("DEVTYPE") {
+        Some("disk") => match device.property("DM_NAME") {
+            Some(dm_name) => append_dm(dm, device, dm_name.to_owned(), parents, t),
+            None if is_loop(device) => append_loop(dm, device, t),
+            None if is_md(device) => append_md(dm, device, parents, t),
+            None => append_disk(dm, device, t),
+        },
+        Some("partition") => append_partition(dm, device, parents.first().copied(), t),
+        _ => (),
+    }
+}
 
-        let table = property(device, "ID_PART_TABLE_TYPE").and_then(|table| match table {
+/// Append a device which we have determined to be a physical disk.
+fn append_disk<D: DeviceInfo + ?Sized>(dm: &mut DiskManager, device: &D, _t: &mut ACellOwner) {
+    let dev = ward::ward!(disk_manager_device(device), else { return });
+
+    let table = device
+        .property("ID_PART_TABLE_TYPE")
+        .and_then(|table| match table {
             "gpt" => Some(PartitionTable::Gpt),
-            "mbr" => Some(PartitionTable::Mbr),
+            "dos" | "mbr" => Some(PartitionTable::Mbr),
             _ => None,
         });
 
-        let sector_size = match device
-            .attribute_value("queue/logical_block_size")
-            .and_then(OsStr::to_str)
-        {
-            Some(size) => match size.parse::<u64>() {
-                Ok(size) => size,
-                Err(_) => {
-                    eprintln!(
-                        "{}: does not contain a valid sector size: {}",
-                        dev.name, size
-                    );
-                    return;
-                }
-            },
-            None => {
-                eprintln!("{}: does not contain a sector size", dev.name);
+    let sector_size = match device.attribute("queue/logical_block_size") {
+        Some(size) => match size.trim().parse::<u64>() {
+            Ok(size) => size,
+            Err(_) => {
+                eprintln!(
+                    "{}: does not contain a valid sector size: {}",
+                    dev.name, size
+                );
                 return;
             }
-        };
+        },
+        None => {
+            eprintln!("{}: does not contain a sector size", dev.name);
+            return;
+        }
+    };
 
-        dm.blocks.insert(
-            dev.name.clone(),
-            BlockDevice::Disk(Arc::new(ACell::new(Disk {
-                device: dev,
-                table,
-                sector_size,
-                model: property(device, "ID_MODEL").unwrap_or_default().to_owned(),
-                serial: property(device, "ID_SERIAL").unwrap_or_default().to_owned(),
-                children: Vec::new(),
-            }))),
-        );
-    }
+    let topology = Topology {
+        physical_sector_size: attribute(device, "queue/physical_block_size").unwrap_or(sector_size),
+        minimum_io_size: attribute(device, "queue/minimum_io_size").unwrap_or(sector_size),
+        optimal_io_size: attribute(device, "queue/optimal_io_size").unwrap_or_default(),
+        alignment_offset: attribute(device, "alignment_offset").unwrap_or_default(),
+    };
 
-    /// Append a device which we have determined to be a physical partition.
-    fn append_partition(&self, dm: &mut DiskManager, dev: &UDevice, t: &mut ACellOwner) {
-        let device = ward::ward!(disk_manager_device(dev), else {
-            eprintln!("partition without device information");
-            return;
-        });
+    let flag = |name| attribute::<u64, _>(device, name).map_or(false, |value| value != 0);
+
+    dm.blocks.insert(
+        dev.name.clone(),
+        BlockDevice::Disk(Arc::new(ACell::new(Disk {
+            table,
+            sector_size,
+            model: device.property("ID_MODEL").unwrap_or_default().to_owned(),
+            serial: device.property("ID_SERIAL").unwrap_or_default().to_owned(),
+            topology,
+            transport: transport(device, &dev.name),
+            rotational: flag("queue/rotational"),
+            removable: flag("removable"),
+            read_only: flag("ro"),
+            discard: flag("queue/discard_max_bytes"),
+            live_media: false,
+            md: None,
+            device: dev,
+            children: Vec::new(),
+        }))),
+    );
+}
 
-        let offset = ward::ward!(property(dev, "ID_PART_ENTRY_OFFSET"), else {
-            eprintln!("{}: lacks ID_PART_ENTRY_OFFSET", device.name);
-            return;
-        });
+/// Append a device which we have determined to be a loop device.
+fn append_loop<D: DeviceInfo + ?Sized>(dm: &mut DiskManager, device: &D, _t: &mut ACellOwner) {
+    let dev = ward::ward!(disk_manager_device(device), else { return });
+
+    // Loop devices without a backing file are not in use.
+    let backing_file = ward::ward!(device.attribute("loop/backing_file"), else { return });
+
+    dm.blocks.insert(
+        dev.name.clone(),
+        BlockDevice::Loop(Arc::new(ACell::new(LoopDevice {
+            backing_file: PathBuf::from(backing_file.trim()),
+            live_media: false,
+            device: dev,
+        }))),
+    );
+}
 
-        let uuid = ward::ward!(property(dev, "ID_PART_ENTRY_UUID"), else {
-            eprintln!("{}: lacks ID_PART_ENTRY_UUID", device.name);
-            return;
-        });
+/// Append a device which we have determined to be an MD RAID array.
+///
+/// The array is a disk which is on top of its members, which are linked by the disk
+/// manager once every device is known.
+fn append_md<D: DeviceInfo + ?Sized>(
+    dm: &mut DiskManager,
+    device: &D,
+    parents: &[&str],
+    t: &mut ACellOwner,
+) {
+    append_disk(dm, device, t);
+
+    let devname = ward::ward!(device.property("DEVNAME"), else { return });
+
+    let disk = match dm.blocks.get(devname) {
+        Some(BlockDevice::Disk(disk)) => disk,
+        _ => return,
+    };
 
-        let syspath = ward::ward!(dev.syspath(), else {
-            eprintln!("{}: partition device without syspath", device.name);
-            return;
-        });
+    let level = device
+        .attribute("md/level")
+        .or_else(|| device.property("MD_LEVEL"))
+        .unwrap_or_default()
+        .trim()
+        .to_owned();
+
+    let md = MdArray {
+        level,
+        uuid: device.property("MD_UUID").unwrap_or_default().to_owned(),
+        members: parents.iter().map(|&parent| parent.to_owned()).collect(),
+        raid_disks: attribute(device, "md/raid_disks").unwrap_or_default(),
+        degraded: attribute(device, "md/degraded").unwrap_or_default(),
+        state: device
+            .attribute("md/array_state")
+            .unwrap_or_default()
+            .trim()
+            .to_owned(),
+    };
 
-        let parent = ward::ward!(parents(syspath).next(), else {
-            eprintln!("{}: partition lacks parent", device.name);
-            return;
-        });
+    let disk = disk.rw(t);
+    disk.device.parents = md.members.clone();
+    disk.md = Some(md);
+}
 
-        let parent_dev = match UDevice::from_syspath(&self.context, &parent) {
-            Ok(dev) => dev,
-            Err(why) => {
-                eprintln!("{:?}: libudev device without syspath: {}", parent, why);
-                return;
-            }
-        };
+/// Append a device which we have determined to be a physical partition.
+fn append_partition<D: DeviceInfo + ?Sized>(
+    dm: &mut DiskManager,
+    dev: &D,
+    parent: Option<&str>,
+    t: &mut ACellOwner,
+) {
+    let mut device = ward::ward!(disk_manager_device(dev), else {
+        eprintln!("partition without device information");
+        return;
+    });
 
-        let parent_devname = ward::ward!(property(&parent_dev, "DEVNAME"), else {
-            eprintln!("{:?}: libudev device without DEVNAME", parent);
-            return;
-        });
+    let offset = ward::ward!(dev.property("ID_PART_ENTRY_OFFSET"), else {
+        eprintln!("{}: lacks ID_PART_ENTRY_OFFSET", device.name);
+        return;
+    });
 
-        let devname = device.name.clone();
+    let uuid = ward::ward!(dev.property("ID_PART_ENTRY_UUID"), else {
+        eprintln!("{}: lacks ID_PART_ENTRY_UUID", device.name);
+        return;
+    });
 
-        let partition = Arc::new(ACell::new(PartitionEntry {
-            offset: offset.parse::<u64>().unwrap_or_default(),
-            uuid: uuid.to_owned(),
-            device,
-        }));
+    let parent_devname = ward::ward!(parent, else {
+        eprintln!("{}: partition lacks parent", device.name);
+        return;
+    });
 
-        dm.blocks
-            .insert(devname, BlockDevice::Partition(partition.clone()));
+    device.parents = vec![parent_devname.to_owned()];
 
-        let parent_block = ward::ward!(dm.blocks.get_mut(parent_devname), else {
-            eprintln!("{:?}: not found in disk manager", parent);
-            return;
-        });
+    let number = dev
+        .property("ID_PART_ENTRY_NUMBER")
+        .or_else(|| dev.attribute("partition"))
+        .and_then(|number| number.trim().parse::<u32>().ok())
+        .unwrap_or_default();
 
-        match parent_block {
-            BlockDevice::Disk(disk) => {
-                disk.rw(t).children.push(partition);
-            }
-            _ => {
-                eprintln!("parent is not a disk");
-            }
+    let kind = partition_kind(dev, number);
+
+    // The kernel reports extended partitions as being two sectors in size.
+    if kind == PartitionKind::Extended {
+        if let Some(size) = dev.property("ID_PART_ENTRY_SIZE") {
+            device.size = size.parse::<u64>().unwrap_or(device.size);
         }
     }
 
-    /// Append a device which we have determined to be a device map.
-    fn append_dm(&self, dm: &mut DiskManager, dev: &UDevice, dm_name: String, t: &mut ACellOwner) {
-        let device = ward::ward!(disk_manager_device(dev), else {
-            eprintln!("partition without device information");
-            return;
-        });
+    let devname = device.name.clone();
 
-        let syspath = ward::ward!(dev.syspath(), else {
-            eprintln!("{}: partition device without syspath", device.name);
-            return;
-        });
+    let partition = Arc::new(ACell::new(PartitionEntry {
+        kind,
+        number,
+        offset: offset.parse::<u64>().unwrap_or_default(),
+        uuid: uuid.to_owned(),
+        label: dev.property("ID_PART_ENTRY_NAME").map(String::from),
+        type_: dev.property("ID_PART_ENTRY_TYPE").map(String::from),
+        device,
+    }));
 
-        let parent = ward::ward!(parents(syspath).next(), else {
-            eprintln!("{}: partition lacks parent", device.name);
-            return;
-        });
+    dm.blocks
+        .insert(devname, BlockDevice::Partition(partition.clone()));
 
-        let parent_dev = match UDevice::from_syspath(&self.context, &parent) {
-            Ok(dev) => dev,
-            Err(why) => {
-                eprintln!("{:?}: libudev device without syspath: {}", parent, why);
-                return;
-            }
-        };
+    let parent_block = ward::ward!(dm.blocks.get_mut(parent_devname), else {
+        eprintln!("{}: not found in disk manager", parent_devname);
+        return;
+    });
 
-        let parent_devname = ward::ward!(property(&parent_dev, "DEVNAME"), else {
-            eprintln!("{:?}: libudev device without DEVNAME", parent);
-            return;
-        });
+    match parent_block {
+        BlockDevice::Disk(disk) => {
+            // Devices are enumerated by name, which would place `sda10` before `sda5`.
+            let children = &disk.ro(t).children;
+            let index = children
+                .iter()
+                .position(|child| child.ro(t).number > number)
+                .unwrap_or(children.len());
+
+            disk.rw(t).children.insert(index, partition);
+        }
+        _ => {
+            eprintln!("parent is not a disk");
+        }
+    }
+}
+
+/// Append a device which we have determined to be a device map.
+fn append_dm<D: DeviceInfo + ?Sized>(
+    dm: &mut DiskManager,
+    dev: &D,
+    dm_name: String,
+    parents: &[&str],
+    t: &mut ACellOwner,
+) {
+    let mut device = ward::ward!(disk_manager_device(dev), else {
+        eprintln!("partition without device information");
+        return;
+    });
+
+    if parents.is_empty() {
+        eprintln!("{}: device map lacks parent", device.name);
+        return;
+    }
 
-        let lv_name = property(dev, "DM_LV_NAME");
+    device.parents = parents.iter().map(|&parent| parent.to_owned()).collect();
 
-        let vg_name = property(dev, "DM_VG_NAME");
+    let lv_name = dev.property("DM_LV_NAME");
 
-        let devname = device.name.clone();
+    let vg_name = dev.property("DM_VG_NAME");
 
-        let device_map = Arc::new(ACell::new(DeviceMap {
-            device,
-            lv_name: lv_name.map(String::from),
-            name: dm_name,
-            vg_name: vg_name.map(String::from),
-        }));
+    let devname = device.name.clone();
 
-        dm.blocks
-            .insert(devname.clone(), BlockDevice::DeviceMap(device_map.clone()));
+    let device_map = Arc::new(ACell::new(DeviceMap {
+        device,
+        lv: None,
+        lv_name: lv_name.map(String::from),
+        name: dm_name,
+        vg_name: vg_name.map(String::from),
+    }));
 
-        match dm.blocks.get_mut(parent_devname) {
+    dm.blocks
+        .insert(devname.clone(), BlockDevice::DeviceMap(device_map.clone()));
+
+    for &parent in parents {
+        let device_map = device_map.clone();
+
+        match dm.blocks.get_mut(parent) {
             Some(BlockDevice::DeviceMap(dm)) => {
                 dm.rw(t).device.children.push(device_map);
             }
@@ -194,50 +385,122 @@ impl UDev {
                 part.rw(t).device.children.push(device_map);
             }
             Some(BlockDevice::Disk(disk)) => disk.rw(t).device.children.push(device_map),
+            Some(BlockDevice::Loop(lo)) => lo.rw(t).device.children.push(device_map),
             None => {
-                eprintln!("{}: could not find parent block", devname)
+                eprintln!("{}: could not find parent block {}", devname, parent)
             }
         }
     }
 }
 
-/// Automatically convert `UDevice` properties to Rust strings.
-fn property<'a>(device: &'a UDevice, property: &str) -> Option<&'a str> {
-    device.property_value(property).and_then(OsStr::to_str)
+/// Wait for udev to finish processing queued events, such as those from a partition table change.
+pub fn settle() {
+    if let Err(why) = crate::command::run("udevadm", &["settle"]) {
+        eprintln!("udevadm settle failed: {}", why);
+    }
+}
+
+/// Parse a sysfs attribute of a device.
+fn attribute<T: std::str::FromStr, D: DeviceInfo + ?Sized>(
+    device: &D,
+    attribute: &str,
+) -> Option<T> {
+    device
+        .attribute(attribute)
+        .and_then(|value| value.trim().parse::<T>().ok())
+}
+
+/// Whether a device is a loop device, such as `/dev/loop0`.
+fn is_loop<D: DeviceInfo + ?Sized>(device: &D) -> bool {
+    device
+        .property("DEVNAME")
+        .map_or(false, |name| name.starts_with("/dev/loop"))
+}
+
+/// Whether a device is an MD RAID array, such as `/dev/md0`.
+fn is_md<D: DeviceInfo + ?Sized>(device: &D) -> bool {
+    device.property("MD_LEVEL").is_some()
+        || device
+            .property("DEVNAME")
+            .map_or(false, |name| name.starts_with("/dev/md"))
+}
+
+/// Determine which bus a disk is attached by.
+fn transport<D: DeviceInfo + ?Sized>(device: &D, devname: &str) -> Transport {
+    // USB mass storage is also reported as SCSI, so the path is checked first.
+    if let Some(path) = device.property("ID_PATH") {
+        if path.contains("-usb-") {
+            return Transport::Usb;
+        } else if path.contains("-nvme-") {
+            return Transport::Nvme;
+        } else if path.contains("-ata-") {
+            return Transport::Sata;
+        } else if path.starts_with("virtio") {
+            return Transport::Virtio;
+        }
+    }
+
+    let name = devname.trim_start_matches("/dev/");
+
+    match device.property("ID_BUS") {
+        _ if name.starts_with("nvme") => Transport::Nvme,
+        _ if name.starts_with("mmcblk") => Transport::Mmc,
+        _ if name.starts_with("vd") => Transport::Virtio,
+        Some("usb") => Transport::Usb,
+        Some("ata") => Transport::Sata,
+        Some("scsi") => Transport::Scsi,
+        _ => Transport::Unknown,
+    }
 }
 
-/// Get device-specific information from a `UDevice`.
-fn disk_manager_device(device: &UDevice) -> Option<Device> {
-    let name = device.property_value("DEVNAME")?;
-    let size = device.attribute_value("size")?;
+/// Determine if a partition is primary, or an MBR extended or logical partition.
+fn partition_kind<D: DeviceInfo + ?Sized>(device: &D, number: u32) -> PartitionKind {
+    if device.property("ID_PART_ENTRY_SCHEME") != Some("dos") {
+        return PartitionKind::Primary;
+    }
+
+    if number > 4 {
+        return PartitionKind::Logical;
+    }
+
+    let sys = device
+        .property("ID_PART_ENTRY_TYPE")
+        .and_then(|type_| u8::from_str_radix(type_.trim_start_matches("0x"), 16).ok());
+
+    match sys {
+        Some(sys) if crate::mbr::is_extended(sys) => PartitionKind::Extended,
+        _ => PartitionKind::Primary,
+    }
+}
 
-    let name = name.to_str()?.to_owned();
-    let size = size.to_str()?.parse::<u64>().ok()?;
+/// Get device-specific information from a device.
+fn disk_manager_device<D: DeviceInfo + ?Sized>(device: &D) -> Option<Device> {
+    let name = device.property("DEVNAME")?.to_owned();
+    let size = device.attribute("size")?.trim().parse::<u64>().ok()?;
     let fs = disk_manager_filesystem(device);
 
     Some(Device {
         name,
         size,
         fs,
+        links: device
+            .property("DEVLINKS")
+            .map(|links| links.split_whitespace().map(String::from).collect())
+            .unwrap_or_default(),
+        mounts: Vec::new(),
+        swap: false,
+        pv: None,
+        parents: Vec::new(),
         children: Vec::new(),
     })
 }
 
-/// Get filesystem-specific information from a `UDevice`.
-fn disk_manager_filesystem(device: &UDevice) -> Option<FileSystem> {
-    if let Some(type_) = device.property_value("ID_FS_TYPE") {
-        let uuid = device.property_value("ID_FS_UUID").and_then(OsStr::to_str);
-
-        if let Some((type_, uuid)) = type_.to_str().zip(uuid) {
-            let type_ = type_.to_owned();
-            let uuid = uuid.to_owned();
-            Some(FileSystem { type_, uuid })
-        } else {
-            None
-        }
-    } else {
-        None
-    }
+/// Get filesystem-specific information from a device.
+fn disk_manager_filesystem<D: DeviceInfo + ?Sized>(device: &D) -> Option<FileSystem> {
+    let type_ = device.property("ID_FS_TYPE")?.to_owned();
+    let uuid = device.property("ID_FS_UUID")?.to_owned();
+    let label = device.property("ID_FS_LABEL").map(String::from);
+    Some(FileSystem { type_, uuid, label })
 }
 
 /// Locate the parents of the given device.
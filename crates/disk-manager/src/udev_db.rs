@@ -0,0 +1,815 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: LGPL-3.0-only
+
+//! Block devices from a `udevadm info --export-db` dump, for reproducing disk layouts.
+//!
+//! A dump from `udevadm` has no sysfs attributes, so they are inferred from the properties
+//! that udev exports: sizes from `ID_PART_ENTRY_OFFSET` and `ID_PART_ENTRY_SIZE`, and the
+//! devices that device maps and MD arrays are on from their `DM_*` and `MD_*` properties.
+//! Partitions are on the device which contains them in the `P:` devpath hierarchy.
+//!
+//! Dumps created with [`export`] are extended with `A:` lines, which record the sysfs
+//! attributes that are read from each device, and the kernel names of its `slaves`.
+
+use crate::source::{BlockSource, DeviceInfo};
+use crate::udev::{UDev, ATTRIBUTES};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A device record from the udev database.
+#[derive(Clone, Debug, Default)]
+pub struct DbDevice {
+    /// Path of the device in sysfs, without the `/sys` prefix.
+    pub devpath: String,
+    pub properties: BTreeMap<String, String>,
+    pub attributes: BTreeMap<String, String>,
+    /// Kernel names of the devices which this device is stacked on.
+    pub slaves: Vec<String>,
+}
+
+impl DeviceInfo for DbDevice {
+    fn property(&self, name: &str) -> Option<&str> {
+        self.properties.get(name).map(String::as_str)
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(String::as_str)
+    }
+}
+
+/// Block devices parsed from a udev database dump.
+#[derive(Clone, Debug, Default)]
+pub struct UDevDb {
+    pub devices: Vec<DbDevice>,
+}
+
+impl UDevDb {
+    /// Read a dump from a file.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        fs::read_to_string(path).map(|text| Self::parse(&text))
+    }
+
+    /// Parse the text of a dump. Devices outside of the block subsystem are ignored.
+    pub fn parse(text: &str) -> Self {
+        let mut devices = Vec::new();
+        let mut device = DbDevice::default();
+
+        for line in text.lines().chain(std::iter::once("")) {
+            let line = line.trim_end();
+
+            if line.is_empty() {
+                let device = std::mem::take(&mut device);
+                if device.property("SUBSYSTEM") == Some("block") {
+                    devices.push(device);
+                }
+
+                continue;
+            }
+
+            let (prefix, value) = match line.split_once(": ") {
+                Some(pair) => pair,
+                None => continue,
+            };
+
+            match prefix {
+                "P" => device.devpath = value.to_owned(),
+                "E" => {
+                    if let Some((key, value)) = value.split_once('=') {
+                        device.properties.insert(key.to_owned(), value.to_owned());
+                    }
+                }
+                "A" => match value.split_once('=') {
+                    Some(("slaves", slaves)) => {
+                        device.slaves = slaves.split_whitespace().map(String::from).collect();
+                    }
+                    Some((key, value)) => {
+                        device.attributes.insert(key.to_owned(), value.to_owned());
+                    }
+                    None => (),
+                },
+                _ => (),
+            }
+        }
+
+        let mut db = Self { devices };
+        db.infer_attributes();
+        db
+    }
+
+    /// The `DEVNAME` of the first parent of a device.
+    fn parent(&self, device: &DbDevice) -> Option<&str> {
+        self.parents(device).first().copied()
+    }
+
+    /// The `DEVNAME` of each device which a device is on top of.
+    ///
+    /// Like sysfs, slaves take precedence over the device which contains this device.
+    fn parents(&self, device: &DbDevice) -> Vec<&str> {
+        let by_devname = |devname: &str| {
+            self.devices
+                .iter()
+                .filter_map(|parent| parent.property("DEVNAME"))
+                .find(|name| *name == devname)
+        };
+
+        let mut parents = device
+            .slaves
+            .iter()
+            .filter_map(|name| {
+                let suffix = ["/", name].concat();
+                self.devices
+                    .iter()
+                    .find(|parent| parent.devpath.ends_with(&suffix))
+                    .and_then(|parent| parent.property("DEVNAME"))
+            })
+            .collect::<Vec<_>>();
+
+        if parents.is_empty() {
+            parents = self.stacked_on(device);
+        }
+
+        if parents.is_empty() {
+            // MD arrays name their members in `MD_DEVICE_{name}_DEV` properties.
+            parents = device
+                .properties
+                .iter()
+                .filter(|(key, _)| key.starts_with("MD_DEVICE_") && key.ends_with("_DEV"))
+                .filter_map(|(_, devname)| by_devname(devname))
+                .collect();
+        }
+
+        if parents.is_empty() {
+            let container = device
+                .devpath
+                .rsplit_once('/')
+                .and_then(|(dir, _)| self.devices.iter().find(|parent| parent.devpath == dir));
+
+            parents.extend(container.and_then(|parent| parent.property("DEVNAME")));
+        }
+
+        parents
+    }
+
+    /// The devices which a device map is on, from its `DM_*` properties.
+    fn stacked_on(&self, device: &DbDevice) -> Vec<&str> {
+        let with_fs = move |type_: &'static str| {
+            self.devices
+                .iter()
+                .filter(move |parent| parent.property("ID_FS_TYPE") == Some(type_))
+        };
+
+        // LUKS maps are `CRYPT-{type}-{uuid without dashes}-{name}`.
+        if let Some(uuid) = device.property("DM_UUID").and_then(crypt_uuid) {
+            return with_fs("crypto_LUKS")
+                .filter(|parent| {
+                    parent.property("ID_FS_UUID").map_or(false, |fs_uuid| {
+                        fs_uuid.replace('-', "").eq_ignore_ascii_case(uuid)
+                    })
+                })
+                .filter_map(|parent| parent.property("DEVNAME"))
+                .collect();
+        }
+
+        if let Some(vg) = device.property("DM_VG_NAME") {
+            let pvs = with_fs("LVM2_member").collect::<Vec<_>>();
+
+            // PVs only name their VG with newer LVM udev rules. Without them, the
+            // LVs of a system with a single PV are on that PV.
+            let members = with_fs("LVM2_member")
+                .filter(|pv| pv.property("LVM_VG_NAME_COMPLETE") == Some(vg))
+                .filter_map(|pv| pv.property("DEVNAME"))
+                .collect::<Vec<_>>();
+
+            return match pvs.as_slice() {
+                [pv] if members.is_empty() => pv.property("DEVNAME").into_iter().collect(),
+                _ => members,
+            };
+        }
+
+        Vec::new()
+    }
+
+    /// Fills in the sysfs attributes which a dump from `udevadm` lacks.
+    ///
+    /// Partitions are sized by their partition table entry, and disks are sized to hold
+    /// their last partition. Device maps and MD arrays take the size of the first device
+    /// that they are on, or zero if it is unknown.
+    fn infer_attributes(&mut self) {
+        for device in &mut self.devices {
+            let properties = &device.properties;
+            let attributes = &mut device.attributes;
+
+            if let Some(size) = properties.get("ID_PART_ENTRY_SIZE") {
+                attributes
+                    .entry("size".into())
+                    .or_insert_with(|| size.clone());
+            }
+
+            if let Some(raid_disks) = properties.get("MD_DEVICES") {
+                attributes
+                    .entry("md/raid_disks".into())
+                    .or_insert_with(|| raid_disks.clone());
+            }
+
+            // Partition offsets and sizes from udev are in 512-byte sectors.
+            if properties.get("DEVTYPE").map(String::as_str) == Some("disk") {
+                attributes
+                    .entry("queue/logical_block_size".into())
+                    .or_insert_with(|| "512".into());
+            }
+        }
+
+        for index in 0..self.devices.len() {
+            if self.devices[index].attributes.contains_key("size") {
+                continue;
+            }
+
+            let devpath = &self.devices[index].devpath;
+
+            let end = self
+                .devices
+                .iter()
+                .filter(|child| {
+                    child
+                        .devpath
+                        .rsplit_once('/')
+                        .map_or(false, |(dir, _)| dir == devpath)
+                })
+                .filter_map(|child| {
+                    let offset = child
+                        .property("ID_PART_ENTRY_OFFSET")?
+                        .parse::<u64>()
+                        .ok()?;
+                    let size = child.attribute("size")?.parse::<u64>().ok()?;
+                    offset.checked_add(size)
+                })
+                .max();
+
+            if let Some(end) = end {
+                self.devices[index]
+                    .attributes
+                    .insert("size".into(), end.to_string());
+            }
+        }
+
+        // Stacked devices are sized after the devices that they are on.
+        loop {
+            let inferred = self
+                .devices
+                .iter()
+                .enumerate()
+                .filter(|(_, device)| !device.attributes.contains_key("size"))
+                .find_map(|(index, device)| {
+                    let parent = self.parent(device)?;
+                    let parent = self
+                        .devices
+                        .iter()
+                        .find(|dev| dev.property("DEVNAME") == Some(parent))?;
+                    Some((index, parent.attribute("size")?.to_owned()))
+                });
+
+            match inferred {
+                Some((index, size)) => {
+                    self.devices[index].attributes.insert("size".into(), size);
+                }
+                None => break,
+            }
+        }
+
+        for device in &mut self.devices {
+            device
+                .attributes
+                .entry("size".into())
+                .or_insert_with(|| "0".into());
+        }
+    }
+}
+
+/// The LUKS UUID, without dashes, from the `DM_UUID` of a LUKS device map.
+fn crypt_uuid(dm_uuid: &str) -> Option<&str> {
+    let rest = dm_uuid.strip_prefix("CRYPT-")?;
+    let (_type, rest) = rest.split_once('-')?;
+    let (uuid, _name) = rest.split_once('-')?;
+    Some(uuid)
+}
+
+impl BlockSource for UDevDb {
+    /// Visits every device, with parents before their children.
+    fn for_each(&mut self, func: &mut dyn FnMut(&dyn DeviceInfo, &[&str])) {
+        let mut visited = BTreeSet::new();
+        let mut pending = self.devices.iter().collect::<Vec<_>>();
+
+        while !pending.is_empty() {
+            let remaining = pending.len();
+
+            pending.retain(|device| {
+                let parents = self.parents(device);
+
+                if parents.iter().all(|parent| visited.contains(parent)) {
+                    func(*device, &parents);
+                    visited.extend(device.property("DEVNAME"));
+                    false
+                } else {
+                    true
+                }
+            });
+
+            // Devices whose parents will never appear are visited as orphans.
+            if pending.len() == remaining {
+                for device in pending.drain(..) {
+                    func(device, &self.parents(device));
+                }
+            }
+        }
+    }
+}
+
+/// Dump the block devices of this system in the extended `udevadm info --export-db` format.
+pub fn export(udev: &mut UDev) -> io::Result<String> {
+    let mut dump = String::new();
+
+    for device in udev.enumerator.scan_devices()? {
+        let syspath = match device.syspath() {
+            Some(syspath) => syspath.to_owned(),
+            None => continue,
+        };
+
+        let devpath = syspath.strip_prefix("/sys").unwrap_or(&syspath);
+
+        let _ = writeln!(dump, "P: {}", devpath.display());
+
+        if let Some(name) = device.property("DEVNAME") {
+            let _ = writeln!(dump, "N: {}", name.trim_start_matches("/dev/"));
+        }
+
+        for property in device.properties() {
+            let _ = writeln!(
+                dump,
+                "E: {}={}",
+                property.name().to_string_lossy(),
+                property.value().to_string_lossy()
+            );
+        }
+
+        for &attribute in ATTRIBUTES {
+            if let Some(value) = device.attribute(attribute) {
+                let _ = writeln!(dump, "A: {}={}", attribute, value.trim());
+            }
+        }
+
+        if let Ok(slaves) = syspath.join("slaves").read_dir() {
+            let slaves = slaves
+                .filter_map(Result::ok)
+                .map(|slave| slave.file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+
+            if !slaves.is_empty() {
+                let _ = writeln!(dump, "A: slaves={}", slaves.join(" "));
+            }
+        }
+
+        dump.push('\n');
+    }
+
+    Ok(dump)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Devices are listed children first, to check that they are visited parents first.
+    const DB: &str = "\
+P: /devices/virtual/block/dm-0
+N: dm-0
+E: DEVNAME=/dev/dm-0
+E: DEVTYPE=disk
+E: SUBSYSTEM=block
+E: DM_NAME=cryptdata
+A: size=67092480
+A: slaves=sda2
+
+P: /devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda/sda2
+N: sda2
+E: DEVNAME=/dev/sda2
+E: DEVTYPE=partition
+E: SUBSYSTEM=block
+E: ID_FS_TYPE=crypto_LUKS
+A: size=67094528
+A: partition=2
+
+P: /devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda
+N: sda
+E: DEVNAME=/dev/sda
+E: DEVTYPE=disk
+E: SUBSYSTEM=block
+E: ID_MODEL=Disk Model=With Equals
+A: size=134217728
+A: queue/logical_block_size=512
+
+P: /devices/pci0000:00/0000:00:1f.3/sound/card0
+E: SUBSYSTEM=sound
+";
+
+    #[test]
+    fn parse_records() {
+        let db = UDevDb::parse(DB);
+
+        // The sound card is not a block device.
+        assert_eq!(db.devices.len(), 3);
+
+        let dm = &db.devices[0];
+        assert_eq!(dm.devpath, "/devices/virtual/block/dm-0");
+        assert_eq!(dm.property("DM_NAME"), Some("cryptdata"));
+        assert_eq!(dm.attribute("size"), Some("67092480"));
+        assert_eq!(dm.slaves, ["sda2"]);
+        assert_eq!(dm.attribute("slaves"), None);
+
+        let disk = &db.devices[2];
+        assert_eq!(disk.property("ID_MODEL"), Some("Disk Model=With Equals"));
+        assert_eq!(disk.attribute("queue/logical_block_size"), Some("512"));
+    }
+
+    #[test]
+    fn parents_first() {
+        let mut db = UDevDb::parse(DB);
+        let mut visited = Vec::new();
+
+        db.for_each(&mut |device, parents| {
+            let devname = device.property("DEVNAME").unwrap_or_default();
+            let parent = parents.first().map(|&parent| parent.to_owned());
+            visited.push((devname.to_owned(), parent));
+        });
+
+        let expected = [
+            ("/dev/sda", None),
+            ("/dev/sda2", Some("/dev/sda")),
+            ("/dev/dm-0", Some("/dev/sda2")),
+        ];
+
+        let expected = expected
+            .iter()
+            .map(|(devname, parent)| (devname.to_string(), parent.map(String::from)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(visited, expected);
+    }
+
+    #[test]
+    fn orphans() {
+        let mut db = UDevDb::parse(
+            "P: /devices/virtual/block/dm-1\nE: DEVNAME=/dev/dm-1\nE: SUBSYSTEM=block\nA: slaves=sdz1\n",
+        );
+
+        let mut visited = Vec::new();
+        db.for_each(&mut |device, parents| {
+            visited.push((
+                device.property("DEVNAME").map(String::from),
+                !parents.is_empty(),
+            ));
+        });
+
+        assert_eq!(visited, [(Some("/dev/dm-1".to_owned()), false)]);
+    }
+
+    // A Pop!_OS install with LVM on LUKS, as `udevadm info --export-db` dumps it.
+    const EXPORT_DB: &str = "\
+P: /devices/pci0000:00/0000:00:1d.0/0000:3b:00.0/nvme/nvme0/nvme0n1
+M: nvme0n1
+R: 1
+U: block
+T: disk
+D: b 259:0
+N: nvme0n1
+L: 0
+S: disk/by-path/pci-0000:3b:00.0-nvme-1
+S: disk/by-id/nvme-Samsung_SSD_970_EVO_Plus_500GB_S4EVNF0M914231K
+Q: 1
+V: 1
+E: DEVPATH=/devices/pci0000:00/0000:00:1d.0/0000:3b:00.0/nvme/nvme0/nvme0n1
+E: DEVNAME=/dev/nvme0n1
+E: DEVTYPE=disk
+E: DISKSEQ=1
+E: MAJOR=259
+E: MINOR=0
+E: SUBSYSTEM=block
+E: USEC_INITIALIZED=1863772
+E: ID_SERIAL_SHORT=S4EVNF0M914231K
+E: ID_MODEL=Samsung SSD 970 EVO Plus 500GB
+E: ID_REVISION=2B2QEXM7
+E: ID_SERIAL=Samsung SSD 970 EVO Plus 500GB_S4EVNF0M914231K
+E: ID_PATH=pci-0000:3b:00.0-nvme-1
+E: ID_PATH_TAG=pci-0000_3b_00_0-nvme-1
+E: ID_PART_TABLE_UUID=5b0c8a4e-8f3c-4d52-9a8e-0b6d7e2f1c3a
+E: ID_PART_TABLE_TYPE=gpt
+E: DEVLINKS=/dev/disk/by-path/pci-0000:3b:00.0-nvme-1 /dev/disk/by-id/nvme-Samsung_SSD_970_EVO_Plus_500GB_S4EVNF0M914231K
+E: TAGS=:systemd:
+E: CURRENT_TAGS=:systemd:
+
+P: /devices/pci0000:00/0000:00:1d.0/0000:3b:00.0/nvme/nvme0/nvme0n1/nvme0n1p1
+M: nvme0n1p1
+R: 1
+U: block
+T: partition
+D: b 259:1
+N: nvme0n1p1
+L: 0
+S: disk/by-partuuid/1e5f4d2c-7b0a-4c38-8f61-2a9d3e4b5c6d
+S: disk/by-uuid/A1B2-C3D4
+Q: 2
+V: 1
+E: DEVPATH=/devices/pci0000:00/0000:00:1d.0/0000:3b:00.0/nvme/nvme0/nvme0n1/nvme0n1p1
+E: DEVNAME=/dev/nvme0n1p1
+E: DEVTYPE=partition
+E: DISKSEQ=1
+E: PARTN=1
+E: MAJOR=259
+E: MINOR=1
+E: SUBSYSTEM=block
+E: USEC_INITIALIZED=1871034
+E: ID_PART_TABLE_UUID=5b0c8a4e-8f3c-4d52-9a8e-0b6d7e2f1c3a
+E: ID_PART_TABLE_TYPE=gpt
+E: ID_FS_UUID=A1B2-C3D4
+E: ID_FS_UUID_ENC=A1B2-C3D4
+E: ID_FS_VERSION=FAT32
+E: ID_FS_TYPE=vfat
+E: ID_FS_USAGE=filesystem
+E: ID_PART_ENTRY_SCHEME=gpt
+E: ID_PART_ENTRY_UUID=1e5f4d2c-7b0a-4c38-8f61-2a9d3e4b5c6d
+E: ID_PART_ENTRY_TYPE=c12a7328-f81f-11d2-ba4b-00a0c93ec93b
+E: ID_PART_ENTRY_NUMBER=1
+E: ID_PART_ENTRY_OFFSET=4096
+E: ID_PART_ENTRY_SIZE=2097152
+E: ID_PART_ENTRY_DISK=259:0
+E: DEVLINKS=/dev/disk/by-partuuid/1e5f4d2c-7b0a-4c38-8f61-2a9d3e4b5c6d /dev/disk/by-uuid/A1B2-C3D4
+E: TAGS=:systemd:
+E: CURRENT_TAGS=:systemd:
+
+P: /devices/pci0000:00/0000:00:1d.0/0000:3b:00.0/nvme/nvme0/nvme0n1/nvme0n1p3
+M: nvme0n1p3
+R: 3
+U: block
+T: partition
+D: b 259:3
+N: nvme0n1p3
+L: 0
+S: disk/by-partuuid/7c3e9a1b-2d4f-4e6a-b8c0-9f1e2d3c4b5a
+S: disk/by-uuid/8e4b0a5c-3f1d-4c2a-9b7e-1d2c3b4a5f60
+Q: 4
+V: 1
+E: DEVPATH=/devices/pci0000:00/0000:00:1d.0/0000:3b:00.0/nvme/nvme0/nvme0n1/nvme0n1p3
+E: DEVNAME=/dev/nvme0n1p3
+E: DEVTYPE=partition
+E: DISKSEQ=1
+E: PARTN=3
+E: MAJOR=259
+E: MINOR=3
+E: SUBSYSTEM=block
+E: USEC_INITIALIZED=1870877
+E: ID_PART_TABLE_UUID=5b0c8a4e-8f3c-4d52-9a8e-0b6d7e2f1c3a
+E: ID_PART_TABLE_TYPE=gpt
+E: ID_FS_VERSION=2
+E: ID_FS_UUID=8e4b0a5c-3f1d-4c2a-9b7e-1d2c3b4a5f60
+E: ID_FS_UUID_ENC=8e4b0a5c-3f1d-4c2a-9b7e-1d2c3b4a5f60
+E: ID_FS_TYPE=crypto_LUKS
+E: ID_FS_USAGE=crypto
+E: ID_PART_ENTRY_SCHEME=gpt
+E: ID_PART_ENTRY_UUID=7c3e9a1b-2d4f-4e6a-b8c0-9f1e2d3c4b5a
+E: ID_PART_ENTRY_TYPE=0fc63daf-8483-4772-8e79-3d69d8477de4
+E: ID_PART_ENTRY_NUMBER=3
+E: ID_PART_ENTRY_OFFSET=10489856
+E: ID_PART_ENTRY_SIZE=957894656
+E: ID_PART_ENTRY_DISK=259:0
+E: DEVLINKS=/dev/disk/by-partuuid/7c3e9a1b-2d4f-4e6a-b8c0-9f1e2d3c4b5a /dev/disk/by-uuid/8e4b0a5c-3f1d-4c2a-9b7e-1d2c3b4a5f60
+E: TAGS=:systemd:
+E: CURRENT_TAGS=:systemd:
+
+P: /devices/virtual/block/dm-0
+M: dm-0
+R: 0
+U: block
+T: disk
+D: b 253:0
+N: dm-0
+L: 0
+S: disk/by-id/dm-name-cryptdata
+S: disk/by-id/dm-uuid-CRYPT-LUKS2-8e4b0a5c3f1d4c2a9b7e1d2c3b4a5f60-cryptdata
+S: mapper/cryptdata
+S: disk/by-id/lvm-pv-uuid-Xq3v2L-9tRk-Hf7p-Wc1N-yZ8b-Ud4M-Ke6s0A
+Q: 7
+V: 1
+E: DEVPATH=/devices/virtual/block/dm-0
+E: DEVNAME=/dev/dm-0
+E: DEVTYPE=disk
+E: DISKSEQ=7
+E: MAJOR=253
+E: MINOR=0
+E: SUBSYSTEM=block
+E: USEC_INITIALIZED=8107264
+E: DM_UDEV_DISABLE_LIBRARY_FALLBACK_FLAG=1
+E: DM_UDEV_PRIMARY_SOURCE_FLAG=1
+E: DM_UDEV_RULES=1
+E: DM_UDEV_RULES_VSN=2
+E: DM_ACTIVATION=1
+E: DM_NAME=cryptdata
+E: DM_UUID=CRYPT-LUKS2-8e4b0a5c3f1d4c2a9b7e1d2c3b4a5f60-cryptdata
+E: DM_SUSPENDED=0
+E: ID_FS_UUID=Xq3v2L-9tRk-Hf7p-Wc1N-yZ8b-Ud4M-Ke6s0A
+E: ID_FS_UUID_ENC=Xq3v2L-9tRk-Hf7p-Wc1N-yZ8b-Ud4M-Ke6s0A
+E: ID_FS_VERSION=LVM2 001
+E: ID_FS_TYPE=LVM2_member
+E: ID_FS_USAGE=raid
+E: SYSTEMD_READY=1
+E: DEVLINKS=/dev/disk/by-id/dm-name-cryptdata /dev/disk/by-id/dm-uuid-CRYPT-LUKS2-8e4b0a5c3f1d4c2a9b7e1d2c3b4a5f60-cryptdata /dev/mapper/cryptdata /dev/disk/by-id/lvm-pv-uuid-Xq3v2L-9tRk-Hf7p-Wc1N-yZ8b-Ud4M-Ke6s0A
+E: TAGS=:systemd:
+E: CURRENT_TAGS=:systemd:
+
+P: /devices/virtual/block/dm-1
+M: dm-1
+R: 1
+U: block
+T: disk
+D: b 253:1
+N: dm-1
+L: 0
+S: mapper/data-root
+S: data/root
+S: disk/by-uuid/3f9d2a6e-4b1c-4e8f-a7d5-6c2b1e0f9a8d
+S: disk/by-id/dm-name-data-root
+Q: 8
+V: 1
+E: DEVPATH=/devices/virtual/block/dm-1
+E: DEVNAME=/dev/dm-1
+E: DEVTYPE=disk
+E: DISKSEQ=8
+E: MAJOR=253
+E: MINOR=1
+E: SUBSYSTEM=block
+E: USEC_INITIALIZED=8192545
+E: DM_UDEV_DISABLE_LIBRARY_FALLBACK_FLAG=1
+E: DM_UDEV_PRIMARY_SOURCE_FLAG=1
+E: DM_UDEV_RULES=1
+E: DM_UDEV_RULES_VSN=2
+E: DM_ACTIVATION=1
+E: DM_NAME=data-root
+E: DM_UUID=LVM-Yk4pJq2WnR7vT1sZ3xC8bN6mF0dH5gL9aE2uI7oP4rS1tV6wX3yB8cD0eF5hG2jK
+E: DM_SUSPENDED=0
+E: DM_VG_NAME=data
+E: DM_LV_NAME=root
+E: ID_FS_UUID=3f9d2a6e-4b1c-4e8f-a7d5-6c2b1e0f9a8d
+E: ID_FS_UUID_ENC=3f9d2a6e-4b1c-4e8f-a7d5-6c2b1e0f9a8d
+E: ID_FS_VERSION=1.0
+E: ID_FS_TYPE=ext4
+E: ID_FS_USAGE=filesystem
+E: DEVLINKS=/dev/mapper/data-root /dev/data/root /dev/disk/by-uuid/3f9d2a6e-4b1c-4e8f-a7d5-6c2b1e0f9a8d /dev/disk/by-id/dm-name-data-root
+E: TAGS=:systemd:
+E: CURRENT_TAGS=:systemd:
+
+P: /devices/virtual/block/md0
+M: md0
+R: 0
+U: block
+T: disk
+D: b 9:0
+N: md0
+L: 100
+S: disk/by-id/md-uuid-4a1b2c3d:5e6f7a8b:9c0d1e2f:3a4b5c6d
+Q: 9
+V: 1
+E: DEVPATH=/devices/virtual/block/md0
+E: DEVNAME=/dev/md0
+E: DEVTYPE=disk
+E: DISKSEQ=9
+E: MAJOR=9
+E: MINOR=0
+E: SUBSYSTEM=block
+E: USEC_INITIALIZED=7436102
+E: MD_LEVEL=raid1
+E: MD_DEVICES=2
+E: MD_METADATA=1.2
+E: MD_UUID=4a1b2c3d:5e6f7a8b:9c0d1e2f:3a4b5c6d
+E: MD_DEVNAME=0
+E: MD_DEVICE_ev_sda_ROLE=0
+E: MD_DEVICE_ev_sda_DEV=/dev/sda
+E: MD_DEVICE_ev_sdb_ROLE=1
+E: MD_DEVICE_ev_sdb_DEV=/dev/sdb
+E: SYSTEMD_WANTS=mdmonitor.service
+E: DEVLINKS=/dev/disk/by-id/md-uuid-4a1b2c3d:5e6f7a8b:9c0d1e2f:3a4b5c6d
+E: TAGS=:systemd:
+E: CURRENT_TAGS=:systemd:
+
+P: /devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda
+M: sda
+R: 0
+U: block
+T: disk
+D: b 8:0
+N: sda
+L: 0
+S: disk/by-id/ata-ST1000DM010-2EP102_Z9A1B2C3
+Q: 3
+V: 1
+E: DEVPATH=/devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda
+E: DEVNAME=/dev/sda
+E: DEVTYPE=disk
+E: DISKSEQ=3
+E: MAJOR=8
+E: MINOR=0
+E: SUBSYSTEM=block
+E: USEC_INITIALIZED=2045319
+E: ID_ATA=1
+E: ID_TYPE=disk
+E: ID_BUS=ata
+E: ID_MODEL=ST1000DM010-2EP102
+E: ID_SERIAL=ST1000DM010-2EP102_Z9A1B2C3
+E: ID_PATH=pci-0000:00:17.0-ata-1
+E: ID_FS_UUID=4a1b2c3d-5e6f-7a8b-9c0d-1e2f3a4b5c6d
+E: ID_FS_UUID_ENC=4a1b2c3d-5e6f-7a8b-9c0d-1e2f3a4b5c6d
+E: ID_FS_VERSION=1.2
+E: ID_FS_TYPE=linux_raid_member
+E: ID_FS_USAGE=raid
+E: DEVLINKS=/dev/disk/by-id/ata-ST1000DM010-2EP102_Z9A1B2C3
+E: TAGS=:systemd:
+E: CURRENT_TAGS=:systemd:
+
+P: /devices/pci0000:00/0000:00:17.0/ata2/host1/target1:0:0/1:0:0:0/block/sdb
+M: sdb
+R: 0
+U: block
+T: disk
+D: b 8:16
+N: sdb
+L: 0
+S: disk/by-id/ata-ST1000DM010-2EP102_Z9A4D5E6
+Q: 4
+V: 1
+E: DEVPATH=/devices/pci0000:00/0000:00:17.0/ata2/host1/target1:0:0/1:0:0:0/block/sdb
+E: DEVNAME=/dev/sdb
+E: DEVTYPE=disk
+E: DISKSEQ=4
+E: MAJOR=8
+E: MINOR=16
+E: SUBSYSTEM=block
+E: USEC_INITIALIZED=2045874
+E: ID_ATA=1
+E: ID_TYPE=disk
+E: ID_BUS=ata
+E: ID_MODEL=ST1000DM010-2EP102
+E: ID_SERIAL=ST1000DM010-2EP102_Z9A4D5E6
+E: ID_PATH=pci-0000:00:17.0-ata-2
+E: ID_FS_UUID=4a1b2c3d-5e6f-7a8b-9c0d-1e2f3a4b5c6d
+E: ID_FS_UUID_ENC=4a1b2c3d-5e6f-7a8b-9c0d-1e2f3a4b5c6d
+E: ID_FS_VERSION=1.2
+E: ID_FS_TYPE=linux_raid_member
+E: ID_FS_USAGE=raid
+E: DEVLINKS=/dev/disk/by-id/ata-ST1000DM010-2EP102_Z9A4D5E6
+E: TAGS=:systemd:
+E: CURRENT_TAGS=:systemd:
+";
+
+    #[test]
+    fn export_db_inference() {
+        let db = UDevDb::parse(EXPORT_DB);
+        let device = |devname: &str| {
+            db.devices
+                .iter()
+                .find(|device| device.property("DEVNAME") == Some(devname))
+                .unwrap()
+        };
+
+        let disk = device("/dev/nvme0n1");
+        assert_eq!(disk.attribute("queue/logical_block_size"), Some("512"));
+        assert_eq!(disk.attribute("size"), Some("968384512"));
+
+        assert_eq!(
+            device("/dev/nvme0n1p3").attribute("size"),
+            Some("957894656")
+        );
+        assert_eq!(device("/dev/dm-0").attribute("size"), Some("957894656"));
+        assert_eq!(device("/dev/md0").attribute("md/raid_disks"), Some("2"));
+
+        assert_eq!(db.parents(device("/dev/nvme0n1p3")), ["/dev/nvme0n1"]);
+        assert_eq!(db.parents(device("/dev/dm-0")), ["/dev/nvme0n1p3"]);
+        assert_eq!(db.parents(device("/dev/dm-1")), ["/dev/dm-0"]);
+        assert_eq!(db.parents(device("/dev/md0")), ["/dev/sda", "/dev/sdb"]);
+        assert!(db.parents(disk).is_empty());
+    }
+
+    #[test]
+    fn export_db_reload() {
+        let mut t = crate::ACellOwner::wait_for_new();
+        let mut dm = crate::DiskManager::simulated();
+
+        dm.reload(&mut UDevDb::parse(EXPORT_DB), &mut t);
+
+        let ancestors = dm.ancestors("/dev/dm-1", &t).collect::<Vec<_>>();
+        assert_eq!(ancestors, ["/dev/dm-0", "/dev/nvme0n1p3", "/dev/nvme0n1"]);
+
+        let disk = dm.root_disk_of("/dev/dm-1", &t).unwrap();
+        assert_eq!(disk.ro(&t).children.len(), 2);
+        assert_eq!(disk.ro(&t).sector_size, 512);
+    }
+}
@@ -3,10 +3,11 @@
 
 use anyhow::Context;
 use pop_disk_manager::os_probe;
-use pop_disk_manager::{os_probe::OsEntry, ACellOwner, DiskManager, UDev};
+use pop_disk_manager::snapshot::Snapshot;
+use pop_disk_manager::{os_probe::OsEntry, ACellOwner, DiskManager, ReloadDiff, UDev};
 
 use crate::frontend::Frontend;
-use crate::{Device, EncryptedDevice, OsInfo, Request};
+use crate::{Device, EncryptedDevice, FreeRegion, OsInfo, Request};
 use std::future::Future;
 use std::path::Path;
 use zbus::{Connection, SignalContext};
@@ -27,8 +28,29 @@ impl Backend {
                     Err(why) => Frontend::decrypt_err(&ctx, why.to_string()).await,
                 },
 
+                Request::DiskLayout => {
+                    let layout = backend.disk_layout();
+                    Frontend::disk_layout_ok(&ctx, layout).await
+                }
+
                 Request::DiskRescan => {
-                    let _ = dbg!(backend.disk_rescan());
+                    match backend.disk_rescan() {
+                        Ok(diff) => {
+                            if !diff.added.is_empty() {
+                                Frontend::disk_rescan_added(&ctx, diff.added).await?;
+                            }
+
+                            if !diff.removed.is_empty() {
+                                Frontend::disk_rescan_removed(&ctx, diff.removed).await?;
+                            }
+
+                            if !diff.changed.is_empty() {
+                                Frontend::disk_rescan_changed(&ctx, diff.changed).await?;
+                            }
+                        }
+                        Err(why) => eprintln!("failed to rescan disks: {:?}", why),
+                    }
+
                     Frontend::disk_rescan_complete(&ctx).await
                 }
 
@@ -37,6 +59,35 @@ impl Backend {
                     Err(why) => Frontend::encrypted_devices_err(&ctx, why.to_string()).await,
                 },
 
+                Request::FreeRegions { disk } => match backend.free_regions(&disk) {
+                    Ok(regions) => Frontend::free_regions_ok(&ctx, disk, regions).await,
+                    Err(why) => Frontend::free_regions_err(&ctx, why.to_string()).await,
+                },
+
+                Request::Hotplug => {
+                    let diff = match backend.disk_rescan() {
+                        Ok(diff) => diff,
+                        Err(why) => {
+                            eprintln!("failed to reload after hotplug event: {:?}", why);
+                            return Ok(());
+                        }
+                    };
+
+                    for device in diff.added {
+                        Frontend::device_added(&ctx, device).await?;
+                    }
+
+                    for device in diff.removed {
+                        Frontend::device_removed(&ctx, device).await?;
+                    }
+
+                    for device in diff.changed {
+                        Frontend::device_changed(&ctx, device).await?;
+                    }
+
+                    Ok(())
+                }
+
                 Request::OsEntries => match dbg!(backend.os_entries()) {
                     Ok(entries) => Frontend::os_entries_ok(&ctx, entries).await,
                     Err(why) => Frontend::os_entries_err(&ctx, why.to_string()).await,
@@ -96,7 +147,11 @@ impl Backend {
             .context("err to unlock device")
     }
 
-    pub fn disk_rescan(&mut self) -> anyhow::Result<()> {
+    pub fn disk_layout(&self) -> Snapshot {
+        Snapshot::new(&self.disk_manager, &self.t)
+    }
+
+    pub fn disk_rescan(&mut self) -> anyhow::Result<ReloadDiff> {
         let &mut Self {
             ref mut disk_manager,
             ref mut t,
@@ -104,8 +159,7 @@ impl Backend {
         } = self;
 
         let udev = &mut udev_context()?;
-        disk_manager.reload(udev, t);
-        Ok(())
+        Ok(disk_manager.reload(udev, t))
     }
 
     pub fn encrypted_devices(&self) -> anyhow::Result<Vec<EncryptedDevice>> {
@@ -134,6 +188,36 @@ impl Backend {
         Ok(encrypted)
     }
 
+    pub fn free_regions(&self, disk: &str) -> anyhow::Result<Vec<FreeRegion>> {
+        let &Self {
+            ref disk_manager,
+            ref t,
+            ..
+        } = self;
+
+        let disk = disk_manager
+            .disk_by_devname(disk, t)
+            .context("could not find disk")?;
+
+        if disk.ro(t).live_media {
+            anyhow::bail!("disk holds the live installation media");
+        }
+
+        let regions = disk
+            .ro(t)
+            .free_regions(t)
+            .into_iter()
+            .map(|region| FreeRegion {
+                start: region.start,
+                end: region.end,
+                size: region.size,
+                logical: region.logical,
+            })
+            .collect();
+
+        Ok(regions)
+    }
+
     pub fn os_entries(&self) -> anyhow::Result<Vec<OsEntry>> {
         let &Self {
             ref disk_manager,
@@ -1,9 +1,10 @@
 // Copyright 2021 System76 <info@system76.com>
 // SPDX-License-Identifier: GPL-3.0-only
 
-use crate::{EncryptedDevice, OsInfo, Request};
+use crate::{EncryptedDevice, FreeRegion, OsInfo, Request};
 use envfile::EnvFile;
 use pop_disk_manager::os_probe::OsEntry;
+use pop_disk_manager::snapshot::Snapshot;
 use postage::mpsc::Sender;
 use postage::prelude::*;
 use std::collections::BTreeMap;
@@ -41,6 +42,32 @@ impl Frontend {
     #[dbus_interface(signal)]
     pub async fn decrypt_ok(ctx: &SignalContext<'_>) -> zbus::Result<()>;
 
+    /// Emitted when a block device appears, such as when a USB drive is plugged in.
+    #[rustfmt::skip]
+    #[dbus_interface(signal)]
+    pub async fn device_added(ctx: &SignalContext<'_>, device: String) -> zbus::Result<()>;
+
+    /// Emitted when the size, filesystem, partition table, or children of a device change.
+    #[rustfmt::skip]
+    #[dbus_interface(signal)]
+    pub async fn device_changed(ctx: &SignalContext<'_>, device: String) -> zbus::Result<()>;
+
+    /// Emitted when a block device disappears.
+    #[rustfmt::skip]
+    #[dbus_interface(signal)]
+    pub async fn device_removed(ctx: &SignalContext<'_>, device: String) -> zbus::Result<()>;
+
+    /// Request a snapshot of every disk, partition, and device map.
+    async fn disk_layout(&mut self) -> zbus::fdo::Result<()> {
+        eprintln!("fetching disk layout");
+        let _ = self.sender.send(Request::DiskLayout).await;
+        Ok(())
+    }
+
+    #[rustfmt::skip]
+    #[dbus_interface(signal)]
+    pub async fn disk_layout_ok(ctx: &SignalContext<'_>, layout: Snapshot) -> zbus::Result<()>;
+
     /// Initiate a rescan of disk information.
     async fn disk_rescan(&mut self) -> zbus::fdo::Result<()> {
         eprintln!("disk rescan");
@@ -48,10 +75,22 @@ impl Frontend {
         Ok(())
     }
 
+    #[rustfmt::skip]
+    #[dbus_interface(signal)]
+    pub async fn disk_rescan_added(ctx: &SignalContext<'_>, devices: Vec<String>) -> zbus::Result<()>;
+
+    #[rustfmt::skip]
+    #[dbus_interface(signal)]
+    pub async fn disk_rescan_changed(ctx: &SignalContext<'_>, devices: Vec<String>) -> zbus::Result<()>;
+
     #[rustfmt::skip]
     #[dbus_interface(signal)]
     pub async fn disk_rescan_complete(ctx: &SignalContext<'_>) -> zbus::Result<()>;
 
+    #[rustfmt::skip]
+    #[dbus_interface(signal)]
+    pub async fn disk_rescan_removed(ctx: &SignalContext<'_>, devices: Vec<String>) -> zbus::Result<()>;
+
     /// Initiate search for encrypted devices.
     async fn encrypted_devices(&mut self) -> zbus::fdo::Result<()> {
         eprintln!("fetching encrypted devices");
@@ -70,6 +109,25 @@ impl Frontend {
         devices: Vec<EncryptedDevice>,
     ) -> zbus::Result<()>;
 
+    /// Request the unallocated regions of a `disk`, such as `/dev/sda`.
+    async fn free_regions(&mut self, disk: String) -> zbus::fdo::Result<()> {
+        eprintln!("fetching free regions of {}", disk);
+        let _ = self.sender.send(Request::FreeRegions { disk }).await;
+        Ok(())
+    }
+
+    #[rustfmt::skip]
+    #[dbus_interface(signal)]
+    pub async fn free_regions_err(ctx: &SignalContext<'_>, why: String) -> zbus::Result<()>;
+
+    #[rustfmt::skip]
+    #[dbus_interface(signal)]
+    pub async fn free_regions_ok(
+        ctx: &SignalContext<'_>,
+        disk: String,
+        regions: Vec<FreeRegion>,
+    ) -> zbus::Result<()>;
+
     /// Determines which mode the system is currently in.
     async fn mode(&self) -> u8 {
         let mode = if let Some(env) = self.env.as_ref() {
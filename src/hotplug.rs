@@ -0,0 +1,61 @@
+// Copyright 2021 System76 <info@system76.com>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use crate::Request;
+use anyhow::Context;
+use async_io::Async;
+use postage::mpsc::Sender;
+use postage::prelude::*;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd};
+
+/// Owns the udev monitor socket, so that it may be polled by the async reactor.
+struct MonitorSocket(libudev::MonitorSocket);
+
+impl AsFd for MonitorSocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The descriptor lives as long as the socket which owns it.
+        unsafe { BorrowedFd::borrow_raw(self.0.as_raw_fd()) }
+    }
+}
+
+/// Watches for block devices being added, removed, or changed, and requests a reload.
+pub async fn monitor(mut sender: Sender<Request>) -> anyhow::Result<()> {
+    let context = libudev::Context::new().context("could not get libudev context")?;
+
+    let mut monitor = libudev::Monitor::new(&context).context("could not get udev monitor")?;
+
+    monitor
+        .match_subsystem("block")
+        .context("err to match block subsystem for udev monitor")?;
+
+    let socket = monitor
+        .listen()
+        .context("could not listen for udev events")?;
+    let mut socket =
+        Async::new(MonitorSocket(socket)).context("could not register udev monitor socket")?;
+
+    loop {
+        socket
+            .readable()
+            .await
+            .context("failed to poll udev monitor socket")?;
+
+        // Events arrive in bursts, such as a disk and each of its partitions, so they are
+        // drained before a single reload is requested.
+        let mut events = 0;
+
+        // Receiving events does not close or replace the descriptor of the socket.
+        while let Some(event) = unsafe { socket.get_mut() }.0.receive_event() {
+            eprintln!(
+                "hotplug {:?}: {:?}",
+                event.event_type(),
+                event.device().devnode()
+            );
+            events += 1;
+        }
+
+        if events != 0 && sender.send(Request::Hotplug).await.is_err() {
+            return Ok(());
+        }
+    }
+}
@@ -6,6 +6,7 @@ extern crate zbus;
 
 pub mod backend;
 pub mod frontend;
+pub mod hotplug;
 
 use crate::backend::Backend;
 use crate::frontend::Frontend;
@@ -41,6 +42,8 @@ async fn async_main() -> anyhow::Result<()> {
         eprintln!("failed to reload disk manager: {}", why);
     }
 
+    let hotplug = hotplug::monitor(sender.clone());
+
     let frontend = Frontend {
         env: envfile::EnvFile::new(&Path::new("/cdrom/recovery.conf")).ok(),
         sender,
@@ -69,7 +72,14 @@ async fn async_main() -> anyhow::Result<()> {
         }
     };
 
-    backend_event_loop.await;
+    // Reloads the disk manager whenever block devices are added, removed, or changed.
+    let hotplug_event_loop = async move {
+        if let Err(why) = hotplug.await {
+            eprintln!("hotplug monitoring stopped: {:?}", why);
+        }
+    };
+
+    futures::future::join(backend_event_loop, hotplug_event_loop).await;
 
     Ok(())
 }
@@ -87,6 +97,15 @@ pub struct EncryptedDevice {
     pub uuid: String,
 }
 
+/// An unallocated region of a disk, in bytes from the start of the disk.
+#[derive(Debug, Type, Serialize, Deserialize)]
+pub struct FreeRegion {
+    pub start: u64,
+    pub end: u64,
+    pub size: u64,
+    pub logical: bool,
+}
+
 #[derive(Debug, Type, Serialize, Deserialize)]
 pub struct OsInfo {
     pub device: Device,
@@ -98,8 +117,11 @@ pub struct OsInfo {
 #[derive(Debug)]
 pub enum Request {
     Decrypt { device: String, key: String },
+    DiskLayout,
     DiskRescan,
     EncryptedDevices,
+    FreeRegions { disk: String },
+    Hotplug,
     OsEntries,
     OsSearch,
 }